@@ -0,0 +1,260 @@
+//! analyze.rs
+//!
+//! Walks a `Timeline` once and collects the facts we care about when checking a
+//! conversion or budgeting voices for an OPL port: what plays on which channel, with
+//! which instruments, how many notes overlap at worst, and whether anything is left
+//! sounding when the track ends.
+
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+use crate::gm;
+use crate::midi::{Msg, Timeline};
+use crate::mus::DRUM_CHANNEL;
+
+/// A tempo change in the song.
+#[derive(Debug, Clone, Serialize)]
+pub struct TempoChange {
+    pub t_us: u64,
+    pub us_per_qn: f64,
+    pub bpm: f64,
+}
+
+/// A GM program used by at least one note.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgramUse {
+    pub program: u8,
+    pub name: &'static str,
+    /// True if notes played before any program change, i.e. on the channel default
+    pub implicit: bool,
+}
+
+/// A percussion key used on the drum channel.
+#[derive(Debug, Clone, Serialize)]
+pub struct DrumUse {
+    pub key: u8,
+    pub name: Option<&'static str>,
+    pub count: usize,
+}
+
+/// Everything that happened on one MIDI channel.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelReport {
+    pub channel: u8,
+    pub notes: usize,
+    /// Lowest and highest key played
+    pub note_range: Option<(u8, u8)>,
+    /// Programs heard on this channel (empty for the drum channel)
+    pub programs: Vec<ProgramUse>,
+    /// Drum keys played (drum channel only)
+    pub drums: Vec<DrumUse>,
+    /// Controller numbers seen
+    pub controllers: Vec<u8>,
+    /// Most notes sounding at once on this channel
+    pub polyphony_peak: usize,
+}
+
+/// A note still held when the timeline ends.
+#[derive(Debug, Clone, Serialize)]
+pub struct HangingNote {
+    pub channel: u8,
+    pub key: u8,
+}
+
+/// The full analysis of one song.
+#[derive(Debug, Clone, Serialize)]
+pub struct SongReport {
+    pub duration_us: u64,
+    pub note_count: usize,
+    /// Most notes sounding at once across all channels
+    pub polyphony_peak: usize,
+    /// When the peak was first reached
+    pub polyphony_peak_at_us: u64,
+    pub tempo_changes: Vec<TempoChange>,
+    pub channels: Vec<ChannelReport>,
+    /// Controller numbers seen on any channel
+    pub controllers: Vec<u8>,
+    pub hanging_notes: Vec<HangingNote>,
+}
+
+/// Per-channel scratch state while walking the timeline.
+struct ChannelState {
+    notes: usize,
+    lo: Option<u8>,
+    hi: Option<u8>,
+    program: Option<u8>,
+    programs: BTreeSet<(u8, bool)>,
+    drums: [usize; 128],
+    controllers: BTreeSet<u8>,
+    /// How many note-ons are currently outstanding per key
+    held: [u16; 128],
+    sounding: usize,
+    peak: usize,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            notes: 0,
+            lo: None,
+            hi: None,
+            program: None,
+            programs: BTreeSet::new(),
+            drums: [0; 128],
+            controllers: BTreeSet::new(),
+            held: [0; 128],
+            sounding: 0,
+            peak: 0,
+        }
+    }
+}
+
+/// Analyze a timeline. Events are assumed sorted by time, as `build_timeline` produces them.
+pub fn analyze(tl: &Timeline) -> SongReport {
+    let mut chans: Vec<ChannelState> = (0..16).map(|_| ChannelState::default()).collect();
+    let mut tempo_changes = Vec::new();
+    let mut sounding = 0usize;
+    let mut peak = 0usize;
+    let mut peak_at = 0u64;
+    let mut note_count = 0usize;
+
+    for e in &tl.events {
+        match e.msg {
+            Msg::NoteOn(ch, key, _) => {
+                let c = &mut chans[ch as usize & 15];
+                c.notes += 1;
+                note_count += 1;
+                c.lo = Some(c.lo.map_or(key, |lo| lo.min(key)));
+                c.hi = Some(c.hi.map_or(key, |hi| hi.max(key)));
+                if ch == DRUM_CHANNEL {
+                    c.drums[key as usize & 127] += 1;
+                } else {
+                    c.programs.insert((c.program.unwrap_or(0), c.program.is_none()));
+                }
+                c.held[key as usize & 127] += 1;
+                c.sounding += 1;
+                c.peak = c.peak.max(c.sounding);
+                sounding += 1;
+                if sounding > peak {
+                    peak = sounding;
+                    peak_at = e.t_us;
+                }
+            }
+            Msg::NoteOff(ch, key, _) => {
+                let c = &mut chans[ch as usize & 15];
+                // A stray note-off for a key that isn't held changes nothing
+                if c.held[key as usize & 127] > 0 {
+                    c.held[key as usize & 127] -= 1;
+                    c.sounding -= 1;
+                    sounding -= 1;
+                }
+            }
+            Msg::Program(ch, prog) => chans[ch as usize & 15].program = Some(prog),
+            Msg::Control(ch, cc, _) => { chans[ch as usize & 15].controllers.insert(cc); }
+            Msg::Tempo(us_per_qn) => tempo_changes.push(TempoChange {
+                t_us: e.t_us,
+                us_per_qn,
+                bpm: 60_000_000.0 / us_per_qn,
+            }),
+            Msg::PitchBend(..) | Msg::AfterTouch(..) | Msg::ChannelAftertouch(..) => {}
+        }
+    }
+
+    let mut controllers = BTreeSet::new();
+    let mut hanging_notes = Vec::new();
+    let mut channels = Vec::new();
+    for (ch, c) in chans.iter().enumerate() {
+        let ch = ch as u8;
+        controllers.extend(c.controllers.iter().copied());
+        for (key, &n) in c.held.iter().enumerate() {
+            if n > 0 {
+                hanging_notes.push(HangingNote { channel: ch, key: key as u8 });
+            }
+        }
+        if c.notes == 0 && c.controllers.is_empty() && c.program.is_none() {
+            continue;
+        }
+        channels.push(ChannelReport {
+            channel: ch,
+            notes: c.notes,
+            note_range: c.lo.zip(c.hi),
+            programs: c.programs.iter()
+                .map(|&(program, implicit)| ProgramUse { program, name: gm::program_name(program), implicit })
+                .collect(),
+            drums: c.drums.iter().enumerate()
+                .filter(|&(_, &n)| n > 0)
+                .map(|(key, &count)| DrumUse { key: key as u8, name: gm::drum_name(key as u8), count })
+                .collect(),
+            controllers: c.controllers.iter().copied().collect(),
+            polyphony_peak: c.peak,
+        });
+    }
+
+    SongReport {
+        duration_us: tl.last_t_us,
+        note_count,
+        polyphony_peak: peak,
+        polyphony_peak_at_us: peak_at,
+        tempo_changes,
+        channels,
+        controllers: controllers.into_iter().collect(),
+        hanging_notes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::Timed;
+
+    fn at(t_us: u64, msg: Msg) -> Timed { Timed { t_us, msg } }
+
+    #[test]
+    fn reports_channels_polyphony_and_hanging_notes() {
+        let tl = Timeline {
+            events: vec![
+                at(0, Msg::Tempo(500_000.0)),
+                at(0, Msg::NoteOn(0, 60, 100)),          // default program 0
+                at(0, Msg::Program(1, 30)),
+                at(0, Msg::Control(1, 7, 100)),
+                at(10, Msg::NoteOn(1, 40, 100)),
+                at(10, Msg::NoteOn(DRUM_CHANNEL, 36, 100)),
+                at(20, Msg::NoteOff(0, 60, 0)),
+                at(20, Msg::NoteOff(DRUM_CHANNEL, 36, 0)),
+                at(30, Msg::NoteOn(DRUM_CHANNEL, 38, 100)),
+                at(40, Msg::NoteOff(DRUM_CHANNEL, 38, 0)),
+                at(40, Msg::NoteOff(DRUM_CHANNEL, 99, 0)), // stray
+            ],
+            last_t_us: 40,
+            ppq: 140.0,
+            initial_us_per_qn: 500_000.0,
+        };
+        let r = analyze(&tl);
+
+        assert_eq!(r.note_count, 4);
+        assert_eq!(r.polyphony_peak, 3);
+        assert_eq!(r.polyphony_peak_at_us, 10);
+        assert_eq!(r.tempo_changes.len(), 1);
+        assert_eq!(r.tempo_changes[0].bpm, 120.0);
+        assert_eq!(r.controllers, vec![7]);
+
+        let ch0 = &r.channels[0];
+        assert_eq!(ch0.channel, 0);
+        assert!(ch0.programs[0].implicit);
+        assert_eq!(ch0.programs[0].name, "Acoustic Grand Piano");
+
+        let ch1 = r.channels.iter().find(|c| c.channel == 1).unwrap();
+        assert_eq!(ch1.programs[0].program, 30);
+        assert!(!ch1.programs[0].implicit);
+        assert_eq!(ch1.note_range, Some((40, 40)));
+
+        let drums = r.channels.iter().find(|c| c.channel == DRUM_CHANNEL).unwrap();
+        assert!(drums.programs.is_empty());
+        let keys: Vec<_> = drums.drums.iter().map(|d| (d.key, d.name)).collect();
+        assert_eq!(keys, vec![(36, Some("Bass Drum 1")), (38, Some("Acoustic Snare"))]);
+
+        // Channel 1's note never ends
+        assert_eq!(r.hanging_notes.len(), 1);
+        assert_eq!((r.hanging_notes[0].channel, r.hanging_notes[0].key), (1, 40));
+    }
+}
@@ -0,0 +1,627 @@
+//! commands.rs
+//!
+//! The non-interactive subcommands (`list`, `info`, `analyze`, `validate`, `pack`,
+//! `play`, `export`, `render`) plus the key-driven playback loops (MIDI and digital)
+//! shared with the REPL.
+//!
+//! Every listing command can emit JSON (`--json`) so scripts don't have to scrape
+//! the human-readable output.
+
+use anyhow::{bail, Context, Result};
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use serde::Serialize;
+use std::{
+    io::{stdin, stdout, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::analyze::{self as report, SongReport};
+use crate::digital::{self, Source};
+use crate::dehacked::Dehacked;
+use crate::game::Identity;
+use crate::gm;
+use crate::mapinfo::MapTable;
+use crate::midi::{format_duration, Timeline};
+use crate::song::{find_song, is_music_lump, load_timeline, to_midi, MusOptions, SongFormat, SongInfo, MUSIC_NAMESPACES};
+use crate::synth::{self, Audio, ChannelMix, OutputOptions, Transport};
+use crate::titles::{Originals, Provenance};
+use crate::wad::Wad;
+
+/// Sample rate for `render` when `--sample-rate` isn't given.
+const RENDER_SAMPLE_RATE: u32 = 44_100;
+
+/// Where songs come from: a WAD (or PK3 or folder) and DEHACKED patch files
+/// applied on top of its own, plus the IWAD it was made for, if given.
+#[derive(Debug, Clone, Copy)]
+pub struct Resources<'a> {
+    pub wad: &'a Path,
+    pub deh: &'a [PathBuf],
+    pub iwad: Option<&'a Path>,
+}
+
+/// An opened `Resources`.
+pub struct Library {
+    pub wad: Wad,
+    /// The WAD's songs, in directory order
+    pub names: Vec<String>,
+    pub maps: MapTable,
+    /// The IWAD's copies of `names`, to spot replacements: the given IWAD's, or the
+    /// WAD's own when it is one
+    pub originals: Option<Originals>,
+    /// The game's IWAD, as identified
+    pub identity: Option<Identity>,
+}
+
+impl Resources<'_> {
+    /// Open the WAD with its songs and the maps that play them, applying the WAD's
+    /// DEHACKED lumps and then the patch files. The game, and with it the built-in
+    /// map tables and titles, is the WAD's if it is an IWAD, else the given IWAD's.
+    pub fn open(self) -> Result<Library> {
+        let wad = Wad::open(self.wad)?;
+        let iwad = self.iwad.map(Wad::open).transpose()?;
+        let identity = wad.identify().or_else(|| iwad.as_ref().and_then(Wad::identify));
+        let game = identity.map(|i| i.game);
+        let patches = self.deh.iter().map(|p| Dehacked::load(p)).collect::<Result<Vec<_>>>()?;
+        let maps = MapTable::from_wad(&wad, game, &patches);
+        let names = song_names(&wad, &maps);
+        let originals = match &iwad {
+            Some(iwad) => Some(Originals::from_iwad(iwad, &names)),
+            None if wad.is_iwad() => Some(Originals::from_iwad(&wad, &names)),
+            None => None,
+        };
+        Ok(Library { wad, names, maps, originals, identity })
+    }
+}
+
+/// Add what a song's bytes alone don't say: its title or the IWAD song it replaces
+/// (see `Provenance::of`), and the maps that play it.
+fn annotate(info: &mut SongInfo, bytes: &[u8], maps: &MapTable, originals: Option<&Originals>) {
+    let p = Provenance::of(&info.name, bytes, maps.game(), originals);
+    info.title = p.title.map(String::from);
+    info.replaces = p.replaces.map(String::from);
+    info.replaced = p.replaced;
+    info.maps = maps.maps_for(&info.name);
+}
+
+/// Names of all music lumps in the WAD, in directory order. Lumps a map plays count
+/// whatever their name, which is how Hexen's songs are found.
+pub fn song_names(wad: &Wad, maps: &MapTable) -> Vec<String> {
+    wad.lumps()
+        .iter()
+        .filter(|l| is_music_lump(l) || (MUSIC_NAMESPACES.contains(&l.namespace) && maps.plays(&l.name)))
+        .map(|l| l.name.clone())
+        .collect()
+}
+
+/// Borrow a song's bytes, ignoring same-named lumps in other namespaces.
+pub fn read_song<'a>(wad: &'a Wad, name: &str) -> Result<&'a [u8]> {
+    wad.read_in(name, MUSIC_NAMESPACES)
+}
+
+/// Resolve `input` against the WAD's songs, with a helpful error if nothing matches.
+fn resolve<'a>(names: &'a [String], maps: &MapTable, input: &str) -> Result<&'a str> {
+    find_song(names, maps, input).with_context(|| format!("song not found: {input}"))
+}
+
+/// Run `f` over `items` on every core, keeping the input order.
+fn par_map<I: Sync, T: Send>(items: &[I], f: impl Fn(&I) -> T + Sync) -> Vec<T> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk = items.len().div_ceil(threads).max(1);
+    std::thread::scope(|s| {
+        let workers: Vec<_> = items.chunks(chunk)
+            .map(|c| s.spawn(|| c.iter().map(&f).collect::<Vec<_>>()))
+            .collect();
+        workers.into_iter().flat_map(|w| w.join().expect("worker panicked")).collect()
+    })
+}
+
+fn print_json<T: Serialize + ?Sized>(v: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(v)?);
+    Ok(())
+}
+
+/// Print the details we know about a loaded timeline.
+pub fn print_timeline_summary(tl: &Timeline) {
+    println!("PPQ: {}", tl.ppq);
+    println!(
+        "Initial tempo: {} µs/qn (~{:.1} BPM)",
+        tl.initial_us_per_qn,
+        60_000_000.0 / tl.initial_us_per_qn
+    );
+    println!("Total events parsed: {}", tl.events.len());
+    println!("Estimated track length: {}", format_duration(tl.last_t_us));
+}
+
+/// `list`: every music lump with its format and length.
+pub fn list(res: Resources, json: bool, mus_opts: MusOptions) -> Result<()> {
+    let Library { wad, names, maps, originals, .. } = res.open()?;
+    let source = res.wad.display().to_string();
+    let infos = par_map(&names, |name| {
+        let bytes = read_song(&wad, name)?;
+        let mut info = SongInfo::inspect(name, &source, bytes, mus_opts);
+        annotate(&mut info, bytes, &maps, originals.as_ref());
+        Ok(info)
+    })
+    .into_iter()
+    .collect::<Result<Vec<_>>>()?;
+
+    if json {
+        return print_json(&infos);
+    }
+    for i in &infos {
+        let len = i.duration_us.map(format_duration).unwrap_or_else(|| "--:--".into());
+        let note = match (&i.error, i.diagnostics.len()) {
+            (Some(_), _) => "  (unreadable)".to_string(),
+            (None, 0) => String::new(),
+            (None, n) => format!("  ({} warning{})", n, if n == 1 { "" } else { "s" }),
+        };
+        let played_on = if i.maps.is_empty() { String::new() } else { format!("  {}", i.maps.join(", ")) };
+        let titled = Provenance { title: i.title.as_deref(), replaces: i.replaces.as_deref(), replaced: i.replaced };
+        println!(
+            "{:<8}  {:>7} bytes  {:<7}  {}{}{}{}",
+            i.name, i.size, i.format.name(), len, played_on, titled, note
+        );
+    }
+    Ok(())
+}
+
+/// `info`: everything `list` shows, for one song, plus timing details.
+pub fn info(res: Resources, song: &str, json: bool, mus_opts: MusOptions) -> Result<()> {
+    let Library { wad, names, maps, originals, identity } = res.open()?;
+    let name = resolve(&names, &maps, song)?;
+    let bytes = read_song(&wad, name)?;
+    let mut info = SongInfo::inspect(name, &res.wad.display().to_string(), bytes, mus_opts);
+    annotate(&mut info, bytes, &maps, originals.as_ref());
+
+    if json {
+        return print_json(&info);
+    }
+    println!("Name:     {}", info.name);
+    println!("Source:   {}", info.source);
+    if let Some(id) = identity { println!("Game:     {}", id); }
+    println!("Size:     {} bytes", info.size);
+    println!("Format:   {}", info.format.name());
+    if let Some(t) = &info.title { println!("Title:    {}", t); }
+    if let Some(t) = &info.replaces { println!("Replaces: {}", t); }
+    match info.replaced {
+        Some(true) => println!("IWAD:     replaced (differs from the IWAD's)"),
+        Some(false) => println!("IWAD:     original"),
+        None => {}
+    }
+    if let Some(d) = info.duration_us { println!("Duration: {}", format_duration(d)); }
+    for map in &info.maps {
+        match maps.get(map).and_then(|m| m.title.as_deref()) {
+            Some(title) => println!("Map:      {} ({})", map, title),
+            None => println!("Map:      {}", map),
+        }
+    }
+    if let Some(n) = info.events { println!("Events:   {}", n); }
+    if let Some(p) = info.ppq { println!("PPQ:      {}", p); }
+    if let Some(t) = info.us_per_qn { println!("Tempo:    {} µs/qn (~{:.1} BPM)", t, 60_000_000.0 / t); }
+    if let Some(h) = &info.mus_header {
+        println!("Channels: {} primary, {} secondary", h.primary_channels, h.secondary_channels);
+        println!("Instruments:");
+        for &patch in &h.instruments {
+            println!("  {:>3}  {}", patch, gm::mus_instrument_name(patch));
+        }
+    }
+    for d in &info.diagnostics { println!("Warning:  {}", d); }
+    if let Some(e) = &info.error { println!("Error:    {}", e); }
+    Ok(())
+}
+
+/// One song's analysis, as printed by `analyze --json`.
+#[derive(Serialize)]
+struct Analyzed {
+    name: String,
+    #[serde(flatten)]
+    report: SongReport,
+}
+
+/// `analyze`: walk each song's timeline and report what it uses.
+///
+/// With no `songs` given, every music lump is analyzed; ones that fail to parse are skipped.
+pub fn analyze(res: Resources, songs: &[String], json: bool, mus_opts: MusOptions) -> Result<()> {
+    let Library { wad, names, maps, .. } = res.open()?;
+    let wanted: Vec<&str> = if songs.is_empty() {
+        names.iter().map(String::as_str).collect()
+    } else {
+        songs.iter().map(|s| resolve(&names, &maps, s)).collect::<Result<_>>()?
+    };
+
+    let results = par_map(&wanted, |&name| -> Result<_> {
+        Ok(load_timeline(read_song(&wad, name)?, mus_opts).map(|tl| report::analyze(&tl)))
+    });
+    let mut done = Vec::new();
+    for (name, result) in wanted.iter().zip(results) {
+        let report = match result? {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("skipping {}: {}", name, e);
+                continue;
+            }
+        };
+        if !json {
+            print_report(name, &report);
+        }
+        done.push(Analyzed { name: name.to_string(), report });
+    }
+
+    if json { print_json(&done)?; }
+    Ok(())
+}
+
+fn print_report(name: &str, r: &SongReport) {
+    println!("{}", name);
+    println!("  Duration:   {}", format_duration(r.duration_us));
+    println!("  Notes:      {}", r.note_count);
+    println!("  Polyphony:  {} peak at {}", r.polyphony_peak, format_duration(r.polyphony_peak_at_us));
+    for t in &r.tempo_changes {
+        println!("  Tempo:      {:.1} BPM at {}", t.bpm, format_duration(t.t_us));
+    }
+    if !r.controllers.is_empty() {
+        println!("  Controllers: {:?}", r.controllers);
+    }
+    for c in &r.channels {
+        let range = c.note_range
+            .map(|(lo, hi)| format!("{}-{}", gm::note_name(lo), gm::note_name(hi)))
+            .unwrap_or_else(|| "-".into());
+        println!("  ch {:>2}: {:>5} notes  range {:<9} poly {:>2}", c.channel, c.notes, range, c.polyphony_peak);
+        for p in &c.programs {
+            println!("         program {:>3} {}{}", p.program, p.name, if p.implicit { " (default)" } else { "" });
+        }
+        for d in &c.drums {
+            println!("         drum {:>3} {} x{}", d.key, d.name.unwrap_or("(non-GM key)"), d.count);
+        }
+        if !c.controllers.is_empty() {
+            println!("         controllers {:?}", c.controllers);
+        }
+    }
+    for h in &r.hanging_notes {
+        println!("  Hanging:    ch {} key {} ({})", h.channel, h.key, gm::note_name(h.key));
+    }
+}
+
+/// What `validate --json` prints.
+#[derive(Serialize)]
+struct Validation {
+    path: PathBuf,
+    /// Lump count, if the directory could be read at all
+    lumps: Option<usize>,
+    /// The game and release, if this is an IWAD we recognize
+    #[serde(flatten)]
+    identity: Option<Identity>,
+    problems: Vec<String>,
+}
+
+/// `validate`: check the WAD's header and directory and list every problem found.
+///
+/// Fails (non-zero exit) if there are any, so it can gate scripts.
+pub fn validate(wad_path: &Path, json: bool) -> Result<()> {
+    let (lumps, identity, problems) = match Wad::open(wad_path) {
+        Ok(wad) => (Some(wad.len()), wad.identify(), wad.validate().iter().map(ToString::to_string).collect()),
+        Err(e) => (None, None, vec![format!("{:#}", e)]),
+    };
+
+    if json {
+        print_json(&Validation { path: wad_path.to_path_buf(), lumps, identity, problems: problems.clone() })?;
+    } else {
+        for p in &problems {
+            println!("{}", p);
+        }
+        if let (Some(n), true) = (lumps, problems.is_empty()) {
+            match identity {
+                Some(id) => println!("{}: {} IWAD, {} lumps, no problems", wad_path.display(), id, n),
+                None => println!("{}: {} lumps, no problems", wad_path.display(), n),
+            }
+        }
+    }
+    if !problems.is_empty() {
+        bail!("{} problem{} found in {}", problems.len(), if problems.len() == 1 { "" } else { "s" }, wad_path.display());
+    }
+    Ok(())
+}
+
+/// `pack`: write whatever `Wad::open` made of `source` out as a PWAD, so a resource
+/// folder or PK3 can be shipped to ports that only take WADs.
+pub fn pack(source: &Path, out: &Path) -> Result<()> {
+    let wad = Wad::open(source)?;
+    std::fs::write(out, wad.bytes()).with_context(|| format!("writing {:?}", out))?;
+    println!("{}: {} lumps written to {}", source.display(), wad.len(), out.display());
+    Ok(())
+}
+
+/// `play`: play one song to the end (or until Esc) and exit.
+pub fn play(
+    res: Resources,
+    soundfont: &str,
+    song: &str,
+    out_opts: &OutputOptions,
+    transport: Arc<Transport>,
+    mus_opts: MusOptions,
+) -> Result<()> {
+    let Library { wad, names, maps, .. } = res.open()?;
+    let name = resolve(&names, &maps, song)?;
+    let bytes = read_song(&wad, name)?;
+    let format = SongFormat::detect(bytes);
+    if format.is_streamed() {
+        let track = digital::open(bytes.to_vec(), format, mus_opts.imf_rate)?;
+        let len = track.duration_us().map(format_duration).unwrap_or_else(|| "--:--".into());
+        println!("Playing {} ({}, {})", name, format.name(), len);
+        return play_digital(soundfont, out_opts, track);
+    }
+    let tl = load_timeline(bytes, mus_opts)?;
+    println!("Playing {} ({})", name, format_duration(tl.last_t_us));
+    play_timeline(soundfont, out_opts, &tl, Arc::new(ChannelMix::default()), transport)
+}
+
+/// One file written by `export`.
+#[derive(Serialize)]
+struct Exported {
+    name: String,
+    path: PathBuf,
+    bytes: u64,
+}
+
+/// `export`: write songs out as Standard MIDI files, or as raw lumps with `raw`.
+///
+/// With no `songs` given, every music lump is exported. OGG/MP3/FLAC/WAV lumps and
+/// tracker modules are always written raw.
+pub fn export(
+    res: Resources,
+    songs: &[String],
+    out_dir: &Path,
+    raw: bool,
+    json: bool,
+    mus_opts: MusOptions,
+) -> Result<()> {
+    let Library { wad, names, maps, .. } = res.open()?;
+    let wanted: Vec<&str> = if songs.is_empty() {
+        names.iter().map(String::as_str).collect()
+    } else {
+        songs.iter().map(|s| resolve(&names, &maps, s)).collect::<Result<_>>()?
+    };
+    std::fs::create_dir_all(out_dir).with_context(|| format!("creating {:?}", out_dir))?;
+
+    let mut done = Vec::new();
+    for name in wanted {
+        let bytes = read_song(&wad, name)?;
+        let format = SongFormat::detect(bytes);
+        // Recorded audio and modules have no MIDI form, so they are written as-is either way
+        let path = if raw || format.is_streamed() {
+            let path = out_dir.join(format!("{name}.{}", format.extension()));
+            std::fs::write(&path, bytes).with_context(|| format!("writing {:?}", path))?;
+            path
+        } else {
+            let midi = match to_midi(bytes, mus_opts) {
+                Ok(m) => m,
+                Err(e) => {
+                    eprintln!("skipping {}: {}", name, e);
+                    continue;
+                }
+            };
+            let path = out_dir.join(format!("{name}.mid"));
+            std::fs::write(&path, &midi).with_context(|| format!("writing {:?}", path))?;
+            path
+        };
+        let len = std::fs::metadata(&path)?.len();
+        if !json {
+            println!("{} -> {} ({} bytes)", name, path.display(), len);
+        }
+        done.push(Exported { name: name.to_string(), path, bytes: len });
+    }
+
+    if json { print_json(&done)?; }
+    Ok(())
+}
+
+/// `render`: synthesize one song into a WAV file as fast as possible.
+///
+/// Digital lumps and modules are decoded straight to the file; the SoundFont is only
+/// needed for MUS/MIDI.
+pub fn render(
+    res: Resources,
+    soundfont: &str,
+    song: &str,
+    out: Option<&Path>,
+    sample_rate: Option<u32>,
+    transport: &Transport,
+    mus_opts: MusOptions,
+) -> Result<()> {
+    let Library { wad, names, maps, .. } = res.open()?;
+    let name = resolve(&names, &maps, song)?;
+    let bytes = read_song(&wad, name)?;
+    let format = SongFormat::detect(bytes);
+
+    let path = out.map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from(format!("{name}.wav")));
+    let rate = sample_rate.unwrap_or(RENDER_SAMPLE_RATE);
+    let frames = if format.is_streamed() {
+        digital::render(digital::open(bytes.to_vec(), format, mus_opts.imf_rate)?, &path, rate)?
+    } else {
+        let tl = load_timeline(bytes, mus_opts)?;
+        synth::render_timeline(soundfont, &tl, &path, rate, &ChannelMix::default(), transport)?
+    };
+    println!("{} -> {} ({})", name, path.display(), format_duration(frames * 1_000_000 / rate as u64));
+    Ok(())
+}
+
+/// Map a key to the channel it toggles: 0–9 for channels 0–9, F1–F6 for 10–15.
+fn channel_key(code: KeyCode) -> Option<u8> {
+    match code {
+        KeyCode::Char(c @ '0'..='9') => Some(c as u8 - b'0'),
+        KeyCode::F(n @ 1..=6) => Some(9 + n),
+        _ => None,
+    }
+}
+
+pub struct RawGuard;
+impl RawGuard {
+    pub fn enter() -> anyhow::Result<Self> { enable_raw_mode()?; Ok(Self) }
+}
+impl Drop for RawGuard {
+    fn drop(&mut self) { let _ = disable_raw_mode(); }
+}
+
+/// Open the audio output, play `tl` and handle playback keys until it ends or Esc is hit.
+///
+/// Without a terminal (piped stdin, CI) there are no keys to read, so it just plays to the end.
+pub fn play_timeline(
+    soundfont: &str,
+    out_opts: &OutputOptions,
+    tl: &Timeline,
+    mix: Arc<ChannelMix>,
+    transport: Arc<Transport>,
+) -> Result<()> {
+    let audio = Audio::new(soundfont, out_opts).context("audio init failed")?;
+    audio.start().context("audio start failed")?;
+
+    // start playback and get a handle
+    let player = audio.play_timeline(tl, mix, transport);
+
+    if !stdin().is_terminal() {
+        while !player.is_finished() {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        println!("Playback finished.");
+        return Ok(());
+    }
+
+    // enter raw mode to capture keys immediately
+    // raw mode guard
+    let _raw = RawGuard::enter()?;
+    println!("Controls: Space = pause/resume, Esc = stop, 0-9/F1-F6 = mute channel 0-15,\r");
+    println!("          -/+ = speed, [/] = transpose\r");
+
+    loop {
+        // quit this loop if the song finished by itself
+        if player.is_finished() {
+            println!("Playback finished.\r");
+            break;
+        }
+
+        // poll for key events with a short timeout
+        if event::poll(Duration::from_millis(50))? && let Event::Key(k) = event::read()? {
+            match k.code {
+                KeyCode::Char(' ') => player.toggle(),
+                code if channel_key(code).is_some() => {
+                    let ch = channel_key(code).unwrap();
+                    let muted = player.mix().toggle_mute(ch);
+                    print!("[ch {} {}]\r\n", ch, if muted { "muted" } else { "unmuted" });
+                    stdout().flush().ok();
+                }
+                KeyCode::Char(c @ ('-' | '+' | '=')) => {
+                    let speed = player.transport().nudge_speed(if c == '-' { -5 } else { 5 });
+                    print!("[speed {:.2}x]\r\n", speed);
+                    stdout().flush().ok();
+                }
+                KeyCode::Char(c @ ('[' | ']')) => {
+                    let st = player.transport().nudge_transpose(if c == '[' { -1 } else { 1 });
+                    print!("[transpose {:+}]\r\n", st);
+                    stdout().flush().ok();
+                }
+                KeyCode::Esc => {
+                    player.stop(); // stop current song
+                    break;
+                }
+                KeyCode::Char('c') if k.modifiers.contains(KeyModifiers::CONTROL) => {
+                    player.stop(); // stop current song
+                    break;
+                }
+                _ => {}
+            }
+        }
+        // small idle sleep to keep CPU down
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    Ok(())
+}
+
+/// Seek step for the arrow keys.
+const SEEK_STEP_S: i64 = 10;
+
+/// Open the audio output, stream a digital track and handle playback keys until it ends
+/// or Esc is hit. Like `play_timeline`, just plays to the end without a terminal.
+pub fn play_digital(soundfont: &str, out_opts: &OutputOptions, track: Box<dyn Source>) -> Result<()> {
+    let audio = Audio::new(soundfont, out_opts).context("audio init failed")?;
+    audio.start().context("audio start failed")?;
+    let deck = audio.play_digital(track);
+
+    if !stdin().is_terminal() {
+        while !deck.is_finished() {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        println!("Playback finished.");
+        return Ok(());
+    }
+
+    let _raw = RawGuard::enter()?;
+    println!("Controls: Space = pause/resume, Esc = stop, Left/Right = seek {}s\r", SEEK_STEP_S);
+
+    loop {
+        if deck.is_finished() {
+            println!("Playback finished.\r");
+            break;
+        }
+        if event::poll(Duration::from_millis(50))? && let Event::Key(k) = event::read()? {
+            match k.code {
+                KeyCode::Char(' ') => deck.toggle(),
+                KeyCode::Left | KeyCode::Right => {
+                    let to = deck.seek_by(if k.code == KeyCode::Left { -SEEK_STEP_S } else { SEEK_STEP_S });
+                    let len = deck.duration_us().map(format_duration).unwrap_or_else(|| "--:--".into());
+                    print!("[{} / {}]\r\n", format_duration(to), len);
+                    stdout().flush().ok();
+                }
+                KeyCode::Esc => {
+                    deck.stop();
+                    break;
+                }
+                KeyCode::Char('c') if k.modifiers.contains(KeyModifiers::CONTROL) => {
+                    deck.stop();
+                    break;
+                }
+                _ => {}
+            }
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::GameId;
+    use crate::wad::WadBuilder;
+
+    #[test]
+    fn iwad_songs_keep_their_mus_diagnostics() {
+        // Score length says 10 bytes but the end of score comes after 3
+        let mut mus = b"MUS\x1A".to_vec();
+        mus.extend_from_slice(&10u16.to_le_bytes());
+        mus.extend_from_slice(&16u16.to_le_bytes());
+        mus.extend_from_slice(&[0; 8]);
+        mus.extend_from_slice(&[0x10, 60, 0x60]);
+
+        let mut b = WadBuilder::default();
+        b.lump("MAP01", b"");
+        b.lump("D_RUNNIN", &mus);
+        let mut bytes = b.to_bytes();
+        bytes[..4].copy_from_slice(b"IWAD");
+        let path = std::env::temp_dir().join(format!("commands-iwad-{}.wad", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let lib = Resources { wad: &path, deh: &[], iwad: None }.open();
+        std::fs::remove_file(&path).unwrap();
+        let Library { wad, names, maps, originals, identity } = lib.unwrap();
+        assert_eq!(identity.map(|i| i.game), Some(GameId::Doom2));
+
+        // The default (native) profile holds for a known game too, so warnings still show
+        let bytes = read_song(&wad, &names[0]).unwrap();
+        let mut info = SongInfo::inspect(&names[0], "doom2.wad", bytes, MusOptions::default());
+        annotate(&mut info, bytes, &maps, originals.as_ref());
+        assert_eq!(info.title.as_deref(), Some("Running from Evil"));
+        assert_eq!(info.diagnostics.len(), 1);
+    }
+}
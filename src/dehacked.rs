@@ -0,0 +1,118 @@
+//! dehacked.rs
+//!
+//! The music side of DEHACKED patches: BEX `[MUSIC]` blocks (`RUNNIN = MYSONG`) and
+//! plain DeHackEd `Text` replacements of a song name. Either way the engine then
+//! plays D_MYSONG wherever it used to play D_RUNNIN. Everything else in a patch
+//! (things, frames, other strings) is skipped.
+//!
+//! Patches come from DEHACKED lumps or from .deh/.bex files given with `--deh`;
+//! `MapTable::apply_dehacked` applies them.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// One song renamed by a patch, both names without the D_ prefix, upper-cased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MusicRename {
+    pub from: String,
+    pub to: String,
+}
+
+/// The music renames in a patch, in the order they appear.
+#[derive(Debug, Clone, Default)]
+pub struct Dehacked {
+    pub renames: Vec<MusicRename>,
+}
+
+impl Dehacked {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        Ok(Self::parse(&String::from_utf8_lossy(&bytes)))
+    }
+
+    pub fn parse(text: &str) -> Self {
+        // DeHackEd counts `Text` lengths without carriage returns
+        let text = text.replace('\r', "");
+        let mut deh = Dehacked::default();
+        let mut rest = text.as_str();
+        let mut in_music = false;
+        while !rest.is_empty() {
+            let (line, after) = rest.split_once('\n').unwrap_or((rest, ""));
+            rest = after;
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[') {
+                in_music = section.trim_end_matches(']').trim().eq_ignore_ascii_case("MUSIC");
+                continue;
+            }
+            if let Some((old_len, new_len)) = text_header(line) {
+                // The old and new strings follow back to back, newlines included
+                let end = rest.char_indices().nth(old_len + new_len).map_or(rest.len(), |(i, _)| i);
+                let (body, after) = rest.split_at(end);
+                rest = after;
+                let split = body.char_indices().nth(old_len).map_or(body.len(), |(i, _)| i);
+                let (from, to) = body.split_at(split);
+                if is_song_name(from) && is_song_name(to) {
+                    deh.push(from, to);
+                }
+                in_music = false;
+                continue;
+            }
+            // A BEX block runs to the first blank line or line that isn't `key = value`
+            match line.split_once('=') {
+                Some((from, to)) if in_music => deh.push(from.trim(), to.trim()),
+                _ => in_music = false,
+            }
+        }
+        deh
+    }
+
+    fn push(&mut self, from: &str, to: &str) {
+        self.renames.push(MusicRename { from: from.to_ascii_uppercase(), to: to.to_ascii_uppercase() });
+    }
+}
+
+/// The two lengths of a `Text <old> <new>` line.
+fn text_header(line: &str) -> Option<(usize, usize)> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("text") {
+        return None;
+    }
+    Some((words.next()?.parse().ok()?, words.next()?.parse().ok()?))
+}
+
+/// Whether a replaced string could be a song name: song names fit in a lump name
+/// after the D_ prefix.
+fn is_song_name(s: &str) -> bool {
+    (1..=6).contains(&s.len()) && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bex_music_blocks_and_text_replacements() {
+        let patch = "Patch File for DeHackEd v3.0\r\n\
+            # a comment\r\n\
+            Text 6 6\r\n\
+            RUNNINMYSONG\r\n\
+            Text 5 8\r\n\
+            HELLOGOODBYE!\r\n\
+            \r\n\
+            [MUSIC]\r\n\
+            stalks = dead\r\n\
+            # still in the block\r\n\
+            COUNTD = LOOP01\r\n\
+            \r\n\
+            BETWEE = NOPE\r\n\
+            [CODEPTR]\r\n\
+            FRAME 1 = Look\r\n";
+        let deh = Dehacked::parse(patch);
+        let pairs: Vec<_> = deh.renames.iter().map(|r| (r.from.as_str(), r.to.as_str())).collect();
+        assert_eq!(pairs, [("RUNNIN", "MYSONG"), ("STALKS", "DEAD"), ("COUNTD", "LOOP01")]);
+    }
+}
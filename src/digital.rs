@@ -0,0 +1,537 @@
+//! digital.rs
+//!
+//! Digital music lumps (OGG Vorbis, MP3, FLAC, WAV), which many PWADs use in place of
+//! MUS/MIDI, and the streaming path they share with tracker modules.
+//!
+//! Anything that produces PCM is a `Source`: Symphonia-decoded `Track`s here,
+//! `tracker::Player` for MOD/S3M/XM/IT and `imf::ImfPlayer` for AdLib songs. Decoding
+//! runs on a feeder thread, one block at a time, so a long track is never held in
+//! memory whole. The feeder resamples to the output rate and queues a few blocks; the
+//! output callback (see `synth::Audio`) drains them and mixes them over whatever the
+//! synth renders, so device, null and WAV outputs all work the same. `Deck` is the
+//! handle for pausing, stopping and seeking.
+
+use std::{
+    io::Cursor,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+    units::Time,
+};
+
+use crate::imf::{ImfPlayer, ImfRate};
+use crate::song::SongFormat;
+use crate::tracker::{Module, Player};
+use crate::wav::WavWriter;
+
+/// Decoded blocks queued ahead of the output. A packet is ~20–50 ms, so this is well
+/// under a second of audio.
+const QUEUE_BLOCKS: usize = 16;
+
+/// Stereo PCM produced on demand: a decoded track or a rendered module.
+pub trait Source: Send {
+    fn sample_rate(&self) -> u32;
+
+    /// Length, if known up front.
+    fn duration_us(&self) -> Option<u64>;
+
+    /// The next run of interleaved stereo samples, or `None` at the end.
+    fn next_block(&mut self) -> Result<Option<Vec<f32>>>;
+
+    /// Seek to `us` microseconds in.
+    fn seek(&mut self, us: u64) -> Result<()>;
+}
+
+/// Open a digital, module or IMF lump for streaming, printing any module load warnings.
+pub fn open(bytes: Vec<u8>, format: SongFormat, imf_rate: ImfRate) -> Result<Box<dyn Source>> {
+    if format == SongFormat::Imf {
+        return Ok(Box::new(ImfPlayer::new(&bytes, imf_rate)?));
+    }
+    if format.is_module() {
+        let module = Module::load(&bytes, format)?;
+        for w in &module.warnings {
+            eprintln!("warning: {} {}", format.name(), w);
+        }
+        return Ok(Box::new(Player::new(module)));
+    }
+    Ok(Box::new(Track::open(bytes, format)?))
+}
+
+/// An opened digital track, decoding on demand.
+pub struct Track {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    sample_rate: u32,
+    channels: usize,
+    /// Length in frames, if the container says
+    frames: Option<u64>,
+    /// Frames to drop from the next decoded packet, after seeking into its middle
+    skip: usize,
+}
+
+impl Track {
+    /// Probe `bytes` and set up a decoder for its first audio track.
+    pub fn open(bytes: Vec<u8>, format: SongFormat) -> Result<Self> {
+        let mut hint = Hint::new();
+        hint.with_extension(format.extension());
+        let source = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+        let opts = FormatOptions { enable_gapless: true, ..Default::default() };
+        let probed = symphonia::default::get_probe()
+            .format(&hint, source, &opts, &MetadataOptions::default())
+            .context("unrecognized audio data")?;
+        let reader = probed.format;
+
+        let track = reader
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .context("no audio track")?;
+        let params = &track.codec_params;
+        let decoder = symphonia::default::get_codecs()
+            .make(params, &DecoderOptions::default())
+            .context("unsupported codec")?;
+
+        Ok(Self {
+            track_id: track.id,
+            sample_rate: params.sample_rate.context("unknown sample rate")?,
+            channels: params.channels.map_or(2, |c| c.count()),
+            frames: params.n_frames,
+            skip: 0,
+            format: reader,
+            decoder,
+        })
+    }
+}
+
+impl Source for Track {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Length of the track, if the container records it (MP3s without a Xing/VBRI
+    /// header don't).
+    fn duration_us(&self) -> Option<u64> {
+        self.frames.map(|f| f * 1_000_000 / self.sample_rate as u64)
+    }
+
+    /// Decode the next packet as interleaved stereo, or `None` at the end.
+    ///
+    /// Packets that fail to decode are skipped, the way players ride over a bad frame.
+    fn next_block(&mut self) -> Result<Option<Vec<f32>>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(p) => p,
+                Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(None);
+                }
+                Err(SymphoniaError::ResetRequired) => {
+                    self.decoder.reset();
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                    buf.copy_interleaved_ref(decoded);
+                    let frames = buf.samples().len() / self.channels.max(1);
+                    let skip = self.skip.min(frames);
+                    self.skip -= skip;
+                    if skip == frames {
+                        continue;
+                    }
+                    return Ok(Some(to_stereo(&buf.samples()[skip * self.channels.max(1)..], self.channels)));
+                }
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Seek to `us` microseconds in.
+    ///
+    /// Containers seek to a packet boundary at or before the target; the frames in
+    /// between are decoded and dropped so playback resumes exactly at `us`.
+    fn seek(&mut self, us: u64) -> Result<()> {
+        let time = Time::from(Duration::from_micros(us));
+        let seeked = self.format.seek(SeekMode::Accurate, SeekTo::Time { time, track_id: Some(self.track_id) })?;
+        self.decoder.reset();
+        // Timestamps are in frames for every codec handled here
+        self.skip = seeked.required_ts.saturating_sub(seeked.actual_ts) as usize;
+        Ok(())
+    }
+}
+
+/// Interleaved samples of any channel count as stereo: mono is doubled, anything past
+/// the first two channels is dropped.
+fn to_stereo(samples: &[f32], channels: usize) -> Vec<f32> {
+    match channels {
+        2 => samples.to_vec(),
+        0 | 1 => samples.iter().flat_map(|&s| [s, s]).collect(),
+        n => samples.chunks_exact(n).flat_map(|f| [f[0], f[1]]).collect(),
+    }
+}
+
+/// Linear interpolation between sample rates. Crude next to a windowed sinc, but
+/// inaudible at the usual 44.1k/48k conversion for game music.
+struct Resampler {
+    /// Input frames per output frame
+    step: f64,
+    /// Position of the next output frame, in input frames; -1 is `last`
+    pos: f64,
+    /// Final frame of the previous block, to interpolate across the seam
+    last: [f32; 2],
+}
+
+impl Resampler {
+    fn new(from: u32, to: u32) -> Self {
+        Self { step: from as f64 / to as f64, pos: 0.0, last: [0.0; 2] }
+    }
+
+    fn reset(&mut self) {
+        self.pos = 0.0;
+        self.last = [0.0; 2];
+    }
+
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.step == 1.0 {
+            return input.to_vec();
+        }
+        let n = input.len() / 2;
+        if n == 0 {
+            return Vec::new();
+        }
+        let frame = |i: isize| if i < 0 { self.last } else { [input[2 * i as usize], input[2 * i as usize + 1]] };
+        let mut out = Vec::with_capacity((n as f64 / self.step) as usize * 2 + 2);
+        while (self.pos.floor() as isize) < n as isize - 1 {
+            let i = self.pos.floor() as isize;
+            let f = (self.pos - i as f64) as f32;
+            let (a, b) = (frame(i), frame(i + 1));
+            out.push(a[0] + (b[0] - a[0]) * f);
+            out.push(a[1] + (b[1] - a[1]) * f);
+            self.pos += self.step;
+        }
+        self.pos -= n as f64;
+        self.last = frame(n as isize - 1);
+        out
+    }
+}
+
+/// State shared between the `Deck`, the feeder thread and the output callback.
+#[derive(Default)]
+struct DeckState {
+    paused: AtomicBool,
+    stopped: AtomicBool,
+    finished: AtomicBool,
+    /// Output frames played so far, as a position in the track
+    position: AtomicU64,
+    /// Bumped on every seek so blocks decoded before it are thrown away
+    generation: AtomicU32,
+    seek_to: Mutex<Option<u64>>,
+}
+
+/// A run of resampled audio and where in the track it starts.
+struct Block {
+    generation: u32,
+    start_frame: u64,
+    samples: Vec<f32>,
+}
+
+/// Controls a playing digital track: the counterpart of `synth::Player`.
+pub struct Deck {
+    state: Arc<DeckState>,
+    sample_rate: u32,
+    duration_us: Option<u64>,
+}
+
+impl Deck {
+    pub fn toggle(&self) {
+        self.state.paused.fetch_xor(true, Ordering::SeqCst);
+    }
+    pub fn stop(&self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+    }
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::SeqCst)
+    }
+    pub fn duration_us(&self) -> Option<u64> {
+        self.duration_us
+    }
+    pub fn position_us(&self) -> u64 {
+        self.state.position.load(Ordering::SeqCst) * 1_000_000 / self.sample_rate as u64
+    }
+
+    /// Jump to `us` into the track. Takes effect within a block or two.
+    pub fn seek(&self, us: u64) {
+        let us = self.duration_us.map_or(us, |d| us.min(d));
+        *self.state.seek_to.lock().unwrap() = Some(us);
+        self.state.generation.fetch_add(1, Ordering::SeqCst);
+        self.state.position.store(us * self.sample_rate as u64 / 1_000_000, Ordering::SeqCst);
+    }
+
+    /// Seek relative to the current position, clamped to the start.
+    pub fn seek_by(&self, delta_s: i64) -> u64 {
+        let to = (self.position_us() as i64 + delta_s * 1_000_000).max(0) as u64;
+        self.seek(to);
+        to
+    }
+}
+
+/// Dropping the deck stops the track, so the feeder never outlives both ends.
+impl Drop for Deck {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The output side of a deck, owned by the audio callback.
+pub struct Feed {
+    rx: Receiver<Block>,
+    block: Option<Block>,
+    offset: usize,
+    state: Arc<DeckState>,
+}
+
+/// Where the output callback finds the current feed, if a digital track is playing.
+pub type FeedSlot = Arc<Mutex<Option<Feed>>>;
+
+/// Start decoding `track` for output at `sample_rate` and install it in `slot`,
+/// replacing whatever was playing there.
+pub fn start(track: Box<dyn Source>, sample_rate: u32, slot: &FeedSlot) -> Deck {
+    let state = Arc::new(DeckState::default());
+    let (tx, rx) = mpsc::sync_channel(QUEUE_BLOCKS);
+    let deck = Deck { state: state.clone(), sample_rate, duration_us: track.duration_us() };
+
+    let feeder_state = state.clone();
+    thread::spawn(move || feed(track, sample_rate, tx, feeder_state));
+
+    *slot.lock().unwrap() = Some(Feed { rx, block: None, offset: 0, state });
+    deck
+}
+
+fn feed(mut track: Box<dyn Source>, sample_rate: u32, tx: SyncSender<Block>, state: Arc<DeckState>) {
+    let mut resampler = Resampler::new(track.sample_rate(), sample_rate);
+    let mut generation = state.generation.load(Ordering::SeqCst);
+    let mut frame = 0u64;
+    while !state.stopped.load(Ordering::SeqCst) {
+        if let Some(us) = state.seek_to.lock().unwrap().take() {
+            generation = state.generation.load(Ordering::SeqCst);
+            match track.seek(us) {
+                Ok(()) => frame = us * sample_rate as u64 / 1_000_000,
+                Err(e) => eprintln!("seek failed: {e:#}"),
+            }
+            resampler.reset();
+        }
+        match track.next_block() {
+            Ok(Some(samples)) => {
+                let samples = resampler.process(&samples);
+                let len = samples.len() as u64 / 2;
+                // Blocks when the queue is full; fails once the output dropped the feed
+                if tx.send(Block { generation, start_frame: frame, samples }).is_err() {
+                    return;
+                }
+                frame += len;
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("decode error: {e:#}"),
+        }
+        // An empty block marks the end. A seek made before the output reaches it still
+        // restarts decoding; once the output reads it the deck is finished and we exit.
+        if tx.send(Block { generation, start_frame: frame, samples: Vec::new() }).is_err() {
+            return;
+        }
+        while state.seek_to.lock().unwrap().is_none() {
+            if state.stopped.load(Ordering::SeqCst) || state.finished.load(Ordering::SeqCst) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+impl Feed {
+    /// Add the next `out.len() / 2` frames to `out` (interleaved stereo).
+    ///
+    /// Silence while paused or if the feeder falls behind. Returns false once the
+    /// track has ended or been stopped, so the caller can drop the feed.
+    fn mix_into(&mut self, out: &mut [f32]) -> bool {
+        let state = &*self.state;
+        if state.stopped.load(Ordering::SeqCst) {
+            state.finished.store(true, Ordering::SeqCst);
+            return false;
+        }
+        let generation = state.generation.load(Ordering::SeqCst);
+        if self.block.as_ref().is_some_and(|b| b.generation != generation) {
+            self.block = None;
+        }
+        let paused = state.paused.load(Ordering::SeqCst);
+
+        let mut i = 0;
+        while i < out.len() {
+            if self.block.as_ref().is_none_or(|b| self.offset >= b.samples.len()) {
+                match self.rx.try_recv() {
+                    // Stale blocks are drained even while paused, so a seek gets through
+                    Ok(b) if b.generation != generation => continue,
+                    Ok(b) if b.samples.is_empty() => {
+                        state.finished.store(true, Ordering::SeqCst);
+                        return false;
+                    }
+                    Ok(b) => {
+                        self.block = Some(b);
+                        self.offset = 0;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        state.finished.store(true, Ordering::SeqCst);
+                        return false;
+                    }
+                }
+            }
+            if paused {
+                break;
+            }
+            let b = self.block.as_ref().unwrap();
+            let n = (out.len() - i).min(b.samples.len() - self.offset);
+            for (o, s) in out[i..i + n].iter_mut().zip(&b.samples[self.offset..self.offset + n]) {
+                *o += s;
+            }
+            i += n;
+            self.offset += n;
+            state.position.store(b.start_frame + self.offset as u64 / 2, Ordering::SeqCst);
+        }
+        true
+    }
+}
+
+/// Decode all of `track` into a WAV file at `sample_rate`, for `render`. Returns the
+/// number of frames written.
+pub fn render(mut track: Box<dyn Source>, path: &Path, sample_rate: u32) -> Result<u64> {
+    let mut resampler = Resampler::new(track.sample_rate(), sample_rate);
+    let mut wav = WavWriter::create(path, sample_rate, 2)?;
+    let mut frames = 0;
+    while let Some(block) = track.next_block()? {
+        let samples = resampler.process(&block);
+        wav.write_samples(&samples)?;
+        frames += samples.len() as u64 / 2;
+    }
+    wav.finish()?;
+    Ok(frames)
+}
+
+/// Mix the slot's feed into `out`, dropping it once it's done.
+pub fn mix_into(slot: &FeedSlot, out: &mut [f32]) {
+    let mut feed = slot.lock().unwrap();
+    if let Some(f) = feed.as_mut()
+        && !f.mix_into(out)
+    {
+        *feed = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One second of a stereo ramp at `rate`, as a WAV file.
+    fn ramp_wav(rate: u32) -> Vec<u8> {
+        let samples: Vec<f32> = (0..rate).flat_map(|i| {
+            let v = i as f32 / rate as f32;
+            [v, -v]
+        }).collect();
+        let mut w = WavWriter::new(Cursor::new(Vec::new()), rate, 2).unwrap();
+        w.write_samples(&samples).unwrap();
+        w.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn decodes_seeks_and_resamples_wav() {
+        let wav = ramp_wav(8000);
+        assert_eq!(SongFormat::detect(&wav), SongFormat::Wav);
+        let mut track = Track::open(wav, SongFormat::Wav).unwrap();
+        assert_eq!(track.sample_rate(), 8000);
+        assert_eq!(track.duration_us(), Some(1_000_000));
+
+        let mut all = Vec::new();
+        while let Some(b) = track.next_block().unwrap() {
+            all.extend(b);
+        }
+        assert_eq!(all.len(), 16_000);
+        assert!((all[2 * 4000] - 0.5).abs() < 1e-3 && (all[2 * 4000 + 1] + 0.5).abs() < 1e-3);
+
+        track.seek(500_000).unwrap();
+        let first = track.next_block().unwrap().unwrap();
+        assert!((first[0] - 0.5).abs() < 1e-3, "resumed at {}", first[0]);
+
+        // 8 kHz -> 16 kHz doubles the frame count and keeps the ramp continuous across blocks
+        let mut r = Resampler::new(8000, 16000);
+        let out: Vec<f32> = all.chunks(2 * 1000).flat_map(|c| r.process(c)).collect();
+        assert!((out.len() as i64 - 32_000).abs() <= 4);
+        assert!(out.chunks(2).zip(out.chunks(2).skip(1)).all(|(a, b)| (b[0] - a[0]).abs() < 1e-3));
+    }
+
+    #[test]
+    fn feed_mixes_pauses_and_finishes() {
+        let slot: FeedSlot = Arc::default();
+        let deck = start(open(ramp_wav(8000), SongFormat::Wav, ImfRate::default()).unwrap(), 8000, &slot);
+
+        let mut buf = vec![0.0f32; 2 * 800];
+        // Wait for the feeder to queue something
+        while deck.position_us() == 0 {
+            thread::sleep(Duration::from_millis(1));
+            mix_into(&slot, &mut buf);
+        }
+        deck.toggle();
+        let at = deck.position_us();
+        buf.fill(0.0);
+        mix_into(&slot, &mut buf);
+        assert_eq!(deck.position_us(), at);
+        assert!(buf.iter().all(|&s| s == 0.0));
+        deck.toggle();
+
+        deck.seek(900_000);
+        while !deck.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+            mix_into(&slot, &mut buf);
+        }
+        assert!(slot.lock().unwrap().is_none());
+        assert!(deck.position_us() >= 990_000);
+    }
+
+    #[test]
+    fn dropping_the_deck_ends_the_feeder() {
+        let slot: FeedSlot = Arc::default();
+        let deck = start(open(ramp_wav(8000), SongFormat::Wav, ImfRate::default()).unwrap(), 8000, &slot);
+        let state = deck.state.clone();
+        // Nothing left to decode: the feeder queues the end marker and waits for a seek
+        deck.seek(u64::MAX);
+        thread::sleep(Duration::from_millis(20));
+        drop(deck);
+        slot.lock().unwrap().take();
+        let started = std::time::Instant::now();
+        while Arc::strong_count(&state) > 1 {
+            assert!(started.elapsed() < Duration::from_secs(2), "feeder still running");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! folder.rs
+//!
+//! A directory of loose resources opened as if it were a WAD, for auditioning work in
+//! progress: `music/D_RUNNIN.mid` is the lump D_RUNNIN in the music namespace, and so
+//! on, laid out exactly like a PK3 (see `pk3::layout`). `pack` writes the result out
+//! as a real PWAD.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::pk3::{self, NamedLump};
+use crate::wad::Wad;
+
+/// Read every file under `root` and lay it out as a WAD.
+///
+/// Files are taken in name order, so the result doesn't depend on the file system.
+/// Hidden files and folders (`.git`, `.DS_Store`) are skipped.
+pub fn to_wad(root: &Path) -> Result<Wad> {
+    let mut files = Vec::new();
+    collect(root, "", &mut files)?;
+    pk3::layout(files)
+}
+
+fn collect(dir: &Path, prefix: &str, files: &mut Vec<NamedLump>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("reading directory {:?}", dir))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for e in entries {
+        let name = e.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = e.path();
+        let rel = format!("{prefix}{name}");
+        // Follows symlinks, so linked-in folders work
+        if path.is_dir() {
+            collect(&path, &format!("{rel}/"), files)?;
+        } else {
+            let bytes = fs::read(&path).with_context(|| format!("reading {:?}", path))?;
+            files.push((rel, bytes));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::Namespace;
+
+    #[test]
+    fn folders_read_like_a_pk3() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("music/wip")).unwrap();
+        fs::create_dir_all(root.join("sounds")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join("music/D_RUNNIN.mid"), b"MThd").unwrap();
+        fs::write(root.join("music/wip/d_romero_remix.mus"), b"MUS\x1a").unwrap();
+        fs::write(root.join("sounds/DSPISTOL.wav"), b"RIFF").unwrap();
+        fs::write(root.join(".git/HEAD"), b"ref").unwrap();
+
+        let wad = to_wad(root).unwrap();
+        assert_eq!(wad.read_in("D_RUNNIN", &[Namespace::Music]).unwrap(), b"MThd");
+        assert_eq!(wad.read_in("d_romero", &[Namespace::Music]).unwrap(), b"MUS\x1a");
+        assert_eq!(wad.read_in("DSPISTOL", &[Namespace::Sounds]).unwrap(), b"RIFF");
+        assert!(!wad.contains("HEAD"));
+
+        // Packing round-trips through a real PWAD
+        let packed = Wad::from_bytes(wad.bytes().to_vec()).unwrap();
+        assert_eq!(packed.names().collect::<Vec<_>>(), wad.names().collect::<Vec<_>>());
+        assert_eq!(packed.read("D_RUNNIN").unwrap(), b"MThd");
+    }
+}
@@ -0,0 +1,211 @@
+//! game.rs
+//!
+//! Which game an IWAD belongs to, and which release of it. Known retail IWADs are
+//! matched by MD5 first, which also gives the version; anything else is told by lumps
+//! only that game (or release) has, the way source ports pick their game. Only IWADs
+//! are identified: a PWAD's maps say nothing reliable about what it was made for.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::wad::Wad;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GameId {
+    /// Episode 1 only
+    DoomShareware,
+    /// Episodes 1 to 3
+    DoomRegistered,
+    /// Episodes 1 to 4
+    UltimateDoom,
+    Doom2,
+    /// Final Doom: TNT: Evilution
+    Tnt,
+    /// Final Doom: The Plutonia Experiment
+    Plutonia,
+    Heretic,
+    Hexen,
+    Strife,
+    /// Chex Quest
+    Chex,
+    /// Freedoom: Phase 1, standing in for Doom
+    Freedoom1,
+    /// Freedoom: Phase 2, standing in for Doom II
+    Freedoom2,
+}
+
+/// Lumps that must all be present, checked in order, so more specific games come
+/// before the games they build on.
+const MARKERS: &[(GameId, &[&str])] = &[
+    (GameId::Freedoom2, &["FREEDOOM", "MAP01"]),
+    (GameId::Freedoom1, &["FREEDOOM", "E1M1"]),
+    (GameId::Strife, &["ENDSTRF", "MAP01"]),
+    (GameId::Chex, &["W94_1", "E1M1"]),
+    (GameId::Hexen, &["TITLE", "MAP01", "WINNOWR"]),
+    (GameId::Tnt, &["MAP01", "REDTNT2"]),
+    (GameId::Plutonia, &["MAP01", "CAMO1"]),
+    (GameId::Doom2, &["MAP01"]),
+    (GameId::Heretic, &["E1M1", "MUS_E1M1"]),
+    (GameId::UltimateDoom, &["E4M1"]),
+    (GameId::DoomRegistered, &["E3M1"]),
+    (GameId::DoomShareware, &["E1M1"]),
+];
+
+/// MD5s of retail IWADs, with the release each one is.
+const KNOWN_IWADS: &[(&str, GameId, &str)] = &[
+    ("f0cefca49926d00903cf57551d901abe", GameId::DoomShareware, "1.9"),
+    ("1cd63c5ddff1bf8ce844237f580e9cf3", GameId::DoomRegistered, "1.9"),
+    ("c4fe9fd920207691a9f493668e0a2083", GameId::UltimateDoom, "1.9"),
+    ("fb35c4a5a9fd49ec29ab6e900572c524", GameId::UltimateDoom, "BFG Edition"),
+    ("30e3c2d0350b67bfbf47271970b74b2f", GameId::Doom2, "1.666"),
+    ("ea74a47a791fdef2e9f2ea8b8a9da13b", GameId::Doom2, "1.7"),
+    ("25e1459ca71d321525f84628f45ca8cd", GameId::Doom2, "1.9"),
+    ("c3bea40570c23e511a7ed3ebcd9865f7", GameId::Doom2, "BFG Edition"),
+    ("4e158d9953c79ccf97bd0663244cc6b6", GameId::Tnt, "1.9"),
+    ("75c8cf89566741fa9d22447604053bd7", GameId::Plutonia, "1.9"),
+    ("66d686b1ed6d35ff103f15dbd30e0341", GameId::Heretic, "1.3"),
+    ("abb033caf81e26f12a2103e1fa25453f", GameId::Hexen, "1.1"),
+    ("2fed2031a5b03892106e0f117f17901f", GameId::Strife, "1.2"),
+    ("25485721882b050afa96a56e5758dd52", GameId::Chex, "1.0"),
+];
+
+/// Lumps that give away a release when the hash isn't known, e.g. a re-released
+/// IWAD that was patched afterwards.
+const RELEASE_MARKERS: &[(&str, &str)] = &[("DMENUPIC", "BFG Edition")];
+
+/// What an IWAD was identified as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Identity {
+    pub game: GameId,
+    /// Version or edition, e.g. "1.9" or "BFG Edition", when it can be told
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release: Option<&'static str>,
+}
+
+impl Identity {
+    pub fn of(wad: &Wad) -> Option<Self> {
+        if !wad.is_iwad() {
+            return None;
+        }
+        let md5 = format!("{:x}", md5::compute(wad.bytes()));
+        if let Some(&(_, game, release)) = KNOWN_IWADS.iter().find(|(h, _, _)| *h == md5) {
+            return Some(Identity { game, release: Some(release) });
+        }
+        let game = MARKERS
+            .iter()
+            .find(|(_, lumps)| lumps.iter().all(|l| wad.contains(l)))
+            .map(|&(game, _)| game)?;
+        let release = RELEASE_MARKERS.iter().find(|(l, _)| wad.contains(l)).map(|&(_, r)| r);
+        Some(Identity { game, release })
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.release {
+            Some(r) => write!(f, "{} ({})", self.game.name(), r),
+            None => f.write_str(self.game.name()),
+        }
+    }
+}
+
+impl GameId {
+    /// The game's name as shown to the user.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DoomShareware => "Doom (shareware)",
+            Self::DoomRegistered => "Doom (registered)",
+            Self::UltimateDoom => "The Ultimate Doom",
+            Self::Doom2 => "Doom II",
+            Self::Tnt => "Final Doom: TNT: Evilution",
+            Self::Plutonia => "Final Doom: The Plutonia Experiment",
+            Self::Heretic => "Heretic",
+            Self::Hexen => "Hexen",
+            Self::Strife => "Strife",
+            Self::Chex => "Chex Quest",
+            Self::Freedoom1 => "Freedoom: Phase 1",
+            Self::Freedoom2 => "Freedoom: Phase 2",
+        }
+    }
+
+    /// How many of Doom's episodes the game has, for games with ExMy maps and D_ music.
+    pub fn doom_episodes(self) -> usize {
+        match self {
+            Self::DoomShareware | Self::Chex => 1,
+            Self::DoomRegistered => 3,
+            Self::UltimateDoom | Self::Freedoom1 => 4,
+            _ => 0,
+        }
+    }
+
+    /// Whether the game plays Doom II's MAP01 to MAP32 songs.
+    pub fn has_doom2_music(self) -> bool {
+        matches!(self, Self::Doom2 | Self::Tnt | Self::Plutonia | Self::Freedoom2)
+    }
+
+    /// Prefix of the game's song lumps. Hexen's songs have none.
+    pub fn music_prefix(self) -> Option<&'static str> {
+        match self {
+            Self::Heretic => Some("MUS_"),
+            Self::Hexen => None,
+            _ => Some("D_"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::WadBuilder;
+
+    fn iwad(lumps: &[&str]) -> Wad {
+        let mut b = WadBuilder::default();
+        for l in lumps {
+            b.lump(l, b"");
+        }
+        let mut bytes = b.to_bytes();
+        bytes[..4].copy_from_slice(b"IWAD");
+        Wad::from_bytes(bytes).unwrap()
+    }
+
+    #[test]
+    fn identifies_games_by_their_lumps() {
+        let cases: &[(&[&str], Option<GameId>)] = &[
+            (&["E1M1", "D_E1M1"], Some(GameId::DoomShareware)),
+            (&["E1M1", "E2M1", "E3M1"], Some(GameId::DoomRegistered)),
+            (&["E1M1", "E3M1", "E4M1"], Some(GameId::UltimateDoom)),
+            (&["MAP01", "D_RUNNIN"], Some(GameId::Doom2)),
+            (&["MAP01", "REDTNT2"], Some(GameId::Tnt)),
+            (&["MAP01", "CAMO1"], Some(GameId::Plutonia)),
+            (&["E1M1", "E2M1", "MUS_E1M1", "TITLE"], Some(GameId::Heretic)),
+            (&["MAP01", "TITLE", "WINNOWR"], Some(GameId::Hexen)),
+            (&["MAP01", "ENDSTRF"], Some(GameId::Strife)),
+            (&["E1M1", "W94_1"], Some(GameId::Chex)),
+            (&["E1M1", "E4M1", "FREEDOOM"], Some(GameId::Freedoom1)),
+            (&["MAP01", "FREEDOOM"], Some(GameId::Freedoom2)),
+            (&["D_RUNNIN"], None),
+        ];
+        for &(lumps, game) in cases {
+            assert_eq!(Identity::of(&iwad(lumps)).map(|i| i.game), game, "{lumps:?}");
+        }
+
+        let mut pwad = WadBuilder::default();
+        pwad.lump("MAP01", b"");
+        assert_eq!(Identity::of(&pwad.build()), None);
+    }
+
+    #[test]
+    fn releases_come_from_hashes_or_lumps() {
+        let bfg = Identity::of(&iwad(&["MAP01", "DMENUPIC"])).unwrap();
+        assert_eq!(bfg, Identity { game: GameId::Doom2, release: Some("BFG Edition") });
+        assert_eq!(bfg.to_string(), "Doom II (BFG Edition)");
+        assert_eq!(Identity::of(&iwad(&["MAP01"])).unwrap().to_string(), "Doom II");
+
+        // Compared against `{:x}` output, so they must be lowercase hex
+        for (h, _, _) in KNOWN_IWADS {
+            assert!(h.len() == 32 && h.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)), "{h}");
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! gm.rs
+//!
+//! General MIDI name tables: the 128 melodic programs and the standard percussion keys
+//! (35–81) on the drum channel. Used for reports, not for playback.
+
+/// GM Level 1 program names, indexed by program number (0-based).
+const PROGRAMS: [&str; 128] = [
+    // Piano
+    "Acoustic Grand Piano", "Bright Acoustic Piano", "Electric Grand Piano", "Honky-tonk Piano",
+    "Electric Piano 1", "Electric Piano 2", "Harpsichord", "Clavinet",
+    // Chromatic percussion
+    "Celesta", "Glockenspiel", "Music Box", "Vibraphone",
+    "Marimba", "Xylophone", "Tubular Bells", "Dulcimer",
+    // Organ
+    "Drawbar Organ", "Percussive Organ", "Rock Organ", "Church Organ",
+    "Reed Organ", "Accordion", "Harmonica", "Tango Accordion",
+    // Guitar
+    "Acoustic Guitar (nylon)", "Acoustic Guitar (steel)", "Electric Guitar (jazz)", "Electric Guitar (clean)",
+    "Electric Guitar (muted)", "Overdriven Guitar", "Distortion Guitar", "Guitar Harmonics",
+    // Bass
+    "Acoustic Bass", "Electric Bass (finger)", "Electric Bass (pick)", "Fretless Bass",
+    "Slap Bass 1", "Slap Bass 2", "Synth Bass 1", "Synth Bass 2",
+    // Strings
+    "Violin", "Viola", "Cello", "Contrabass",
+    "Tremolo Strings", "Pizzicato Strings", "Orchestral Harp", "Timpani",
+    // Ensemble
+    "String Ensemble 1", "String Ensemble 2", "Synth Strings 1", "Synth Strings 2",
+    "Choir Aahs", "Voice Oohs", "Synth Voice", "Orchestra Hit",
+    // Brass
+    "Trumpet", "Trombone", "Tuba", "Muted Trumpet",
+    "French Horn", "Brass Section", "Synth Brass 1", "Synth Brass 2",
+    // Reed
+    "Soprano Sax", "Alto Sax", "Tenor Sax", "Baritone Sax",
+    "Oboe", "English Horn", "Bassoon", "Clarinet",
+    // Pipe
+    "Piccolo", "Flute", "Recorder", "Pan Flute",
+    "Blown Bottle", "Shakuhachi", "Whistle", "Ocarina",
+    // Synth lead
+    "Lead 1 (square)", "Lead 2 (sawtooth)", "Lead 3 (calliope)", "Lead 4 (chiff)",
+    "Lead 5 (charang)", "Lead 6 (voice)", "Lead 7 (fifths)", "Lead 8 (bass + lead)",
+    // Synth pad
+    "Pad 1 (new age)", "Pad 2 (warm)", "Pad 3 (polysynth)", "Pad 4 (choir)",
+    "Pad 5 (bowed)", "Pad 6 (metallic)", "Pad 7 (halo)", "Pad 8 (sweep)",
+    // Synth effects
+    "FX 1 (rain)", "FX 2 (soundtrack)", "FX 3 (crystal)", "FX 4 (atmosphere)",
+    "FX 5 (brightness)", "FX 6 (goblins)", "FX 7 (echoes)", "FX 8 (sci-fi)",
+    // Ethnic
+    "Sitar", "Banjo", "Shamisen", "Koto",
+    "Kalimba", "Bagpipe", "Fiddle", "Shanai",
+    // Percussive
+    "Tinkle Bell", "Agogo", "Steel Drums", "Woodblock",
+    "Taiko Drum", "Melodic Tom", "Synth Drum", "Reverse Cymbal",
+    // Sound effects
+    "Guitar Fret Noise", "Breath Noise", "Seashore", "Bird Tweet",
+    "Telephone Ring", "Helicopter", "Applause", "Gunshot",
+];
+
+/// GM Level 1 percussion names for keys 35..=81 on the drum channel.
+const DRUMS: [&str; 47] = [
+    "Acoustic Bass Drum", "Bass Drum 1", "Side Stick", "Acoustic Snare",
+    "Hand Clap", "Electric Snare", "Low Floor Tom", "Closed Hi-Hat",
+    "High Floor Tom", "Pedal Hi-Hat", "Low Tom", "Open Hi-Hat",
+    "Low-Mid Tom", "Hi-Mid Tom", "Crash Cymbal 1", "High Tom",
+    "Ride Cymbal 1", "Chinese Cymbal", "Ride Bell", "Tambourine",
+    "Splash Cymbal", "Cowbell", "Crash Cymbal 2", "Vibraslap",
+    "Ride Cymbal 2", "Hi Bongo", "Low Bongo", "Mute Hi Conga",
+    "Open Hi Conga", "Low Conga", "High Timbale", "Low Timbale",
+    "High Agogo", "Low Agogo", "Cabasa", "Maracas",
+    "Short Whistle", "Long Whistle", "Short Guiro", "Long Guiro",
+    "Claves", "Hi Wood Block", "Low Wood Block", "Mute Cuica",
+    "Open Cuica", "Mute Triangle", "Open Triangle",
+];
+
+/// First key covered by `DRUMS`.
+const FIRST_DRUM_KEY: u8 = 35;
+
+/// Name of a melodic GM program (0–127).
+pub fn program_name(program: u8) -> &'static str {
+    PROGRAMS.get(program as usize).copied().unwrap_or("(invalid program)")
+}
+
+/// Name of a GM percussion key, or `None` outside the standard 35–81 range.
+pub fn drum_name(key: u8) -> Option<&'static str> {
+    key.checked_sub(FIRST_DRUM_KEY).and_then(|i| DRUMS.get(i as usize).copied())
+}
+
+/// Name of a patch from a MUS instrument list: 0–127 are programs, 135–181 percussion keys.
+pub fn mus_instrument_name(patch: u16) -> String {
+    match patch {
+        0..=127 => program_name(patch as u8).to_string(),
+        128..=255 => match drum_name((patch - 100) as u8) {
+            Some(name) => format!("{name} (percussion)"),
+            None => "(unknown percussion)".to_string(),
+        },
+        _ => "(invalid patch)".to_string(),
+    }
+}
+
+/// Scientific pitch name for a MIDI key, with middle C (60) as C4.
+pub fn note_name(key: u8) -> String {
+    const NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    format!("{}{}", NAMES[key as usize % 12], key as i32 / 12 - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_line_up_with_gm() {
+        assert_eq!(program_name(0), "Acoustic Grand Piano");
+        assert_eq!(program_name(30), "Distortion Guitar");
+        assert_eq!(program_name(127), "Gunshot");
+        assert_eq!(drum_name(35), Some("Acoustic Bass Drum"));
+        assert_eq!(drum_name(38), Some("Acoustic Snare"));
+        assert_eq!(drum_name(81), Some("Open Triangle"));
+        assert_eq!(drum_name(34), None);
+        assert_eq!(drum_name(82), None);
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(note_name(69), "A4");
+        assert_eq!(mus_instrument_name(30), "Distortion Guitar");
+        assert_eq!(mus_instrument_name(136), "Bass Drum 1 (percussion)");
+        assert_eq!(mus_instrument_name(300), "(invalid patch)");
+    }
+}
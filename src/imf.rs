@@ -0,0 +1,261 @@
+//! imf.rs
+//!
+//! id Software Music Format: the AdLib music of Wolfenstein 3D, Commander Keen and
+//! other id/Apogee games. A song is a list of OPL2 register writes, each followed by
+//! a delay in ticks, played here through `opl::Opl`.
+//!
+//! Type 0 files are the bare list; type 1 files put its length in bytes first and
+//! may have tags after it. Neither has a magic number, so `detect` checks that the
+//! data reads as sensible register writes. The tick rate isn't recorded either and
+//! depends on the game, hence `ImfRate`.
+
+use std::str::FromStr;
+
+use anyhow::Result;
+
+use crate::digital::Source;
+use crate::opl::{self, Opl};
+
+/// Most OPL frames rendered per block, so long rests don't make huge blocks.
+const BLOCK_FRAMES: u64 = 2048;
+
+/// Ticks per second of the delays.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImfRate {
+    /// Duke Nukem II
+    Hz280,
+    /// Commander Keen
+    Hz560,
+    /// Wolfenstein 3D and Spear of Destiny
+    #[default]
+    Hz700,
+}
+
+impl ImfRate {
+    pub fn hz(self) -> u32 {
+        match self {
+            Self::Hz280 => 280,
+            Self::Hz560 => 560,
+            Self::Hz700 => 700,
+        }
+    }
+}
+
+impl FromStr for ImfRate {
+    type Err = String;
+
+    /// Parses `280`, `560` or `700`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim_end_matches("hz").trim_end_matches("Hz") {
+            "280" => Ok(Self::Hz280),
+            "560" => Ok(Self::Hz560),
+            "700" => Ok(Self::Hz700),
+            _ => Err(format!("expected 280, 560 or 700, got {s:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImfKind {
+    Type0,
+    Type1,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ImfError {
+    #[error("not IMF data")]
+    NotImf,
+}
+
+/// One register write and the ticks to wait after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Command {
+    pub reg: u8,
+    pub value: u8,
+    pub delay: u16,
+}
+
+/// Whether `reg` is an OPL2 register (0 is allowed too, as songs start with a
+/// 0,0 write).
+fn is_register(reg: u8) -> bool {
+    matches!(reg, 0x00..=0x04 | 0x08 | 0x20..=0x35 | 0x40..=0x55 | 0x60..=0x75 | 0x80..=0x95
+        | 0xA0..=0xA8 | 0xB0..=0xB8 | 0xBD | 0xC0..=0xC8 | 0xE0..=0xF5)
+}
+
+/// Read `data` as commands if every write is to a real register and at least one
+/// keys a note on.
+fn commands(data: &[u8]) -> Option<Vec<Command>> {
+    if data.len() < 16 || !data.len().is_multiple_of(4) {
+        return None;
+    }
+    let cmds: Vec<Command> = data
+        .chunks_exact(4)
+        .map(|c| Command { reg: c[0], value: c[1], delay: u16::from_le_bytes([c[2], c[3]]) })
+        .collect();
+    let valid = cmds.iter().all(|c| is_register(c.reg))
+        && cmds.iter().any(|c| (0xB0..=0xB8).contains(&c.reg) && c.value & 0x20 != 0);
+    valid.then_some(cmds)
+}
+
+/// The commands of a type 1 file: a length that fits, then valid data.
+fn type1(bytes: &[u8]) -> Option<Vec<Command>> {
+    let len = u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]) as usize;
+    if len == 0 {
+        return None;
+    }
+    commands(bytes.get(2..2 + len)?)
+}
+
+pub fn detect(bytes: &[u8]) -> Option<ImfKind> {
+    parse(bytes).ok().map(|(kind, _)| kind)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<(ImfKind, Vec<Command>), ImfError> {
+    if let Some(cmds) = type1(bytes) {
+        return Ok((ImfKind::Type1, cmds));
+    }
+    commands(bytes).map(|c| (ImfKind::Type0, c)).ok_or(ImfError::NotImf)
+}
+
+/// Plays an IMF song through the OPL emulator.
+pub struct ImfPlayer {
+    commands: Vec<Command>,
+    rate: u32,
+    opl: Opl,
+    next: usize,
+    /// OPL frames to render before the next command
+    pending: u64,
+    /// Fraction of a frame carried between delays
+    carry: f64,
+    /// Ticks into the song
+    ticks: u64,
+}
+
+impl ImfPlayer {
+    pub fn new(bytes: &[u8], rate: ImfRate) -> Result<Self> {
+        let (_, commands) = parse(bytes)?;
+        Ok(Self { commands, rate: rate.hz(), opl: Opl::new(), next: 0, pending: 0, carry: 0.0, ticks: 0 })
+    }
+
+    pub fn length_us(&self) -> u64 {
+        let ticks: u64 = self.commands.iter().map(|c| c.delay as u64).sum();
+        ticks * 1_000_000 / self.rate as u64
+    }
+
+    /// Run commands up to the next delay, queueing its frames.
+    fn advance(&mut self) {
+        while self.pending == 0 && self.next < self.commands.len() {
+            let c = self.commands[self.next];
+            self.next += 1;
+            self.opl.write(c.reg, c.value);
+            self.ticks += c.delay as u64;
+            let exact = c.delay as f64 * opl::RATE as f64 / self.rate as f64 + self.carry;
+            self.pending = exact as u64;
+            self.carry = exact - self.pending as f64;
+        }
+    }
+}
+
+impl Source for ImfPlayer {
+    fn sample_rate(&self) -> u32 {
+        opl::RATE
+    }
+
+    fn duration_us(&self) -> Option<u64> {
+        Some(self.length_us())
+    }
+
+    fn next_block(&mut self) -> Result<Option<Vec<f32>>> {
+        self.advance();
+        if self.pending == 0 {
+            return Ok(None);
+        }
+        let frames = self.pending.min(BLOCK_FRAMES);
+        self.pending -= frames;
+        let mut mono = vec![0.0; frames as usize];
+        self.opl.render(&mut mono);
+        Ok(Some(mono.iter().flat_map(|&s| [s, s]).collect()))
+    }
+
+    /// Replays the register writes from the start without rendering, then skips into
+    /// the delay that spans `us`.
+    fn seek(&mut self, us: u64) -> Result<()> {
+        self.opl = Opl::new();
+        (self.next, self.pending, self.carry, self.ticks) = (0, 0, 0.0, 0);
+        let target = us * self.rate as u64 / 1_000_000;
+        while self.next < self.commands.len() {
+            let c = self.commands[self.next];
+            if self.ticks + c.delay as u64 > target {
+                break;
+            }
+            self.opl.write(c.reg, c.value);
+            self.ticks += c.delay as u64;
+            self.next += 1;
+        }
+        if let Some(c) = self.commands.get(self.next).copied() {
+            self.next += 1;
+            self.opl.write(c.reg, c.value);
+            let left = self.ticks + c.delay as u64 - target;
+            self.ticks += c.delay as u64;
+            self.pending = left * opl::RATE as u64 / self.rate as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A one-note song: a sine patch on channel 0, held for `ticks`, then released.
+    fn song(ticks: u16) -> Vec<u8> {
+        let writes: [(u8, u8, u16); 10] = [
+            (0x00, 0x00, 0),
+            (0x20, 0x21, 0),
+            (0x23, 0x21, 0),
+            (0x40, 0x3F, 0),
+            (0x43, 0x00, 0),
+            (0x63, 0xF0, 0),
+            (0x83, 0x0F, 0),
+            (0xA0, 0x44, 0),
+            (0xB0, 0x32, ticks),
+            (0xB0, 0x12, 0),
+        ];
+        writes.iter().flat_map(|&(r, v, d)| [[r, v], d.to_le_bytes()].concat()).collect()
+    }
+
+    #[test]
+    fn detects_both_types_and_times_them_by_rate() {
+        let body = song(700);
+        let mut type1 = (body.len() as u16).to_le_bytes().to_vec();
+        type1.extend(&body);
+        type1.extend(b"title\0");
+        assert_eq!(detect(&body), Some(ImfKind::Type0));
+        assert_eq!(detect(&type1), Some(ImfKind::Type1));
+        // Writes to registers the chip doesn't have, or no notes at all
+        assert_eq!(detect(&[0x10; 64]), None);
+        assert_eq!(detect(&body[..32]), None);
+
+        assert_eq!(ImfPlayer::new(&body, ImfRate::Hz700).unwrap().length_us(), 1_000_000);
+        assert_eq!(ImfPlayer::new(&type1, ImfRate::Hz560).unwrap().length_us(), 1_250_000);
+        assert_eq!("560".parse::<ImfRate>(), Ok(ImfRate::Hz560));
+    }
+
+    #[test]
+    fn renders_the_note_and_seeks_into_it() {
+        let mut p = ImfPlayer::new(&song(700), ImfRate::Hz700).unwrap();
+        let mut audio = Vec::new();
+        while let Some(b) = p.next_block().unwrap() {
+            audio.extend(b);
+        }
+        assert_eq!(audio.len() as u32, 2 * opl::RATE);
+        assert!(audio.iter().any(|s| s.abs() > 0.1));
+
+        p.seek(500_000).unwrap();
+        let mut rest = 0;
+        while let Some(b) = p.next_block().unwrap() {
+            rest += b.len() / 2;
+        }
+        assert_eq!(rest as u32, opl::RATE / 2);
+    }
+}
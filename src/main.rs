@@ -1,108 +1,396 @@
 use anyhow::Result;
-use clap::Parser;
-use std::{io::{stdin, stdout, Write}, path::PathBuf, thread, time::Duration};
-
-use crossterm::event::{self, Event, KeyCode};
-use crossterm::terminal::{enable_raw_mode, disable_raw_mode};
+use clap::{Args, Parser, Subcommand};
+use std::{path::PathBuf, sync::Arc};
 
 
 mod wad;
-use wad::Wad;
+mod pk3;
+mod pk7;
+mod folder;
 
-use crate::mus::mus_to_smf;
 mod mus;
+mod mus2mid;
 
 mod midi;
 mod synth;
+mod wav;
+mod digital;
+mod opl;
+mod imf;
+mod tracker;
+mod tracker_formats;
+mod dehacked;
+mod game;
+mod mapinfo;
+mod prompt;
+mod search;
+mod titles;
+mod song;
+mod gm;
+mod analyze;
+mod commands;
+
+use mus::Strictness;
+use mus2mid::MusProfile;
+use imf::ImfRate;
+use song::{find_song, load_timeline, MusOptions, SongFormat};
+use commands::Resources;
+use mapinfo::MapTable;
+use search::SongIndex;
+use titles::Provenance;
+use synth::{ChannelMix, OutputOptions, OutputTarget, Transport};
 
-use midi::{build_timeline, format_duration};
-use synth::Audio;
+/// How many ranked suggestions the REPL offers for a song it can't find.
+const SUGGESTIONS: usize = 9;
 
+/// With no subcommand this starts the interactive REPL on WAD + SOUNDFONT.
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Path to DOOM or DOOM2 WAD
-    wad: PathBuf,
+    #[arg(required_unless_present = "list_devices")]
+    wad: Option<PathBuf>,
     /// Path to GM SoundFont (.sf2)
-    soundfont: String,
+    #[arg(required_unless_present = "list_devices")]
+    soundfont: Option<String>,
+    /// List audio hosts and output devices, then exit
+    #[arg(long)]
+    list_devices: bool,
+    /// Refuse malformed MUS lumps instead of converting them with warnings
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Convert MUS like this engine does: native, dmx, chocolate or prboom
+    #[arg(long, global = true, default_value = "native")]
+    mus_profile: MusProfile,
+    /// DEHACKED/BEX patch whose music renames apply on top of the WAD's own (repeatable)
+    #[arg(long = "deh", global = true, value_name = "FILE")]
+    deh: Vec<PathBuf>,
+    /// The IWAD a PWAD was made for, to flag songs it replaces
+    #[arg(long, global = true, value_name = "IWAD")]
+    iwad: Option<PathBuf>,
+    /// Tick rate of IMF songs: 700 (Wolfenstein 3D), 560 (Commander Keen) or 280 (Duke Nukem II)
+    #[arg(long, global = true, default_value = "700")]
+    imf_rate: ImfRate,
+    #[command(flatten)]
+    audio: AudioArgs,
+}
+
+/// Output and playback options, accepted before or after any subcommand.
+#[derive(Args, Debug)]
+struct AudioArgs {
+    /// Audio host to use (e.g. ALSA, JACK); defaults to the platform default
+    #[arg(long, global = true)]
+    host: Option<String>,
+    /// Output device name (exact or case-insensitive substring)
+    #[arg(long, global = true)]
+    device: Option<String>,
+    /// Output sample rate in Hz
+    #[arg(long, global = true)]
+    sample_rate: Option<u32>,
+    /// Output buffer size in frames
+    #[arg(long, global = true)]
+    buffer_size: Option<u32>,
+    /// Where audio goes: device, null (discard) or wav:<path>; the REPL writes one file
+    /// per song played, numbered path-1.wav, path-2.wav, ...
+    #[arg(long, global = true, default_value = "device")]
+    output: OutputTarget,
+    /// Playback speed multiplier (0.25 to 4)
+    #[arg(long, global = true, default_value_t = 1.0, value_parser = parse_tempo)]
+    tempo: f32,
+    /// Transpose all non-drum channels by this many semitones
+    #[arg(long, global = true, default_value_t = 0, allow_negative_numbers = true)]
+    transpose: i8,
 }
 
-const MUSIC_PREFIXES: &[&str] = &["D_", "MUS_"];
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the songs in a WAD
+    List {
+        wad: PathBuf,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show format, length and timing details for one song
+    Info {
+        wad: PathBuf,
+        song: String,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Report channels, instruments, note range, polyphony and more (all songs if none are named)
+    Analyze {
+        wad: PathBuf,
+        songs: Vec<String>,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check a WAD's header and directory and report every problem
+    Validate {
+        wad: PathBuf,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write a WAD, PK3 or resource folder out as a single PWAD
+    Pack {
+        source: PathBuf,
+        /// The PWAD to write
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Play one song and exit
+    Play {
+        wad: PathBuf,
+        soundfont: String,
+        song: String,
+    },
+    /// Write songs out as Standard MIDI files (all songs if none are named)
+    Export {
+        wad: PathBuf,
+        songs: Vec<String>,
+        /// Directory to write into
+        #[arg(short = 'd', long, default_value = ".")]
+        out_dir: PathBuf,
+        /// Write the lumps as-is instead of converting to MIDI
+        #[arg(long)]
+        raw: bool,
+        /// Print JSON describing the written files
+        #[arg(long)]
+        json: bool,
+    },
+    /// Render one song to a WAV file, faster than real time
+    Render {
+        wad: PathBuf,
+        soundfont: String,
+        song: String,
+        /// Output file (defaults to <SONG>.wav)
+        #[arg(short = 'o', long = "out")]
+        out: Option<PathBuf>,
+    },
+}
 
-/// Accepts: RUNNIN, D_RUNNIN, E1M1, MUS_E1M1, etc.
-/// Tries exact, then tries with each known prefix.
-fn find_song<'a>(names: &'a [String], input: &str) -> Option<&'a str> {
-    let q = input.trim().to_ascii_uppercase();
-    if q.is_empty() { return None; }
+impl AudioArgs {
+    fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            target: self.output.clone(),
+            host: self.host.clone(),
+            device: self.device.clone(),
+            sample_rate: self.sample_rate,
+            buffer_size: self.buffer_size,
+        }
+    }
 
-    if let Some(hit) = names.iter().find(|n| **n == q) {
-        return Some(hit.as_str());
+    fn transport(&self) -> Transport {
+        Transport::new(self.tempo, self.transpose)
     }
-    for p in MUSIC_PREFIXES {
-        let cand = format!("{}{}", p, q);
-        if let Some(hit) = names.iter().find(|n| **n == cand) {
-            return Some(hit.as_str());
+}
+
+/// `--tempo`: a positive, finite multiplier; `Transport` clamps it to its range.
+fn parse_tempo(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(t) if t.is_finite() && t > 0.0 => Ok(t),
+        Ok(_) => Err(format!("expected a positive speed multiplier, got {s}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn print_devices() {
+    for host in synth::list_devices() {
+        println!("Host: {}", host.name);
+        if host.devices.is_empty() {
+            println!("  (no output devices)");
+        }
+        for d in &host.devices {
+            let mark = if d.is_default { " [default]" } else { "" };
+            println!("  {}{}", d.name, mark);
+            if let Some(c) = &d.default_config {
+                println!("    default: {}", c);
+            }
+            for r in &d.ranges {
+                println!("    supports: {}", r);
+            }
         }
     }
-    None
 }
 
-struct RawGuard;
-impl RawGuard {
-    fn enter() -> anyhow::Result<Self> { enable_raw_mode()?; Ok(Self) }
+/// Handle `mute`, `solo`, `vol` and `mix` REPL commands. Returns false if `line` isn't one.
+///
+/// Channels are MIDI channels 0–15 (9 is drums), matching the 0–9/F1–F6 playback keys.
+fn mix_command(mix: &ChannelMix, line: &str) -> bool {
+    let mut parts = line.split_whitespace();
+    let Some(cmd) = parts.next().map(|c| c.to_ascii_lowercase()) else { return false };
+    let words: Vec<&str> = parts.collect();
+    let channel = |w: &str| w.parse::<u8>().ok().filter(|&c| c <= 15);
+
+    match (cmd.as_str(), words.as_slice()) {
+        ("mute" | "solo", []) => { println!("Usage: {} <ch> [ch...]", cmd); return true; }
+        ("mute" | "solo", ws) => {
+            let Some(chs) = ws.iter().map(|w| channel(w)).collect::<Option<Vec<_>>>() else {
+                println!("Expected channel numbers 0-15");
+                return true;
+            };
+            for ch in chs {
+                if cmd == "mute" { mix.toggle_mute(ch); } else { mix.toggle_solo(ch); }
+            }
+        }
+        ("vol", [ch, pct]) => {
+            let Some(ch) = channel(ch) else { println!("Expected a channel number 0-15"); return true; };
+            let Some(pct) = pct.parse::<u8>().ok().filter(|&p| p <= 100) else {
+                println!("Expected a volume of 0-100%");
+                return true;
+            };
+            mix.set_volume(ch, pct);
+        }
+        ("vol", _) => { println!("Usage: vol <ch> <0-100>"); return true; }
+        ("mix", []) => {}
+        _ => return false,
+    }
+    print_mix(mix);
+    true
 }
-impl Drop for RawGuard {
-    fn drop(&mut self) { let _ = disable_raw_mode(); }
+
+/// The maps that play `song`, as the REPL's song lists show them.
+fn maps_column(maps: &MapTable, song: &str) -> String {
+    let played_on = maps.maps_for(song);
+    if played_on.is_empty() { String::new() } else { format!(" [{}]", played_on.join(", ")) }
 }
 
+fn print_mix(mix: &ChannelMix) {
+    println!("ch  state  vol");
+    for ch in 0..16u8 {
+        let state = match (mix.is_muted(ch), mix.is_soloed(ch)) {
+            (true, _) => "mute",
+            (false, true) => "solo",
+            _ if mix.audible(ch) => "on",
+            _ => "-",
+        };
+        println!("{:>2}  {:<5}  {:>3}%{}", ch, state, mix.volume(ch), if ch == 9 { "  (drums)" } else { "" });
+    }
+}
 
 fn main() -> Result<()> {
     let opt = Opt::parse();
-    let mut wad = Wad::open(&opt.wad)?;
-    println!("Using SoundFont: {}", opt.soundfont);
+    if opt.list_devices {
+        print_devices();
+        return Ok(());
+    }
+    let out_opts = opt.audio.output_options();
+    let mus_opts = MusOptions {
+        strictness: if opt.strict { Strictness::Strict } else { Strictness::Lenient },
+        profile: opt.mus_profile,
+        imf_rate: opt.imf_rate,
+    };
 
-    let music_lumps: Vec<_> = wad.iter_with_prefixes(MUSIC_PREFIXES).collect();
+    let res = |wad| Resources { wad, deh: &opt.deh, iwad: opt.iwad.as_deref() };
 
-    println!("\nAvailable songs:");
-    for l in &music_lumps {
-        println!("  {} ({} bytes)", l.name, l.size);
+    if let Some(cmd) = &opt.command {
+        let transport = opt.audio.transport();
+        return match cmd {
+            Command::List { wad, json } => commands::list(res(wad), *json, mus_opts),
+            Command::Info { wad, song, json } => commands::info(res(wad), song, *json, mus_opts),
+            Command::Validate { wad, json } => commands::validate(wad, *json),
+            Command::Analyze { wad, songs, json } => commands::analyze(res(wad), songs, *json, mus_opts),
+            Command::Pack { source, out } => commands::pack(source, out),
+            Command::Play { wad, soundfont, song } => {
+                commands::play(res(wad), soundfont, song, &out_opts, Arc::new(transport), mus_opts)
+            }
+            Command::Export { wad, songs, out_dir, raw, json } => {
+                commands::export(res(wad), songs, out_dir, *raw, *json, mus_opts)
+            }
+            Command::Render { wad, soundfont, song, out } => {
+                commands::render(res(wad), soundfont, song, out.as_deref(), opt.audio.sample_rate, &transport, mus_opts)
+            }
+        };
     }
 
-    let music_names: Vec<String> = music_lumps.iter().map(|l| l.name.clone()).collect();
+    // clap guarantees both are present unless --list-devices or a subcommand was given
+    let (Some(wad_path), Some(soundfont)) = (&opt.wad, &opt.soundfont) else { unreachable!() };
 
-    // REPL: type a song name (RUNNIN or D_RUNNIN). Empty line quits.
-    loop {
-        print!("\n> Enter song (RUNNIN / E1M1), 'list' to show all, or empty to quit: ");
+    let commands::Library { wad, names: music_names, maps, originals, identity } = res(wad_path).open()?;
+    println!("Using SoundFont: {}", soundfont);
+    if let Some(id) = identity {
+        println!("Game: {}", id);
+    }
 
-        stdout().flush().ok();
+    // Title, or the IWAD song it replaces, as `list` shows it
+    let provenance = |name: &str| {
+        let bytes = commands::read_song(&wad, name).unwrap_or_default();
+        Provenance::of(name, bytes, maps.game(), originals.as_ref())
+    };
+    println!("\nAvailable songs:");
+    for name in &music_names {
+        let size = wad.find_in(name, song::MUSIC_NAMESPACES).map(|i| wad.lumps()[i].size).unwrap_or(0);
+        println!("  {} ({} bytes){}{}", name, size, maps_column(&maps, name), provenance(name));
+    }
 
-        let mut line = String::new();
-        if stdin().read_line(&mut line).is_err() {
-            break; // EOF or input error
-        }
+    // Mute/solo/volume persist across songs until changed or reset with `mix reset`
+    let mix = Arc::new(ChannelMix::default());
+    // Same for speed and transposition, seeded from the command line
+    let transport = Arc::new(opt.audio.transport());
+
+    let index = SongIndex::new(&music_names, &maps, |name| provenance(name).title);
+    // The last numbered list shown, so a number picks from it
+    let mut choices: Vec<String> = Vec::new();
+    // Songs played so far, to number the WAV files
+    let mut songs_played = 0;
+
+    // REPL: type a song name (RUNNIN or D_RUNNIN), map or title, or a number from the
+    // last list. Tab completes. Empty line quits.
+    loop {
+        let prompt = "\n> Enter song (RUNNIN / E1M1 / MAP07 / title), 'list' to show all, a number to pick, 'mute/solo/vol/mix' for channels, or empty to quit: ";
+        let line = match prompt::read_line(prompt, |typed| index.complete(typed)) {
+            Ok(Some(line)) => line,
+            _ => break, // EOF or input error
+        };
         let line = line.trim();
         if line.is_empty() {
             break;
         }
 
+        if line.eq_ignore_ascii_case("mix reset") {
+            mix.reset();
+            print_mix(&mix);
+            continue;
+        }
+        if mix_command(&mix, line) {
+            continue;
+        }
+
         if line.eq_ignore_ascii_case("list") {
             println!("\nAvailable songs:");
-            for name in &music_names {
-                println!("  {}", name);
+            for (i, name) in music_names.iter().enumerate() {
+                println!("  {:>3}. {}{}{}", i + 1, name, maps_column(&maps, name), provenance(name));
             }
+            choices = music_names.clone();
             continue;
         }
 
-        let Some(candidate) = find_song(&music_names, line) else {
-            println!("Not found. Suggestions:");
-            let q = line.to_ascii_uppercase();
-            for n in music_names.iter().filter(|n| n.contains(&q)).take(6) {
-                println!("  {}", n);
+        let picked = match line.parse::<usize>() {
+            Ok(n) if (1..=choices.len()).contains(&n) => Some(choices[n - 1].as_str()),
+            _ => find_song(&music_names, &maps, line),
+        };
+        let Some(candidate) = picked else {
+            let hits = index.search(line, SUGGESTIONS);
+            if hits.is_empty() {
+                println!("Not found.");
+                continue;
+            }
+            println!("Not found. Did you mean:");
+            for (i, hit) in hits.iter().enumerate() {
+                let matched = if hit.key == hit.song { String::new() } else { format!("  ({})", hit.key) };
+                println!("  {:>3}. {}{}", i + 1, hit.song, matched);
             }
+            choices = hits.into_iter().map(|h| h.song).collect();
             continue;
         };
 
         // Read lump
-        let bytes = match wad.read(&candidate) {
+        let bytes = match commands::read_song(&wad, candidate) {
             Ok(b) => b,
             Err(e) => {
                 println!("Failed to read {}: {}", candidate, e);
@@ -112,77 +400,32 @@ fn main() -> Result<()> {
         println!("\nRead {}: {} bytes", candidate, bytes.len());
 
         // Format detector
-        if bytes.starts_with(b"MUS\x1A") {
-            println!("Format: MUS");
-            let smf = match mus_to_smf(&bytes) {
-                Ok(s) => s,
-                Err(e) => {
-                    println!("MUS parse error: {}", e);
-                    continue;
-                }
-            };
-
-            let tl = build_timeline(&smf);
-            println!("PPQ: {}", tl.ppq);
-            println!(
-                "Initial tempo: {} µs/qn (~{:.1} BPM)",
-                tl.initial_us_per_qn,
-                60_000_000.0 / tl.initial_us_per_qn
-            );
-            println!("Total events parsed: {}", tl.events.len());
-            println!("Estimated track length: {}", format_duration(tl.last_t_us));
-
-            let audio = match Audio::new(&opt.soundfont) {
-                Ok(a) => a,
-                Err(e) => { println!("Audio init failed: {}", e); continue; }
-            };
-            if let Err(e) = audio.start() {
-                println!("Audio start failed: {}", e);
+        let format = SongFormat::detect(bytes);
+        println!("Format: {}", format.name());
+        if format == SongFormat::Unknown {
+            continue;
+        }
+        songs_played += 1;
+        let out_opts = OutputOptions { target: out_opts.target.numbered(songs_played), ..out_opts.clone() };
+        if format.is_streamed() {
+            let played = digital::open(bytes.to_vec(), format, mus_opts.imf_rate)
+                .and_then(|track| commands::play_digital(soundfont, &out_opts, track));
+            if let Err(e) = played {
+                println!("{:#}", e);
+            }
+            continue;
+        }
+        let tl = match load_timeline(bytes, mus_opts) {
+            Ok(tl) => tl,
+            Err(e) => {
+                println!("{} parse error: {}", format.name(), e);
                 continue;
             }
+        };
+        commands::print_timeline_summary(&tl);
 
-            // start playback and get a handle
-            let player = audio.play_timeline(&tl);
-            // enter raw mode to capture keys immediately
-            // raw mode guard
-            let _raw = RawGuard::enter()?;
-            println!("Controls: Space = pause/resume, Esc = stop");
-
-            loop {
-                // quit this loop if the song finished by itself
-                if player.is_finished() {
-                    println!("Playback finished.");
-                    break;
-                }
-
-                // poll for key events with a short timeout
-                if event::poll(std::time::Duration::from_millis(50))? {
-                    if let Event::Key(k) = event::read()? {
-                        match k.code {
-                            KeyCode::Char(' ') => {
-                                player.toggle();
-                                // Optional: visual feedback
-                                // println!("[{}]", if paused { "paused" } else { "playing" });
-                            }
-                            KeyCode::Esc => {
-                                player.stop(); // stop current song
-                                break;
-                            }
-                            KeyCode::Char('c') if k.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
-                                player.stop(); // stop current song
-                                break;
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-                // small idle sleep to keep CPU down
-                std::thread::sleep(std::time::Duration::from_millis(5));
-            }
-        } else if bytes.starts_with(b"MThd") {
-            println!("Format: Standard MIDI (not handled here yet).");
-        } else {
-            println!("Format: unknown");
+        if let Err(e) = commands::play_timeline(soundfont, &out_opts, &tl, mix.clone(), transport.clone()) {
+            println!("{:#}", e);
         }
     }
 
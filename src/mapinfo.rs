@@ -0,0 +1,367 @@
+//! mapinfo.rs
+//!
+//! Which song each map plays. The table starts from the engine's built-in choices
+//! (Doom's episodes and Doom II's list, which Final Doom reuses; only the ones the
+//! game has when it is known), renamed by any DEHACKED patches, then takes Heretic's
+//! MUS_E1M1 style names at their word, then applies the text lumps a WAD can override
+//! them with: MAPINFO (or ZMAPINFO), UMAPINFO and SNDINFO `$MAP` lines. Hexen's songs
+//! (WINNOWR, JACHR, ...) are only tied to maps that way.
+//!
+//! The MAPINFO family is read a token at a time, so Hexen's original syntax,
+//! ZDoom's braces and `music = X` and UMAPINFO's `levelname` all parse alike.
+
+use crate::dehacked::Dehacked;
+use crate::game::GameId;
+use crate::song::MUSIC_PREFIXES;
+use crate::wad::{lump_name, Wad};
+
+/// Doom's songs for E1M1 to E4M9. Episode 4 (Ultimate Doom) reuses earlier songs.
+const DOOM_MUSIC: [&str; 36] = [
+    "D_E1M1", "D_E1M2", "D_E1M3", "D_E1M4", "D_E1M5", "D_E1M6", "D_E1M7", "D_E1M8", "D_E1M9",
+    "D_E2M1", "D_E2M2", "D_E2M3", "D_E2M4", "D_E2M5", "D_E2M6", "D_E2M7", "D_E2M8", "D_E2M9",
+    "D_E3M1", "D_E3M2", "D_E3M3", "D_E3M4", "D_E3M5", "D_E3M6", "D_E3M7", "D_E3M8", "D_E3M9",
+    "D_E3M4", "D_E3M2", "D_E3M3", "D_E1M5", "D_E2M7", "D_E2M4", "D_E2M6", "D_E2M5", "D_E1M9",
+];
+
+/// Doom II's songs for MAP01 to MAP32; TNT and Plutonia replace the lumps, not the list.
+const DOOM2_MUSIC: [&str; 32] = [
+    "D_RUNNIN", "D_STALKS", "D_COUNTD", "D_BETWEE", "D_DOOM", "D_THE_DA", "D_SHAWN", "D_DDTBLU",
+    "D_IN_CIT", "D_DEAD", "D_STLKS2", "D_THEDA2", "D_DOOM2", "D_DDTBL2", "D_RUNNI2", "D_DEAD2",
+    "D_STLKS3", "D_ROMERO", "D_SHAWN2", "D_MESSAG", "D_COUNT2", "D_DDTBL3", "D_AMPIE", "D_THEDA3",
+    "D_ADRIAN", "D_MESSG2", "D_ROMER2", "D_TENSE", "D_SHAWN3", "D_OPENIN", "D_EVIL", "D_ULTIMA",
+];
+
+/// Doom's songs that no map plays: title screens, intermissions and finales.
+const OTHER_MUSIC: [&str; 8] = ["D_INTRO", "D_INTROA", "D_INTER", "D_VICTOR", "D_BUNNY", "D_DM2TTL", "D_DM2INT", "D_READ_M"];
+
+/// MAPINFO keywords that start a block other than a map's.
+const BLOCK_KEYWORDS: &[&str] = &[
+    "cluster", "clusterdef", "episode", "defaultmap", "adddefaultmap", "gamedefaults",
+    "gameinfo", "intermission", "skill", "automap",
+];
+
+/// One map and the song lump it plays.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapMusic {
+    /// Map lump name, e.g. MAP01 or E1M1
+    pub map: String,
+    pub title: Option<String>,
+    pub lump: String,
+}
+
+/// Maps with their songs, in the order the maps were first defined.
+#[derive(Debug, Default)]
+pub struct MapTable {
+    entries: Vec<MapMusic>,
+    /// Built-in songs DEHACKED moved to another lump, as (old lump, new lump)
+    renamed: Vec<(String, String)>,
+    game: Option<GameId>,
+}
+
+impl MapTable {
+    /// The vanilla tables, patched by `wad`'s DEHACKED lumps and then `patches`, with
+    /// `wad`'s overrides applied. ZMAPINFO replaces MAPINFO when both are present, as
+    /// in ZDoom; SNDINFO comes last, as in Hexen.
+    pub fn from_wad(wad: &Wad, game: Option<GameId>, patches: &[Dehacked]) -> Self {
+        let mut table = Self::vanilla(game);
+        for &i in wad.find_all("DEHACKED").unwrap_or_default() {
+            if let Ok(bytes) = wad.read_at(i) {
+                table.apply_dehacked(&Dehacked::parse(&String::from_utf8_lossy(bytes)));
+            }
+        }
+        for patch in patches {
+            table.apply_dehacked(patch);
+        }
+        for l in wad.lumps() {
+            if let Some(map) = l.name.strip_prefix("MUS_").filter(|m| is_episode_map(m)) {
+                table.set_music(map, &l.name);
+            }
+        }
+        let mapinfo = if wad.contains("ZMAPINFO") { "ZMAPINFO" } else { "MAPINFO" };
+        let lumps = [
+            (mapinfo, Self::parse_mapinfo as fn(&mut Self, &str)),
+            ("UMAPINFO", Self::parse_mapinfo),
+            ("SNDINFO", Self::parse_sndinfo),
+        ];
+        for (name, parse) in lumps {
+            for &i in wad.find_all(name).unwrap_or_default() {
+                if let Ok(bytes) = wad.read_at(i) {
+                    parse(&mut table, &String::from_utf8_lossy(bytes));
+                }
+            }
+        }
+        table
+    }
+
+    /// `game`'s built-in songs, or both Doom's and Doom II's when the game isn't known.
+    pub fn vanilla(game: Option<GameId>) -> Self {
+        let (episodes, doom2) = game.map_or((4, true), |g| (g.doom_episodes(), g.has_doom2_music()));
+        let maps = (1..=episodes).flat_map(|e| (1..=9).map(move |m| format!("E{e}M{m}")));
+        let levels = (1..=32).filter(|_| doom2).map(|n| format!("MAP{n:02}"));
+        let entries = maps.zip(DOOM_MUSIC).chain(levels.zip(DOOM2_MUSIC))
+            .map(|(map, lump)| MapMusic { map, title: None, lump: lump.to_string() })
+            .collect();
+        MapTable { entries, renamed: Vec::new(), game }
+    }
+
+    pub fn game(&self) -> Option<GameId> {
+        self.game
+    }
+
+    /// Prefixes to try on a bare song name, the game's own first.
+    pub fn prefixes(&self) -> Vec<&'static str> {
+        let own = self.game.and_then(GameId::music_prefix);
+        own.into_iter().chain(MUSIC_PREFIXES.iter().copied().filter(|&p| Some(p) != own)).collect()
+    }
+
+    /// Move built-in songs to the lumps `deh` renames them to. As in the engine, a
+    /// rename matches a song by its current name, so patches can chain.
+    pub fn apply_dehacked(&mut self, deh: &Dehacked) {
+        for r in &deh.renames {
+            let (from, to) = (format!("D_{}", r.from), lump_name(&format!("D_{}", r.to)));
+            let mut builtin = DOOM_MUSIC.iter().chain(&DOOM2_MUSIC).chain(&OTHER_MUSIC);
+            let Some(&original) = builtin.find(|&&o| self.renamed(o).unwrap_or(o) == from) else {
+                continue;
+            };
+            for e in self.entries.iter_mut().filter(|e| e.lump == from) {
+                e.lump = to.clone();
+            }
+            self.renamed.retain(|(old, _)| old != original);
+            self.renamed.push((original.to_string(), to));
+        }
+    }
+
+    /// The lump a patch moved the built-in song `lump` to, if any.
+    pub fn renamed(&self, lump: &str) -> Option<&str> {
+        self.renamed.iter().find(|(old, _)| old == lump).map(|(_, new)| new.as_str())
+    }
+
+    pub fn get(&self, map: &str) -> Option<&MapMusic> {
+        self.entries.iter().find(|e| e.map.eq_ignore_ascii_case(map))
+    }
+
+    /// Maps that play `lump`.
+    pub fn maps_for(&self, lump: &str) -> Vec<String> {
+        self.entries.iter().filter(|e| e.lump == lump).map(|e| e.map.clone()).collect()
+    }
+
+    /// Whether any map plays `lump`.
+    pub fn plays(&self, lump: &str) -> bool {
+        self.entries.iter().any(|e| e.lump == lump)
+    }
+
+    fn entry(&mut self, map: &str) -> &mut MapMusic {
+        let map = map.to_ascii_uppercase();
+        let i = match self.entries.iter().position(|e| e.map == map) {
+            Some(i) => i,
+            None => {
+                self.entries.push(MapMusic { map, title: None, lump: String::new() });
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[i]
+    }
+
+    pub fn set_music(&mut self, map: &str, lump: &str) {
+        self.entry(map).lump = music_lump(lump);
+    }
+
+    pub fn set_title(&mut self, map: &str, title: &str) {
+        self.entry(map).title = Some(title.to_string());
+    }
+
+    /// Take map titles and `music` lines from a MAPINFO, ZMAPINFO or UMAPINFO lump.
+    pub fn parse_mapinfo(&mut self, text: &str) {
+        let mut tokens = tokens(text).into_iter().peekable();
+        let mut current: Option<String> = None;
+        let mut depth = 0usize;
+        while let Some(tok) = tokens.next() {
+            let key = if tok.quoted { String::new() } else { tok.text.to_ascii_lowercase() };
+            if key == "{" {
+                depth += 1;
+            } else if key == "}" {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    current = None;
+                }
+            } else if key == "map" && depth == 0 {
+                let Some(map) = tokens.next() else { break };
+                let map = map_name(&map.text);
+                // `lookup` titles name a language string, which we can't resolve
+                if tokens.peek().is_some_and(|t| t.text.eq_ignore_ascii_case("lookup")) {
+                    tokens.next();
+                    tokens.next();
+                } else if let Some(title) = tokens.next_if(|t| t.quoted) {
+                    self.set_title(&map, &title.text);
+                }
+                self.entry(&map);
+                current = Some(map);
+            } else if key == "music" {
+                if let (Some(map), Some(lump)) = (&current, tokens.next()) {
+                    self.set_music(map, &lump.text);
+                }
+            } else if key == "levelname" {
+                if let (Some(map), Some(title)) = (&current, tokens.next()) {
+                    self.set_title(map, &title.text);
+                }
+            } else if depth == 0 && BLOCK_KEYWORDS.contains(&key.as_str()) {
+                current = None;
+            }
+        }
+        self.entries.retain(|e| !e.lump.is_empty() || e.title.is_some());
+    }
+
+    /// Take `$MAP <number> <lump>` lines from a SNDINFO lump.
+    pub fn parse_sndinfo(&mut self, text: &str) {
+        for line in text.lines() {
+            let words = tokens(line);
+            if let [cmd, map, lump, ..] = words.as_slice()
+                && cmd.text.eq_ignore_ascii_case("$map")
+            {
+                self.set_music(&map_name(&map.text), &lump.text);
+            }
+        }
+    }
+}
+
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// Split text into words, quoted strings and braces, dropping `;` and `//` comments
+/// and `=` and `,`.
+fn tokens(text: &str) -> Vec<Token> {
+    let mut out = Vec::new();
+    for line in text.lines() {
+        let mut chars = line.chars().peekable();
+        let mut word = String::new();
+        while let Some(c) = chars.next() {
+            let comment = c == ';' || (c == '/' && chars.peek() == Some(&'/'));
+            if comment || c.is_whitespace() || matches!(c, '{' | '}' | '=' | ',' | '"') {
+                if !word.is_empty() {
+                    out.push(Token { text: std::mem::take(&mut word), quoted: false });
+                }
+                if comment {
+                    break;
+                }
+                if c == '{' || c == '}' {
+                    out.push(Token { text: c.to_string(), quoted: false });
+                }
+                if c == '"' {
+                    out.push(Token { text: chars.by_ref().take_while(|&c| c != '"').collect(), quoted: true });
+                }
+            } else {
+                word.push(c);
+            }
+        }
+        if !word.is_empty() {
+            out.push(Token { text: word, quoted: false });
+        }
+    }
+    out
+}
+
+/// Hexen numbers its maps; everything else names the map lump.
+fn map_name(tok: &str) -> String {
+    match tok.parse::<u32>() {
+        Ok(n) => format!("MAP{n:02}"),
+        Err(_) => tok.to_ascii_uppercase(),
+    }
+}
+
+/// The lump a `music` value refers to: ZDoom allows a file path and a `:order`
+/// suffix for modules.
+fn music_lump(value: &str) -> String {
+    let value = value.split(':').next().unwrap_or(value);
+    lump_name(value.rsplit(['/', '\\']).next().unwrap_or(value))
+}
+
+/// `ExMy`, as Heretic's songs are named.
+fn is_episode_map(name: &str) -> bool {
+    let b = name.as_bytes();
+    b.len() == 4 && b[0] == b'E' && b[1].is_ascii_digit() && b[2] == b'M' && b[3].is_ascii_digit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::WadBuilder;
+
+    const MAPINFO: &str = r#"
+; Hexen
+map 1 "WINNOWING HALL"
+warptrans 1
+next 2
+sky1 SKY2 0
+music WINNOWR
+
+map 2 "SEVEN PORTALS"   // no music here
+next 3
+
+cluster 1
+music NOTAMAP
+
+map MAP03 lookup HUSTR_3 { music = "music/jachr.ogg:2" }
+"#;
+
+    #[test]
+    fn reads_mapinfo_and_sndinfo() {
+        let mut t = MapTable::default();
+        t.parse_mapinfo(MAPINFO);
+        let first = t.get("MAP01").unwrap();
+        assert_eq!((first.title.as_deref(), first.lump.as_str()), (Some("WINNOWING HALL"), "WINNOWR"));
+        assert_eq!(t.get("map02").unwrap().lump, "");
+        assert_eq!(t.get("MAP03").unwrap().lump, "JACHR");
+        assert_eq!(t.get("MAP03").unwrap().title, None);
+        assert!(!t.plays("NOTAMAP"));
+
+        t.parse_sndinfo("$map 2 ORB\n$MAP 1 JACHR ; replaced\n$musicvolume 100\n");
+        assert_eq!(t.maps_for("JACHR"), ["MAP01", "MAP03"]);
+        assert_eq!(t.maps_for("ORB"), ["MAP02"]);
+    }
+
+    #[test]
+    fn overrides_the_vanilla_tables() {
+        let vanilla = MapTable::vanilla(None);
+        assert_eq!(vanilla.get("E4M1").unwrap().lump, "D_E3M4");
+        assert_eq!(vanilla.get("MAP07").unwrap().lump, "D_SHAWN");
+        assert_eq!(vanilla.maps_for("D_ULTIMA"), ["MAP32"]);
+
+        let mut b = WadBuilder::default();
+        b.lump("MUS_E1M1", b"MUS\x1a");
+        b.lump("MAPINFO", b"map MAP02 \"ignored\"\nmusic D_IGNORE\n");
+        b.lump("ZMAPINFO", b"map MAP02 \"Halls\" {\n  next = \"MAP03\"\n  music = \"D_HALLS\"\n}\n");
+        b.lump("UMAPINFO", b"MAP MAP03\n{\n  levelname = \"Pits\"\n  episode = \"M_EPI1\", \"One\", \"o\"\n  music = \"D_PITS\"\n}\n");
+        b.lump("SNDINFO", b"$MAP 1 WINNOWR\n");
+        let t = MapTable::from_wad(&b.build(), None, &[]);
+        assert_eq!(t.get("E1M1").unwrap().lump, "MUS_E1M1");
+        assert_eq!(t.get("MAP01").unwrap().lump, "WINNOWR");
+        assert_eq!(t.get("MAP02").unwrap(), &MapMusic { map: "MAP02".into(), title: Some("Halls".into()), lump: "D_HALLS".into() });
+        assert_eq!(t.get("MAP03").unwrap().title.as_deref(), Some("Pits"));
+        assert_eq!(t.get("MAP03").unwrap().lump, "D_PITS");
+        assert_eq!(t.get("MAP04").unwrap().lump, "D_BETWEE");
+
+        // A known game only gets its own built-in songs
+        let shareware = MapTable::vanilla(Some(GameId::DoomShareware));
+        assert!(shareware.get("E1M9").is_some() && shareware.get("E2M1").is_none());
+        assert!(shareware.get("MAP01").is_none());
+        let heretic = MapTable::vanilla(Some(GameId::Heretic));
+        assert!(heretic.get("E1M1").is_none());
+        assert_eq!(heretic.prefixes(), ["MUS_", "D_"]);
+    }
+
+    #[test]
+    fn dehacked_renames_chain_and_move_every_map() {
+        let mut b = WadBuilder::default();
+        b.lump("DEHACKED", b"[MUSIC]\nE3M4 = SONG1\nNOSUCH = SONG2\n");
+        b.lump("MAPINFO", b"map E1M1 \"Hangar\"\nmusic D_E3M4\n");
+        let later = Dehacked::parse("Text 5 5\nSONG1SONG3\n");
+        let t = MapTable::from_wad(&b.build(), None, &[later]);
+        assert_eq!(t.maps_for("D_SONG3"), ["E3M4", "E4M1"]);
+        assert_eq!(t.renamed("D_E3M4"), Some("D_SONG3"));
+        assert_eq!(t.renamed("D_NOSUCH"), None);
+        // MAPINFO names a lump, not a song, so renames don't touch it
+        assert_eq!(t.get("E1M1").unwrap().lump, "D_E3M4");
+    }
+}
@@ -1,21 +1,161 @@
 // mus.rs
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
 use midly::{
     Header, Format, Timing, Smf,
     TrackEvent, TrackEventKind, MetaMessage, MidiMessage,
-    num::{u4, u14, u15, u24},
+    num::{u4, u15, u24},
 };
 
-pub fn mus_to_smf(mus: &[u8]) -> Result<Smf<'static>> {
-    // Header: "MUS\x1A", score length, score start
-    if mus.len() < 16 || &mus[0..4] != b"MUS\x1A" { bail!("not a MUS"); }
+/// Size of the fixed part of a MUS header, before the instrument list.
+const HEADER_LEN: usize = 16;
 
-    let score_len   = u16::from_le_bytes([mus[4], mus[5]]) as usize;
-    let score_start = u16::from_le_bytes([mus[6], mus[7]]) as usize;
+/// Most channels a MUS song can use: 15 melodic plus percussion.
+const MAX_CHANNELS: usize = 16;
 
-    let end = score_start.checked_add(score_len)
-        .context("score_start + score_len overflow")?;
-    if end > mus.len() { bail!("MUS score range out of bounds"); }
+/// Something wrong with a MUS lump, found while converting it.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MusIssue {
+    #[error("event truncated by end of score")]
+    TruncatedEvent,
+    #[error("delay truncated by end of score")]
+    TruncatedDelay,
+    #[error("score has no end-of-score event")]
+    MissingEndOfScore,
+    #[error("header says score is {declared} bytes, found {actual}")]
+    ScoreLength { declared: usize, actual: usize },
+    #[error("{0} channels declared, MUS allows at most 16")]
+    TooManyChannels(usize),
+    #[error("header lists {declared} instruments, but the score starts at {score_start}")]
+    InstrumentCount { declared: usize, score_start: usize },
+    #[error("unsupported controller {0}, dropped")]
+    UnsupportedController(u8),
+    #[error("unknown system event {0}, dropped")]
+    UnknownSystemEvent(u8),
+    #[error("unused event type {0} skipped")]
+    UnusedEventType(u8),
+}
+
+/// One problem in a MUS lump: where it is, which event byte it belongs to and what's wrong.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct MusDiagnostic {
+    /// Byte offset into the lump
+    pub offset: usize,
+    /// The event byte, if the problem belongs to an event rather than the header
+    pub event: Option<u8>,
+    #[serde(serialize_with = "serialize_display")]
+    pub issue: MusIssue,
+}
+
+fn serialize_display<S: serde::Serializer>(v: &MusIssue, s: S) -> std::result::Result<S::Ok, S::Error> {
+    s.collect_str(v)
+}
+
+impl std::fmt::Display for MusDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.event {
+            Some(ev) => write!(f, "offset {:#06x}, event {:#04x}: {}", self.offset, ev, self.issue),
+            None => write!(f, "offset {:#06x}: {}", self.offset, self.issue),
+        }
+    }
+}
+
+/// What to do when a MUS lump turns out to be malformed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Convert as much as possible and report the problems as diagnostics
+    #[default]
+    Lenient,
+    /// Fail the conversion on the first problem
+    Strict,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MusError {
+    #[error("not a MUS")]
+    NotMus,
+    #[error("MUS header truncated: instrument list needs {needed} bytes, lump has {len}")]
+    TruncatedHeader { needed: usize, len: usize },
+    #[error("malformed MUS: {0}")]
+    Malformed(MusDiagnostic),
+}
+
+/// The MUS header: where the score is and what it needs to play.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct MusHeader {
+    /// Score length in bytes
+    pub score_len: u16,
+    /// Offset of the score from the start of the lump
+    pub score_start: u16,
+    /// Melodic channels used, not counting percussion
+    pub primary_channels: u16,
+    /// Channels the song can do without on cards with fewer voices
+    pub secondary_channels: u16,
+    /// Patches the song uses: 0–127 are GM programs, 135–181 are percussion keys 35–81
+    pub instruments: Vec<u16>,
+}
+
+impl MusHeader {
+    /// Read the fixed header and the instrument list that follows it.
+    pub fn parse(mus: &[u8]) -> Result<Self, MusError> {
+        // "MUS\x1A", score length, score start, primary and secondary channels, instrument count
+        if mus.len() < HEADER_LEN || &mus[0..4] != b"MUS\x1A" { return Err(MusError::NotMus); }
+
+        let word = |at: usize| u16::from_le_bytes([mus[at], mus[at + 1]]);
+        let count = word(12) as usize;
+        let needed = HEADER_LEN + count * 2;
+        if needed > mus.len() {
+            return Err(MusError::TruncatedHeader { needed, len: mus.len() });
+        }
+        Ok(MusHeader {
+            score_len: word(4),
+            score_start: word(6),
+            primary_channels: word(8),
+            secondary_channels: word(10),
+            instruments: (0..count).map(|n| word(HEADER_LEN + n * 2)).collect(),
+        })
+    }
+
+    /// Total channels the song declares.
+    pub fn channels(&self) -> usize {
+        self.primary_channels as usize + self.secondary_channels as usize
+    }
+
+    /// Offset just past the instrument list, where the score should start.
+    pub fn instruments_end(&self) -> usize {
+        HEADER_LEN + self.instruments.len() * 2
+    }
+}
+
+/// A converted song plus everything that looked wrong on the way.
+#[derive(Debug)]
+pub struct MusConversion {
+    pub smf: Smf<'static>,
+    pub diagnostics: Vec<MusDiagnostic>,
+}
+
+/// Convert a MUS lump, collecting a diagnostic for every problem found.
+///
+/// In `Strict` mode the first problem is returned as `MusError::Malformed` instead.
+pub fn convert(mus: &[u8], strictness: Strictness) -> Result<MusConversion> {
+    let mus_header = MusHeader::parse(mus)?;
+    let score_len = mus_header.score_len as usize;
+    let score_start = mus_header.score_start as usize;
+
+    let mut diags = Diagnostics { list: Vec::new(), strictness };
+
+    if mus_header.channels() > MAX_CHANNELS {
+        diags.report(8, None, MusIssue::TooManyChannels(mus_header.channels()))?;
+    }
+    if mus_header.instruments_end() != score_start {
+        diags.report(12, None, MusIssue::InstrumentCount {
+            declared: mus_header.instruments.len(),
+            score_start,
+        })?;
+    }
+
+    if score_start > mus.len() { bail!("MUS score start {} is past the end of the lump", score_start); }
+    // A short lump still gets converted as far as it goes
+    let end = (score_start + score_len).min(mus.len());
 
     let stream = &mus[score_start..end];
 
@@ -37,8 +177,10 @@ pub fn mus_to_smf(mus: &[u8]) -> Result<Smf<'static>> {
 
     let mut i = 0usize;
     let mut pending_delta: u32 = 0;
+    let mut finished = false;
 
     while i < stream.len() {
+        let at = score_start + i;
         let ev = stream[i]; i += 1;
 
         // Bit 7 says there is a delta time field after this event
@@ -50,10 +192,22 @@ pub fn mus_to_smf(mus: &[u8]) -> Result<Smf<'static>> {
         let ch_midi = map_channel(ch_mus);
         let ch = u4::from(ch_midi);
 
+        // Next data byte of this event, or a truncation diagnostic
+        macro_rules! data {
+            () => {
+                match stream.get(i) {
+                    Some(&b) => { i += 1; b }
+                    None => {
+                        diags.report(at, Some(ev), MusIssue::TruncatedEvent)?;
+                        break;
+                    }
+                }
+            };
+        }
+
         match ty {
             0 => { // Release note
-                if i >= stream.len() { break; }
-                let key = stream[i] & 0x7F; i += 1;
+                let key = data!() & 0x7F;
                 push(&mut track, pending_delta, TrackEventKind::Midi {
                     channel: ch,
                     message: MidiMessage::NoteOff { key: key.into(), vel: 0.into() }
@@ -61,15 +215,13 @@ pub fn mus_to_smf(mus: &[u8]) -> Result<Smf<'static>> {
                 pending_delta = 0;
             }
             1 => { // Play note
-                if i >= stream.len() { break; }
-                let mut key = stream[i]; i += 1;
+                let mut key = data!();
 
                 // Velocity flag lives in bit 7 of the key byte
                 let mut vel = last_vel[ch_mus as usize];
                 if key & 0x80 != 0 {
                     key &= 0x7F;
-                    if i >= stream.len() { break; }
-                    vel = stream[i]; i += 1;
+                    vel = data!();
                     last_vel[ch_mus as usize] = vel;
                 }
                 // Clamp velocity to 127 (0x7F)
@@ -82,8 +234,7 @@ pub fn mus_to_smf(mus: &[u8]) -> Result<Smf<'static>> {
                 pending_delta = 0;
             }
             2 => { // Pitch wheel (7-bit, centered at 64)
-                if i >= stream.len() { break; }
-                let v = stream[i] as i32; i += 1;        // 0..255, center 128
+                let v = data!() as i32;                  // 0..255, center 128
                 let centered = v - 128;                  // -128..+127
                 let bend14 = (8192 + centered * 64)      // scale so ±128 → ≈ ±8192
                     .clamp(0, 16383) as u16;
@@ -94,69 +245,94 @@ pub fn mus_to_smf(mus: &[u8]) -> Result<Smf<'static>> {
                 });
                 pending_delta = 0;
             }
-            3 => {
-                // System event: one data byte follows. We don't use it for GM, but we MUST consume it.
-                //eprintln!("MUS System event at offset {}", i - 1);
-                if i >= stream.len() { break; }
-                let _sys = stream[i]; // values are DMX-internal (e.g. score markers), ignore for playback
-                i += 1;
-                // no pending_delta reset here; treat like other events (we already reset after pushing events).
+            3 => { // System event: a valueless controller
+                let sys = data!();
+                if let Some(cc) = map_system_event(sys) {
+                    push(&mut track, pending_delta, TrackEventKind::Midi {
+                        channel: ch,
+                        message: MidiMessage::Controller { controller: cc.into(), value: 0.into() }
+                    });
+                    pending_delta = 0;
+                } else {
+                    diags.report(at, Some(ev), MusIssue::UnknownSystemEvent(sys))?;
+                }
             }
             4 => { // Controller
-                if i >= stream.len() { break; }
-                let ctrl = stream[i]; i += 1;
-
-                match ctrl {
-                    0 => { // Program change
-                        if i >= stream.len() { break; }
-                        let prog = stream[i]; i += 1;
-                        push(&mut track, pending_delta, TrackEventKind::Midi {
-                            channel: ch,
-                            message: MidiMessage::ProgramChange { program: prog.into() }
-                        });
-                    }
-                    _ => {
-                        if i >= stream.len() { break; }
-                        let val = stream[i]; i += 1;
-                        if let Some(cc) = map_controller(ctrl) {
-                            push(&mut track, pending_delta, TrackEventKind::Midi {
-                                channel: ch,
-                                message: MidiMessage::Controller {
-                                    controller: cc.into(),
-                                    value: val.into(),
-                                }
-                            });
+                let ctrl = data!();
+                let val = data!();
+
+                if ctrl == 0 { // Program change
+                    push(&mut track, pending_delta, TrackEventKind::Midi {
+                        channel: ch,
+                        message: MidiMessage::ProgramChange { program: val.into() }
+                    });
+                    pending_delta = 0;
+                } else if let Some(cc) = map_controller(ctrl) {
+                    push(&mut track, pending_delta, TrackEventKind::Midi {
+                        channel: ch,
+                        message: MidiMessage::Controller {
+                            controller: cc.into(),
+                            value: val.into(),
                         }
-                    }
+                    });
+                    pending_delta = 0;
+                } else {
+                    // Nothing was pushed, so the pending delay carries over to the next event
+                    diags.report(at, Some(ev), MusIssue::UnsupportedController(ctrl))?;
                 }
-                pending_delta = 0;
             }
             5 => {
-                // end of measure, no pauload */
+                // end of measure, no payload
             }
             6 => { // End of score
-                // eprintln!("MUS end at offset {}", i - 1);
+                finished = true;
                 break;
             }
-            7 => { // Unused but historically includes 1 byte so players keep sync
-                if i >= stream.len() { break; }
-                let _ignored = stream[i]; i += 1;
-            }
-            _ => {
-                // Unknown type. You can choose to bail or skip.
+            _ => { // 7: unused, but historically includes 1 byte so players keep sync
+                let _ignored = data!();
+                diags.report(at, Some(ev), MusIssue::UnusedEventType(ty))?;
             }
         }
 
         // Only read a delta if the event's MSB was set
         if has_delta {
             let (d, used) = read_var_time(&stream[i..]);
+            if used == 0 || stream[i + used - 1] & 0x80 != 0 {
+                diags.report(at, Some(ev), MusIssue::TruncatedDelay)?;
+            }
             i += used;
             pending_delta = pending_delta.saturating_add(d);
         }
     }
 
+    if !finished {
+        diags.report(score_start + i, None, MusIssue::MissingEndOfScore)?;
+    }
+    // Where the score really ends: just past end-of-score, or the end of the lump
+    let actual = if finished { i } else { end - score_start };
+    if actual != score_len {
+        diags.report(4, None, MusIssue::ScoreLength { declared: score_len, actual })?;
+    }
+
     track.push(TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
-    Ok(Smf { header, tracks: vec![track] })
+    Ok(MusConversion { smf: Smf { header, tracks: vec![track] }, diagnostics: diags.list })
+}
+
+/// Diagnostics collected so far, and whether the next one is fatal.
+struct Diagnostics {
+    list: Vec<MusDiagnostic>,
+    strictness: Strictness,
+}
+
+impl Diagnostics {
+    fn report(&mut self, offset: usize, event: Option<u8>, issue: MusIssue) -> Result<(), MusError> {
+        let d = MusDiagnostic { offset, event, issue };
+        if self.strictness == Strictness::Strict {
+            return Err(MusError::Malformed(d));
+        }
+        self.list.push(d);
+        Ok(())
+    }
 }
 
 fn push(track: &mut Vec<midly::TrackEvent<'static>>, delta: u32, kind: midly::TrackEventKind<'static>) {
@@ -176,10 +352,13 @@ fn read_var_time(bytes: &[u8]) -> (u32, usize) {
     (val, used)
 }
 
-fn map_channel(ch_mus: u8) -> u8 {
+/// General MIDI percussion channel (MIDI channel 10, zero-based 9).
+pub const DRUM_CHANNEL: u8 = 9;
+
+pub fn map_channel(ch_mus: u8) -> u8 {
     // MUS channel 15 is drums. Map to GM channel 9.
     match ch_mus {
-        15 => 9,                // drums
+        15 => DRUM_CHANNEL,     // drums
         c if c >= 9 => c+1, // 9..14 -> 10..15
         c => c,
     }
@@ -202,6 +381,16 @@ fn map_controller(c: u8) -> Option<u8> {
     })
 }
 
+fn map_system_event(c: u8) -> Option<u8> {
+    Some(match c {
+        10 => 120, // all sounds off
+        11 => 123, // all notes off
+        12 => 126, // mono
+        13 => 127, // poly
+        14 => 121, // reset all controllers
+        _  => return None,
+    })
+}
 
 #[cfg(test)]
 mod tests {
@@ -226,7 +415,7 @@ mod tests {
         mus.extend_from_slice(&[0; 8]); // pad header to 16 bytes
         mus.extend_from_slice(&score); // score at offset 16
 
-        let smf = mus_to_smf(&mus).expect("Should convert valid MUS lump");
+        let smf = convert(&mus, Strictness::Lenient).expect("Should convert valid MUS lump").smf;
         assert_eq!(smf.header.format, midly::Format::SingleTrack);
         assert_eq!(smf.tracks.len(), 1);
         let events = &smf.tracks[0];
@@ -235,4 +424,110 @@ mod tests {
         assert!(events.iter().any(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. })));
         assert!(events.iter().any(|e| matches!(e.kind, TrackEventKind::Meta(midly::MetaMessage::EndOfTrack))));
     }
+
+    /// Header with no instruments followed by `score`, with score_len set to `declared`.
+    fn lump(score: &[u8], declared: u16) -> Vec<u8> {
+        let mut mus = b"MUS\x1A".to_vec();
+        mus.extend_from_slice(&declared.to_le_bytes());
+        mus.extend_from_slice(&16u16.to_le_bytes());
+        mus.extend_from_slice(&[0; 8]);
+        mus.extend_from_slice(score);
+        mus
+    }
+
+    #[test]
+    fn lenient_mode_reports_malformed_scores() {
+        // Play note 60 with a velocity flag but no velocity byte, and no end of score
+        let mus = lump(&[0x10, 0x80 | 60], 2);
+        let conv = convert(&mus, Strictness::Lenient).unwrap();
+        let issues: Vec<_> = conv.diagnostics.iter().map(|d| (d.offset, d.event, d.issue.clone())).collect();
+        assert_eq!(issues, vec![
+            (16, Some(0x10), MusIssue::TruncatedEvent),
+            (18, None, MusIssue::MissingEndOfScore),
+        ]);
+
+        // Score length says 10 bytes but the end of score comes after 3
+        let conv = convert(&lump(&[0x10, 60, 0x60], 10), Strictness::Lenient).unwrap();
+        assert_eq!(conv.diagnostics.len(), 1);
+        assert_eq!(conv.diagnostics[0].issue, MusIssue::ScoreLength { declared: 10, actual: 3 });
+        assert_eq!(conv.diagnostics[0].to_string(), "offset 0x0004: header says score is 10 bytes, found 3");
+    }
+
+    #[test]
+    fn header_counts_are_checked() {
+        // One trailing byte after the score so the instrument list can still be read
+        let mut mus = lump(&[0x60, 0x00], 1);
+        mus[8] = 15; // primary channels
+        mus[10] = 2; // secondary channels
+        mus[12] = 1; // instruments, but the score starts right after the header
+        let conv = convert(&mus, Strictness::Lenient).unwrap();
+        let issues: Vec<_> = conv.diagnostics.into_iter().map(|d| d.issue).collect();
+        assert_eq!(issues, vec![
+            MusIssue::TooManyChannels(17),
+            MusIssue::InstrumentCount { declared: 1, score_start: 16 },
+        ]);
+    }
+
+    #[test]
+    fn unsupported_controller_is_reported_and_keeps_its_delay() {
+        // Controller 12 with value 0 and a 10-tick delay, then play note 60, end
+        let mus = lump(&[0xC0, 12, 0, 10, 0x10, 60, 0x60], 7);
+        let conv = convert(&mus, Strictness::Lenient).unwrap();
+        assert_eq!(conv.diagnostics[0].issue, MusIssue::UnsupportedController(12));
+        let note = conv.smf.tracks[0].iter()
+            .find(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. }))
+            .unwrap();
+        assert_eq!(note.delta.as_int(), 10);
+    }
+
+    #[test]
+    fn strict_mode_fails_on_first_problem() {
+        let err = convert(&lump(&[0x10, 60], 2), Strictness::Strict).unwrap_err();
+        match err.downcast_ref::<MusError>() {
+            Some(MusError::Malformed(d)) => assert_eq!(d.issue, MusIssue::MissingEndOfScore),
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(convert(&lump(&[0x10, 60, 0x60], 3), Strictness::Strict).is_ok());
+    }
+
+    #[test]
+    fn parses_header_and_instrument_list() {
+        // score_len, score_start, primary, secondary, instrument count, reserved,
+        // then two instruments (Distortion Guitar, Bass Drum 1) and the score
+        let mut mus = b"MUS\x1A".to_vec();
+        for w in [1u16, 20, 5, 1, 2, 0, 30, 136] {
+            mus.extend_from_slice(&w.to_le_bytes());
+        }
+        mus.push(0x60);
+
+        let h = MusHeader::parse(&mus).unwrap();
+        assert_eq!(h, MusHeader {
+            score_len: 1,
+            score_start: 20,
+            primary_channels: 5,
+            secondary_channels: 1,
+            instruments: vec![30, 136],
+        });
+        assert_eq!(h.channels(), 6);
+        assert_eq!(h.instruments_end(), 20);
+        assert!(convert(&mus, Strictness::Strict).is_ok());
+
+        // Instrument count running past the end of the lump
+        assert!(matches!(MusHeader::parse(&mus[..18]), Err(MusError::TruncatedHeader { needed: 20, len: 18 })));
+    }
+
+    #[test]
+    fn system_events_become_controllers() {
+        // All notes off and reset all controllers on channel 2, then an unknown one, end
+        let mus = lump(&[0x32, 11, 0x32, 14, 0x32, 9, 0x60], 7);
+        let conv = convert(&mus, Strictness::Lenient).unwrap();
+        let ccs: Vec<_> = conv.smf.tracks[0].iter().filter_map(|e| match e.kind {
+            TrackEventKind::Midi { channel, message: MidiMessage::Controller { controller, value } } =>
+                Some((channel.as_int(), controller.as_int(), value.as_int())),
+            _ => None,
+        }).collect();
+        assert_eq!(ccs, vec![(2, 123, 0), (2, 121, 0)]);
+        assert_eq!(conv.diagnostics.len(), 1);
+        assert_eq!(conv.diagnostics[0].issue, MusIssue::UnknownSystemEvent(9));
+    }
 }
@@ -0,0 +1,361 @@
+//! mus2mid.rs
+//!
+//! MUS → MIDI conversion the way particular engines do it.
+//!
+//! `mus::convert` is our own converter and reports problems instead of guessing. The
+//! profiles here don't try to be clever: the Chocolate Doom and PrBoom-plus ones follow
+//! those ports' mus2mid step by step, so an exported file is byte for byte what they
+//! make of it, quirks included. DMX is closed source and never wrote MIDI files, so its
+//! profile only models the quirks it is known for (default channel volume, clamped
+//! velocities, ignored unknown events); nothing here has been checked against what
+//! DMX actually sends. All of them write format 0 at 70 PPQ with no tempo event, so
+//! the 120 BPM MIDI default gives DMX's 140 Hz tick.
+
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+use crate::mus::{self, map_channel, MusHeader, Strictness, DRUM_CHANNEL};
+
+/// Which engine's MUS conversion to reproduce.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MusProfile {
+    /// Our own converter (`mus::convert`), with diagnostics
+    #[default]
+    Native,
+    /// A model of what vanilla DOOM's DMX library sends to a General MIDI device,
+    /// not verified against DMX itself
+    Dmx,
+    /// Chocolate Doom's mus2mid
+    Chocolate,
+    /// PrBoom-plus's mus2mid, the older converter Chocolate's grew out of
+    PrBoom,
+}
+
+impl FromStr for MusProfile {
+    type Err = String;
+
+    /// Parses `native`, `dmx`, `chocolate` or `prboom`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "native" => Ok(Self::Native),
+            "dmx" => Ok(Self::Dmx),
+            "chocolate" => Ok(Self::Chocolate),
+            "prboom" => Ok(Self::PrBoom),
+            _ => Err(format!("expected native, dmx, chocolate or prboom, got {s:?}")),
+        }
+    }
+}
+
+impl MusProfile {
+    /// How this engine's converter behaves; `None` for our own.
+    fn quirks(self) -> Option<Quirks> {
+        Some(match self {
+            Self::Native => return None,
+            Self::Chocolate => Quirks {
+                allocate_channels: true,
+                notes_off_on_first_use: true,
+                initial_volume: None,
+                clamp_velocity: false,
+                reject_unknown: true,
+            },
+            Self::PrBoom => Quirks {
+                allocate_channels: true,
+                notes_off_on_first_use: false,
+                initial_volume: None,
+                clamp_velocity: false,
+                reject_unknown: true,
+            },
+            Self::Dmx => Quirks {
+                allocate_channels: false,
+                notes_off_on_first_use: false,
+                initial_volume: Some(DMX_DEFAULT_VOLUME),
+                clamp_velocity: true,
+                reject_unknown: false,
+            },
+        })
+    }
+}
+
+/// Channel volume DMX starts every channel at.
+const DMX_DEFAULT_VOLUME: u8 = 100;
+
+/// MIDI division written by every mus2mid.
+const DIVISION: u16 = 70;
+
+/// MUS controller number → MIDI controller. 0 is the program change and never looked up;
+/// 10–14 are the valueless system events.
+const CONTROLLERS: [u8; 15] = [
+    0x00, 0x20, 0x01, 0x07, 0x0A, 0x0B, 0x5B, 0x5D,
+    0x40, 0x43, 0x78, 0x7B, 0x7E, 0x7F, 0x79,
+];
+
+/// Where the profiles differ. Controller values above 127 are clamped and program
+/// numbers masked to 7 bits in all of them.
+struct Quirks {
+    /// Hand out MIDI channels in order of first use (skipping 9) instead of a fixed map
+    allocate_channels: bool,
+    /// Send All Notes Off when a melodic channel is first used (Chocolate's D_DDTBLU fix)
+    notes_off_on_first_use: bool,
+    /// Volume sent on a channel when it's first used
+    initial_volume: Option<u8>,
+    /// Clamp velocities above 127 instead of dropping bit 7
+    clamp_velocity: bool,
+    /// Give up on controllers, system events and event types the engine doesn't know,
+    /// rather than skipping them
+    reject_unknown: bool,
+}
+
+/// Reads the score a byte at a time, failing at the end of the lump like the engines do.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8> {
+        let Some(&b) = self.data.get(self.pos) else {
+            bail!("MUS data ends at offset {} before the end of score", self.pos);
+        };
+        self.pos += 1;
+        Ok(b)
+    }
+}
+
+/// The MIDI track being written, and the delay waiting to go in front of the next event.
+#[derive(Default)]
+struct Writer {
+    track: Vec<u8>,
+    queued: u32,
+}
+
+impl Writer {
+    fn event(&mut self, bytes: &[u8]) {
+        // Big-endian VLQ, most significant group first
+        let mut groups = vec![(self.queued & 0x7F) as u8];
+        let mut t = self.queued >> 7;
+        while t != 0 {
+            groups.push((t & 0x7F) as u8 | 0x80);
+            t >>= 7;
+        }
+        self.track.extend(groups.iter().rev());
+        self.track.extend_from_slice(bytes);
+        self.queued = 0;
+    }
+}
+
+/// Convert a MUS lump to a Standard MIDI File as `profile`'s engine would: exactly for
+/// the ports' mus2mids, as far as it is modelled for DMX.
+///
+/// `Native` writes out what `mus::convert` produces, leniently.
+pub fn convert(mus: &[u8], profile: MusProfile) -> Result<Vec<u8>> {
+    let Some(q) = profile.quirks() else {
+        let mut out = Vec::new();
+        mus::convert(mus, Strictness::Lenient)?.smf.write_std(&mut out)?;
+        return Ok(out);
+    };
+    let header = MusHeader::parse(mus)?;
+    let mut r = Reader { data: mus, pos: header.score_start as usize };
+    let mut w = Writer::default();
+
+    // MIDI channel per MUS channel, assigned on first use
+    let mut channels: [Option<u8>; 16] = [None; 16];
+    let mut velocity = [127u8; 16];
+
+    loop {
+        let desc = r.byte()?;
+        // Every engine looks the channel up before it looks at the event, even end of score
+        let ch = midi_channel(desc & 0x0F, &mut channels, &q, &mut w);
+
+        match desc & 0x70 {
+            0x00 => { // Release note
+                let key = r.byte()?;
+                w.event(&[0x80 | ch, key & 0x7F, 0]);
+            }
+            0x10 => { // Play note
+                let key = r.byte()?;
+                if key & 0x80 != 0 {
+                    let v = r.byte()?;
+                    velocity[ch as usize] = if q.clamp_velocity { v.min(0x7F) } else { v & 0x7F };
+                }
+                w.event(&[0x90 | ch, key & 0x7F, velocity[ch as usize]]);
+            }
+            0x20 => { // Pitch wheel, 8 bits scaled up to 14
+                let bend = r.byte()? as u16 * 64;
+                w.event(&[0xE0 | ch, (bend & 0x7F) as u8, ((bend >> 7) & 0x7F) as u8]);
+            }
+            0x30 => { // System event
+                let c = r.byte()?;
+                if (10..=14).contains(&c) {
+                    w.event(&[0xB0 | ch, CONTROLLERS[c as usize], 0]);
+                } else if q.reject_unknown {
+                    bail!("unknown MUS system event {} at offset {}", c, r.pos - 2);
+                }
+            }
+            0x40 => { // Controller
+                let c = r.byte()?;
+                let v = r.byte()?;
+                if c == 0 {
+                    w.event(&[0xC0 | ch, v & 0x7F]);
+                } else if (1..=9).contains(&c) {
+                    w.event(&[0xB0 | ch, CONTROLLERS[c as usize], if v & 0x80 != 0 { 0x7F } else { v }]);
+                } else if q.reject_unknown {
+                    bail!("unknown MUS controller {} at offset {}", c, r.pos - 3);
+                }
+            }
+            0x60 => break, // End of score
+            ty if q.reject_unknown => bail!("unknown MUS event type {} at offset {}", ty >> 4, r.pos - 1),
+            0x70 => { r.byte()?; } // unused, one data byte
+            _ => {}                // 0x50: end of measure, no data
+        }
+
+        if desc & 0x80 != 0 {
+            let mut delay = 0u32;
+            loop {
+                let b = r.byte()?;
+                delay = delay.wrapping_mul(128).wrapping_add((b & 0x7F) as u32);
+                if b & 0x80 == 0 { break; }
+            }
+            w.queued = w.queued.wrapping_add(delay);
+        }
+    }
+    w.event(&[0xFF, 0x2F, 0x00]);
+
+    let mut out = Vec::with_capacity(22 + w.track.len());
+    out.extend_from_slice(b"MThd");
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(&0u16.to_be_bytes()); // format 0
+    out.extend_from_slice(&1u16.to_be_bytes()); // one track
+    out.extend_from_slice(&DIVISION.to_be_bytes());
+    out.extend_from_slice(b"MTrk");
+    out.extend_from_slice(&(w.track.len() as u32).to_be_bytes());
+    out.extend_from_slice(&w.track);
+    Ok(out)
+}
+
+/// MIDI channel for a MUS channel, setting it up (and writing any setup events) on first use.
+fn midi_channel(mus_ch: u8, channels: &mut [Option<u8>; 16], q: &Quirks, w: &mut Writer) -> u8 {
+    if let Some(ch) = channels[mus_ch as usize] {
+        return ch;
+    }
+    let ch = if mus_ch == 15 {
+        DRUM_CHANNEL
+    } else if q.allocate_channels {
+        // One past the highest melodic channel handed out so far, never the drum channel
+        let next = channels[..15].iter().flatten().max().map_or(0, |&c| c + 1);
+        if next == DRUM_CHANNEL { next + 1 } else { next }
+    } else {
+        map_channel(mus_ch)
+    };
+    channels[mus_ch as usize] = Some(ch);
+
+    if q.notes_off_on_first_use && mus_ch != 15 {
+        w.event(&[0xB0 | ch, 0x7B, 0]);
+    }
+    if let Some(v) = q.initial_volume {
+        w.event(&[0xB0 | ch, 0x07, v]);
+    }
+    ch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MUS lump with no instruments around `score`.
+    fn lump(score: &[u8]) -> Vec<u8> {
+        let mut mus = b"MUS\x1A".to_vec();
+        mus.extend_from_slice(&(score.len() as u16).to_le_bytes());
+        mus.extend_from_slice(&16u16.to_le_bytes());
+        mus.extend_from_slice(&[0; 8]);
+        mus.extend_from_slice(score);
+        mus
+    }
+
+    /// Exercises every quirk: velocity 200, a second melodic channel, percussion,
+    /// controller value 200, a system event, pitch wheel and a two-byte delay.
+    const SCORE: [u8; 22] = [
+        0x10, 0xBC, 0xC8,       // ch 0: play 60, velocity 200
+        0xC1, 0x00, 0x1E, 0x0A, // ch 1: program 30; delay 10
+        0x9F, 0x24, 0x81, 0x00, // ch 15: play 36; delay 128
+        0x41, 0x03, 0xC8,       // ch 1: volume 200
+        0x31, 0x0B,             // ch 1: all notes off
+        0xA0, 0xC0, 0x05,       // ch 0: pitch wheel 192; delay 5
+        0x00, 0x3C,             // ch 0: release 60
+        0x60,                   // end of score
+    ];
+
+    #[test]
+    fn chocolate_golden() {
+        let expected: &[u8] = &[
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 0x46,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 0x28,
+            0x00, 0xB0, 0x7B, 0x00,       // ch 0 first use: all notes off
+            0x00, 0x90, 0x3C, 0x48,       // velocity 200 & 0x7F
+            0x00, 0xB1, 0x7B, 0x00,       // ch 1 first use
+            0x00, 0xC1, 0x1E,
+            0x0A, 0x99, 0x24, 0x7F,
+            0x81, 0x00, 0xB1, 0x07, 0x7F, // controller value clamped
+            0x00, 0xB1, 0x7B, 0x00,
+            0x00, 0xE0, 0x00, 0x60,
+            0x05, 0x80, 0x3C, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        assert_eq!(convert(&lump(&SCORE), MusProfile::Chocolate).unwrap(), expected);
+    }
+
+    #[test]
+    fn prboom_golden() {
+        let expected: &[u8] = &[
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 0x46,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 0x20,
+            0x00, 0x90, 0x3C, 0x48,
+            0x00, 0xC1, 0x1E,
+            0x0A, 0x99, 0x24, 0x7F,
+            0x81, 0x00, 0xB1, 0x07, 0x7F,
+            0x00, 0xB1, 0x7B, 0x00,
+            0x00, 0xE0, 0x00, 0x60,
+            0x05, 0x80, 0x3C, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        assert_eq!(convert(&lump(&SCORE), MusProfile::PrBoom).unwrap(), expected);
+    }
+
+    /// Pins down our DMX model so it doesn't drift; these bytes come from the model,
+    /// not from a capture of DMX.
+    #[test]
+    fn dmx_model_output() {
+        let expected: &[u8] = &[
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 0x46,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 0x2C,
+            0x00, 0xB0, 0x07, 0x64,       // ch 0 first use: default volume
+            0x00, 0x90, 0x3C, 0x7F,       // velocity 200 clamped
+            0x00, 0xB1, 0x07, 0x64,
+            0x00, 0xC1, 0x1E,
+            0x0A, 0xB9, 0x07, 0x64,       // percussion gets the default volume too
+            0x00, 0x99, 0x24, 0x7F,
+            0x81, 0x00, 0xB1, 0x07, 0x7F,
+            0x00, 0xB1, 0x7B, 0x00,
+            0x00, 0xE0, 0x00, 0x60,
+            0x05, 0x80, 0x3C, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        assert_eq!(convert(&lump(&SCORE), MusProfile::Dmx).unwrap(), expected);
+    }
+
+    #[test]
+    fn allocation_skips_the_drum_channel_and_unknown_events_differ() {
+        // MUS channels 0..=9 each play once: the tenth melodic channel lands on MIDI 10
+        let mut score: Vec<u8> = (0..10).flat_map(|c| [0x10 | c, 60]).collect();
+        score.push(0x60);
+        let midi = convert(&lump(&score), MusProfile::PrBoom).unwrap();
+        assert_eq!(&midi[midi.len() - 8..], &[0x00, 0x9A, 60, 127, 0x00, 0xFF, 0x2F, 0x00]);
+
+        // System event 15 doesn't exist: DMX shrugs, the mus2mids give up
+        let odd = lump(&[0x30, 15, 0x60]);
+        assert!(convert(&odd, MusProfile::Dmx).is_ok());
+        assert!(convert(&odd, MusProfile::Chocolate).is_err());
+        assert!(convert(&odd, MusProfile::PrBoom).is_err());
+        assert_eq!("Chocolate".parse::<MusProfile>(), Ok(MusProfile::Chocolate));
+        assert!("zdoom".parse::<MusProfile>().is_err());
+    }
+}
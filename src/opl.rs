@@ -0,0 +1,424 @@
+//! opl.rs
+//!
+//! A software Yamaha OPL2 (YM3812), the FM chip on AdLib and Sound Blaster cards, for
+//! playing register-level AdLib music (see `imf.rs`).
+//!
+//! Covers what music drivers use: nine two-operator channels, the four OPL2
+//! waveforms, feedback, FM and additive connection, envelopes with key-scaled rates,
+//! total level and level scaling, and the tremolo/vibrato LFOs. Rhythm mode keys the
+//! percussion operators but plays them as plain tones, without the noise generator.
+//! Levels follow the chip's decibel steps, but envelopes are computed in floating
+//! point rather than bit-exactly. Output is mono at the chip's own 49716 Hz.
+
+use std::f32::consts::TAU;
+
+/// Native sample rate: the 3.58 MHz clock divided by 72.
+pub const RATE: u32 = 49_716;
+/// Full attenuation, where an operator is silent.
+const MAX_DB: f32 = 96.0;
+/// Phase accumulators count one waveform cycle as 2^20.
+const PHASE_BITS: u32 = 20;
+/// Twice the frequency multiplier for each MULT value (so 0 can be one half).
+const MULT2: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+/// Level scaling per top four F-number bits, in 0.75 dB steps at block 7.
+const KSL_ROM: [i32; 16] = [0, 32, 40, 45, 48, 51, 53, 55, 56, 58, 59, 60, 61, 62, 63, 64];
+/// Right shift of the level scaling for each KSL setting (off, 3, 1.5 and 6 dB/oct).
+const KSL_SHIFT: [u32; 4] = [8, 1, 2, 0];
+/// Per-channel output level, leaving headroom for all nine at once.
+const CHANNEL_GAIN: f32 = 0.2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Stage {
+    #[default]
+    Off,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Operator {
+    tremolo: bool,
+    vibrato: bool,
+    /// EGT: hold at the sustain level until key-off
+    sustained: bool,
+    ksr: bool,
+    mult: u8,
+    ksl: u8,
+    total_level: u8,
+    attack: u8,
+    decay: u8,
+    sustain: u8,
+    release: u8,
+    wave: u8,
+
+    keyed: bool,
+    stage: Stage,
+    /// Envelope attenuation in dB
+    env: f32,
+    phase: u32,
+    /// Last two outputs, for feedback
+    out: [f32; 2],
+}
+
+#[derive(Debug, Clone, Default)]
+struct Channel {
+    fnum: u16,
+    block: u8,
+    key: bool,
+    feedback: u8,
+    additive: bool,
+    ops: [Operator; 2],
+}
+
+impl Channel {
+    /// Key scale rate offset: block and top F-number bit, as NTS picks it.
+    fn rate_offset(&self, nts: bool) -> u32 {
+        let bit = if nts { self.fnum >> 8 } else { self.fnum >> 9 } & 1;
+        (self.block as u32) << 1 | bit as u32
+    }
+
+    /// Level scaling attenuation in dB before the KSL shift is applied.
+    fn ksl_db(&self, ksl: u8) -> f32 {
+        let base = (KSL_ROM[(self.fnum >> 6) as usize & 15] << 2) - ((8 - self.block as i32) << 5);
+        (base.max(0) >> KSL_SHIFT[ksl as usize & 3]) as f32 * 0.1875
+    }
+}
+
+/// Seconds an envelope takes over its full range at effective rate `rate` (0..=63):
+/// the chip doubles speed every four steps, from `slowest` at rate 4.
+fn envelope_seconds(rate: u32, slowest: f32) -> f32 {
+    slowest / (1u32 << ((rate >> 2) - 1)) as f32 * 4.0 / (4 + (rate & 3)) as f32
+}
+
+#[derive(Debug, Clone)]
+pub struct Opl {
+    channels: [Channel; 9],
+    wave_select: bool,
+    nts: bool,
+    deep_tremolo: bool,
+    deep_vibrato: bool,
+    /// Register 0xBD's rhythm bits, with bit 5 enabling rhythm mode
+    rhythm: u8,
+    /// Samples rendered, for the LFOs
+    clock: u64,
+}
+
+impl Default for Opl {
+    fn default() -> Self {
+        let mut channels: [Channel; 9] = Default::default();
+        // Operators start silent, so one that never attacks stays that way
+        for o in channels.iter_mut().flat_map(|c| c.ops.iter_mut()) {
+            o.env = MAX_DB;
+        }
+        Self {
+            channels,
+            wave_select: false,
+            nts: false,
+            deep_tremolo: false,
+            deep_vibrato: false,
+            rhythm: 0,
+            clock: 0,
+        }
+    }
+}
+
+/// Channel and operator for an operator register offset (0x00..=0x15).
+fn slot(offset: u8) -> Option<(usize, usize)> {
+    let (group, i) = ((offset / 8) as usize, (offset % 8) as usize);
+    match i {
+        0..=2 if group < 3 => Some((group * 3 + i, 0)),
+        3..=5 if group < 3 => Some((group * 3 + i - 3, 1)),
+        _ => None,
+    }
+}
+
+impl Opl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write `value` to register `reg`.
+    pub fn write(&mut self, reg: u8, value: u8) {
+        match reg {
+            0x01 => self.wave_select = value & 0x20 != 0,
+            0x08 => self.nts = value & 0x40 != 0,
+            0x20..=0x35 | 0x40..=0x55 | 0x60..=0x75 | 0x80..=0x95 | 0xE0..=0xF5 => {
+                let Some((ch, op)) = slot(reg & 0x1F) else { return };
+                let o = &mut self.channels[ch].ops[op];
+                match reg & 0xE0 {
+                    0x20 => {
+                        o.tremolo = value & 0x80 != 0;
+                        o.vibrato = value & 0x40 != 0;
+                        o.sustained = value & 0x20 != 0;
+                        o.ksr = value & 0x10 != 0;
+                        o.mult = value & 0x0F;
+                    }
+                    0x40 => {
+                        o.ksl = value >> 6;
+                        o.total_level = value & 0x3F;
+                    }
+                    0x60 => {
+                        o.attack = value >> 4;
+                        o.decay = value & 0x0F;
+                    }
+                    0x80 => {
+                        o.sustain = value >> 4;
+                        o.release = value & 0x0F;
+                    }
+                    _ => o.wave = value & 3,
+                }
+            }
+            0xA0..=0xA8 => {
+                let c = &mut self.channels[(reg - 0xA0) as usize];
+                c.fnum = (c.fnum & 0x300) | value as u16;
+            }
+            0xB0..=0xB8 => {
+                let ch = (reg - 0xB0) as usize;
+                let c = &mut self.channels[ch];
+                c.fnum = (c.fnum & 0xFF) | ((value as u16 & 3) << 8);
+                c.block = (value >> 2) & 7;
+                c.key = value & 0x20 != 0;
+                self.update_keys();
+            }
+            0xBD => {
+                self.deep_tremolo = value & 0x80 != 0;
+                self.deep_vibrato = value & 0x40 != 0;
+                self.rhythm = value & 0x3F;
+                self.update_keys();
+            }
+            0xC0..=0xC8 => {
+                let c = &mut self.channels[(reg - 0xC0) as usize];
+                c.feedback = (value >> 1) & 7;
+                c.additive = value & 1 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn rhythm_mode(&self) -> bool {
+        self.rhythm & 0x20 != 0
+    }
+
+    /// Start or release each operator whose key (channel or drum bit) changed.
+    fn update_keys(&mut self) {
+        let drums = if self.rhythm_mode() { self.rhythm } else { 0 };
+        for (ch, c) in self.channels.iter_mut().enumerate() {
+            for (op, o) in c.ops.iter_mut().enumerate() {
+                // BD is channel 6; HH/SD and TOM/CY are the operators of 7 and 8
+                let drum = match (ch, op) {
+                    (6, _) => drums & 0x10 != 0,
+                    (7, 0) => drums & 0x01 != 0,
+                    (7, 1) => drums & 0x08 != 0,
+                    (8, 0) => drums & 0x04 != 0,
+                    (8, 1) => drums & 0x02 != 0,
+                    _ => false,
+                };
+                let on = c.key || drum;
+                if on && !o.keyed {
+                    o.stage = Stage::Attack;
+                    o.phase = 0;
+                } else if !on && o.keyed && o.stage != Stage::Off {
+                    o.stage = Stage::Release;
+                }
+                o.keyed = on;
+            }
+        }
+    }
+
+    /// Add the next `out.len()` mono samples to `out`.
+    pub fn render(&mut self, out: &mut [f32]) {
+        for s in out.iter_mut() {
+            let t = self.clock as f32 / RATE as f32;
+            self.clock += 1;
+            // Tremolo: a 3.7 Hz triangle of 1 or 4.8 dB
+            let tri = 1.0 - (2.0 * (t * 3.7).fract() - 1.0).abs();
+            let tremolo_db = tri * if self.deep_tremolo { 4.8 } else { 1.0 };
+            // Vibrato: 6.1 Hz, 7 or 14 cents
+            let cents = (t * 6.1 * TAU).sin() * if self.deep_vibrato { 14.0 } else { 7.0 };
+            let vibrato = 2f32.powf(cents / 1200.0);
+
+            let rhythm = self.rhythm_mode();
+            let nts = self.nts;
+            let wave_select = self.wave_select;
+            let mut mix = 0.0;
+            for (ch, c) in self.channels.iter_mut().enumerate() {
+                if c.ops.iter().all(|o| o.stage == Stage::Off) {
+                    continue;
+                }
+                let base = (c.fnum as u32) << c.block;
+                let rate_offset = c.rate_offset(nts);
+                let ksl = [c.ksl_db(c.ops[0].ksl), c.ksl_db(c.ops[1].ksl)];
+                let feedback = c.feedback;
+                let additive = c.additive;
+                let [m, k] = &mut c.ops;
+                let run = |o: &mut Operator, ksl: f32, modulation: f32| {
+                    o.step_envelope(rate_offset);
+                    let out = o.output(ksl, tremolo_db, modulation, wave_select);
+                    let inc = base * MULT2[o.mult as usize] / 2;
+                    let inc = if o.vibrato { (inc as f32 * vibrato) as u32 } else { inc };
+                    o.phase = o.phase.wrapping_add(inc) & ((1 << PHASE_BITS) - 1);
+                    out
+                };
+
+                let fb = if feedback > 0 { (m.out[0] + m.out[1]) * 4.0 / (1 << (9 - feedback)) as f32 } else { 0.0 };
+                let out = if rhythm && ch >= 7 {
+                    run(m, ksl[0], 0.0) + run(k, ksl[1], 0.0)
+                } else {
+                    let mod_out = run(m, ksl[0], fb);
+                    m.out = [mod_out, m.out[0]];
+                    if additive && !(rhythm && ch == 6) {
+                        mod_out + run(k, ksl[1], 0.0)
+                    } else {
+                        // A full-scale modulator swings the carrier four cycles
+                        run(k, ksl[1], mod_out * 4.0)
+                    }
+                };
+                mix += out * CHANNEL_GAIN;
+            }
+            *s += mix;
+        }
+    }
+}
+
+impl Operator {
+    fn step_envelope(&mut self, rate_offset: u32) {
+        let offset = if self.ksr { rate_offset } else { rate_offset >> 2 };
+        let rate = |r: u8| if r == 0 { 0 } else { (4 * r as u32 + offset).min(63) };
+        let per_sample = |r: u8| match rate(r) {
+            0 => 0.0,
+            r => MAX_DB / (envelope_seconds(r, 39.28) * RATE as f32),
+        };
+        let sustain_db = if self.sustain == 15 { 93.0 } else { self.sustain as f32 * 3.0 };
+
+        match self.stage {
+            Stage::Off => {}
+            Stage::Attack => match rate(self.attack) {
+                0 => {}
+                r if r >= 60 => {
+                    self.env = 0.0;
+                    self.stage = Stage::Decay;
+                }
+                r => {
+                    // Attenuation falls exponentially, taking envelope_seconds to get
+                    // from silence to full level
+                    let k = 5.26 / (envelope_seconds(r, 2.826) * RATE as f32);
+                    self.env -= (self.env + 0.5) * k;
+                    if self.env <= 0.05 {
+                        self.env = 0.0;
+                        self.stage = Stage::Decay;
+                    }
+                }
+            },
+            Stage::Decay => {
+                self.env += per_sample(self.decay);
+                if self.env >= sustain_db {
+                    self.env = sustain_db;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            // Without EGT the note carries on fading at the release rate
+            Stage::Sustain if self.sustained => {}
+            Stage::Sustain | Stage::Release => {
+                self.env += per_sample(self.release);
+                if self.env >= MAX_DB {
+                    self.env = MAX_DB;
+                    self.stage = Stage::Off;
+                }
+            }
+        }
+    }
+
+    /// This sample's output, -1..=1, with the phase pushed along by `modulation` cycles.
+    fn output(&self, ksl_db: f32, tremolo_db: f32, modulation: f32, wave_select: bool) -> f32 {
+        if self.stage == Stage::Off {
+            return 0.0;
+        }
+        let attenuation = self.env
+            + self.total_level as f32 * 0.75
+            + ksl_db
+            + if self.tremolo { tremolo_db } else { 0.0 };
+        if attenuation >= MAX_DB {
+            return 0.0;
+        }
+        let x = (self.phase as f32 / (1 << PHASE_BITS) as f32 + modulation).rem_euclid(1.0);
+        let sine = (x * TAU).sin();
+        let wave = match if wave_select { self.wave } else { 0 } {
+            0 => sine,
+            1 => sine.max(0.0),
+            2 => sine.abs(),
+            _ if x.rem_euclid(0.5) < 0.25 => sine.abs(),
+            _ => 0.0,
+        };
+        wave * 10f32.powf(-attenuation / 20.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Channel 0 as a plain sine: carrier only, instant attack, full sustain.
+    fn sine_patch(opl: &mut Opl) {
+        opl.write(0x20, 0x21); // modulator: sustained, MULT 1
+        opl.write(0x23, 0x21); // carrier: same
+        opl.write(0x40, 0x3F); // modulator silent
+        opl.write(0x43, 0x00); // carrier at full level
+        opl.write(0x63, 0xF0); // instant attack, no decay
+        opl.write(0x83, 0x0F); // sustain at 0 dB, fastest release
+        opl.write(0xC0, 0x00);
+    }
+
+    fn crossings(s: &[f32]) -> usize {
+        s.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count()
+    }
+
+    #[test]
+    fn plays_a_note_at_the_right_pitch_and_releases_it() {
+        let mut opl = Opl::new();
+        sine_patch(&mut opl);
+        // F-number 0x244 at block 4 is ~440 Hz: 580 * 49716 * 2^(4-20)
+        opl.write(0xA0, 0x44);
+        opl.write(0xB0, 0x20 | (4 << 2) | 0x02);
+
+        let mut buf = vec![0.0; RATE as usize];
+        opl.render(&mut buf);
+        let hz = crossings(&buf) as f32 / 2.0;
+        assert!((hz - 440.0).abs() < 2.0, "{hz} Hz");
+        let peak = buf.iter().fold(0.0f32, |a, &s| a.max(s.abs()));
+        assert!((peak - CHANNEL_GAIN).abs() < 0.01, "peak {peak}");
+
+        // Key off: the fastest release is silent within a few ms
+        opl.write(0xB0, (4 << 2) | 0x02);
+        let mut tail = vec![0.0; RATE as usize / 10];
+        opl.render(&mut tail);
+        assert!(tail[tail.len() / 2..].iter().all(|&s| s == 0.0));
+        assert_eq!(opl.channels[0].ops[1].stage, Stage::Off);
+
+        // The modulator at full level adds overtones, not pitch
+        opl.write(0x40, 0x00);
+        opl.write(0x60, 0xF0);
+        opl.write(0xB0, 0x20 | (4 << 2) | 0x02);
+        let mut fm = vec![0.0; RATE as usize];
+        opl.render(&mut fm);
+        assert!(crossings(&fm) > crossings(&buf));
+    }
+
+    #[test]
+    fn registers_map_to_the_right_operators() {
+        assert_eq!(slot(0x00), Some((0, 0)));
+        assert_eq!(slot(0x03), Some((0, 1)));
+        assert_eq!(slot(0x08), Some((3, 0)));
+        assert_eq!(slot(0x15), Some((8, 1)));
+        assert_eq!(slot(0x06), None);
+
+        let mut opl = Opl::new();
+        opl.write(0x55, 0x80 | 0x12);
+        assert_eq!((opl.channels[8].ops[1].ksl, opl.channels[8].ops[1].total_level), (2, 0x12));
+        // Rhythm mode keys the bass drum on channel 6 without a channel key-on
+        opl.write(0xBD, 0x30);
+        assert!(opl.channels[6].ops.iter().all(|o| o.stage == Stage::Attack));
+        assert_eq!(opl.channels[7].ops[0].stage, Stage::Off);
+    }
+}
@@ -0,0 +1,353 @@
+//! pk3.rs
+//!
+//! PK3 archives: ZIP files laid out the ZDoom way, with the top-level folder choosing
+//! the namespace (`music/`, `sounds/`, `sprites/`, ...) and any `.wad` inside loaded as
+//! an embedded WAD.
+//!
+//! Rather than a second lump API, an archive is repacked into an in-memory PWAD:
+//! each folder becomes an `X_START`/`X_END` section, so listing, lookup and playback
+//! go through `Wad` unchanged. Resource folders (`folder.rs`) use the same layout.
+//! Only stored and deflated entries are supported, which is what every PK3 tool
+//! writes; ZIP64 and encryption are rejected. Entries are never inflated past the size
+//! the directory declares for them, and those sizes may not add up to more than a WAD
+//! can hold.
+
+use anyhow::Result;
+
+use miniz_oxide::inflate::{decompress_to_vec_with_limit, TINFLStatus};
+
+use crate::wad::{lump_name, Namespace, Wad, WadBuilder};
+
+const LOCAL_SIG: u32 = 0x0403_4b50;
+const CENTRAL_SIG: u32 = 0x0201_4b50;
+const END_SIG: u32 = 0x0605_4b50;
+/// Fixed part of the end of central directory record; a comment of up to 64K follows.
+const END_LEN: usize = 22;
+const CENTRAL_LEN: usize = 46;
+const LOCAL_LEN: usize = 30;
+
+/// Most an archive may unpack to; a WAD's offsets are 32-bit, so anything larger
+/// couldn't be repacked anyway.
+pub const MAX_UNPACKED: u64 = u32::MAX as u64;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Pk3Error {
+    #[error("no ZIP central directory found")]
+    NoCentralDirectory,
+    #[error("ZIP64 archives aren't supported")]
+    Zip64,
+    #[error("central directory is truncated")]
+    TruncatedDirectory,
+    #[error("{0}: entry data runs past end of file")]
+    EntryOutOfBounds(String),
+    #[error("{0}: encrypted entries aren't supported")]
+    Encrypted(String),
+    #[error("{name}: compression method {method} isn't supported")]
+    UnsupportedMethod { name: String, method: u16 },
+    #[error("{name}: {reason}")]
+    Inflate { name: String, reason: String },
+    #[error("{0}: inflates past its declared size")]
+    TooLarge(String),
+    #[error("archive unpacks to {0} bytes, more than a WAD can hold")]
+    ArchiveTooLarge(u64),
+    #[error("{0}: CRC mismatch")]
+    Crc(String),
+}
+
+/// One file in the archive's central directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Full path with `/` separators, as stored
+    pub path: String,
+    method: u16,
+    flags: u16,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    local_offset: u32,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.path.ends_with('/')
+    }
+}
+
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06")
+}
+
+/// Read the central directory.
+pub fn entries(data: &[u8]) -> Result<Vec<Entry>, Pk3Error> {
+    if data.len() < END_LEN {
+        return Err(Pk3Error::NoCentralDirectory);
+    }
+    // The end record is the last thing in the file, before an optional comment
+    let search_from = data.len().saturating_sub(END_LEN + u16::MAX as usize);
+    let end = (search_from..=data.len() - END_LEN)
+        .rev()
+        .find(|&i| read_u32(data, i) == END_SIG)
+        .ok_or(Pk3Error::NoCentralDirectory)?;
+    let count = read_u16(data, end + 10) as usize;
+    let dir_offset = read_u32(data, end + 16);
+    if count == 0xffff || dir_offset == 0xffff_ffff {
+        return Err(Pk3Error::Zip64);
+    }
+
+    let mut at = dir_offset as usize;
+    let mut out = Vec::with_capacity(count.min(data.len() / CENTRAL_LEN));
+    for _ in 0..count {
+        let fixed = data.get(at..at + CENTRAL_LEN).ok_or(Pk3Error::TruncatedDirectory)?;
+        if read_u32(fixed, 0) != CENTRAL_SIG {
+            return Err(Pk3Error::TruncatedDirectory);
+        }
+        let name_len = read_u16(fixed, 28) as usize;
+        let extra_len = read_u16(fixed, 30) as usize;
+        let comment_len = read_u16(fixed, 32) as usize;
+        let name = data
+            .get(at + CENTRAL_LEN..at + CENTRAL_LEN + name_len)
+            .ok_or(Pk3Error::TruncatedDirectory)?;
+        let entry = Entry {
+            path: String::from_utf8_lossy(name).replace('\\', "/"),
+            flags: read_u16(fixed, 8),
+            method: read_u16(fixed, 10),
+            crc: read_u32(fixed, 16),
+            compressed: read_u32(fixed, 20),
+            size: read_u32(fixed, 24),
+            local_offset: read_u32(fixed, 42),
+        };
+        if entry.compressed == 0xffff_ffff || entry.size == 0xffff_ffff || entry.local_offset == 0xffff_ffff {
+            return Err(Pk3Error::Zip64);
+        }
+        out.push(entry);
+        at += CENTRAL_LEN + name_len + extra_len + comment_len;
+    }
+    Ok(out)
+}
+
+/// Decompress one entry and check its CRC.
+pub fn read(data: &[u8], e: &Entry) -> Result<Vec<u8>, Pk3Error> {
+    let out_of_bounds = || Pk3Error::EntryOutOfBounds(e.path.clone());
+    if e.flags & 1 != 0 {
+        return Err(Pk3Error::Encrypted(e.path.clone()));
+    }
+    // The local header repeats the name and may carry a different extra field
+    let local = e.local_offset as usize;
+    let header = data.get(local..local + LOCAL_LEN).ok_or_else(out_of_bounds)?;
+    if read_u32(header, 0) != LOCAL_SIG {
+        return Err(out_of_bounds());
+    }
+    let start = local + LOCAL_LEN + read_u16(header, 26) as usize + read_u16(header, 28) as usize;
+    let raw = data.get(start..start + e.compressed as usize).ok_or_else(out_of_bounds)?;
+
+    let bytes = match e.method {
+        0 => raw.to_vec(),
+        8 => decompress_to_vec_with_limit(raw, e.size as usize).map_err(|err| match err.status {
+            TINFLStatus::HasMoreOutput => Pk3Error::TooLarge(e.path.clone()),
+            _ => Pk3Error::Inflate { name: e.path.clone(), reason: err.to_string() },
+        })?,
+        method => return Err(Pk3Error::UnsupportedMethod { name: e.path.clone(), method }),
+    };
+    if bytes.len() != e.size as usize || crc32fast::hash(&bytes) != e.crc {
+        return Err(Pk3Error::Crc(e.path.clone()));
+    }
+    Ok(bytes)
+}
+
+/// Namespace for a top-level PK3 folder, and the marker prefix that opens it.
+fn folder_namespace(folder: &str) -> Option<(Namespace, &'static str)> {
+    Some(match folder.to_ascii_lowercase().as_str() {
+        "music" => (Namespace::Music, "MU"),
+        "sounds" => (Namespace::Sounds, "DS"),
+        "sprites" => (Namespace::Sprites, "S"),
+        "flats" => (Namespace::Flats, "F"),
+        "patches" => (Namespace::Patches, "P"),
+        "textures" => (Namespace::Textures, "TX"),
+        "colormaps" => (Namespace::Colormaps, "C"),
+        "voxels" => (Namespace::Voxels, "VX"),
+        "hires" => (Namespace::HiRes, "HI"),
+        _ => return None,
+    })
+}
+
+/// A path or lump name, and the file's contents.
+pub type NamedLump = (String, Vec<u8>);
+
+/// Repack a PK3 into a PWAD.
+pub fn to_wad(data: &[u8]) -> Result<Wad> {
+    let entries = entries(data)?;
+    let declared = entries.iter().map(|e| e.size as u64).sum::<u64>();
+    if declared > MAX_UNPACKED {
+        return Err(Pk3Error::ArchiveTooLarge(declared).into());
+    }
+    let mut files = Vec::new();
+    for e in entries.iter().filter(|e| !e.is_dir()) {
+        files.push((e.path.clone(), read(data, e)?));
+    }
+    layout(files)
+}
+
+/// Lay out files given by `/`-separated relative path the way ZDoom does for PK3s
+/// and resource folders.
+///
+/// Root files and unrecognized folders come first with no markers, then one section
+/// per recognized folder (subfolders flattened into it), then embedded WADs in
+/// order. Names are file stems cut to 8 characters, like ZDoom's short names. An
+/// embedded WAD that doesn't parse is skipped with a warning rather than losing the
+/// rest of the archive.
+pub fn layout(files: impl IntoIterator<Item = NamedLump>) -> Result<Wad> {
+    let mut global = Vec::new();
+    let mut sections: Vec<(Namespace, &str, Vec<NamedLump>)> = Vec::new();
+    let mut embedded = Vec::new();
+
+    for (path, bytes) in files {
+        let (folder, file) = match path.split_once('/') {
+            Some((folder, rest)) => (Some(folder), rest.rsplit('/').next().unwrap_or(rest)),
+            None => (None, path.as_str()),
+        };
+        if file.to_ascii_lowercase().ends_with(".wad") {
+            match Wad::from_bytes(bytes) {
+                Ok(wad) => embedded.push(wad),
+                Err(e) => eprintln!("warning: skipping embedded WAD {}: {}", path, e),
+            }
+            continue;
+        }
+        let name = lump_name(file);
+        match folder.and_then(folder_namespace) {
+            Some((ns, marker)) => match sections.iter_mut().find(|(n, ..)| *n == ns) {
+                Some((.., lumps)) => lumps.push((name, bytes)),
+                None => sections.push((ns, marker, vec![(name, bytes)])),
+            },
+            None => global.push((name, bytes)),
+        }
+    }
+
+    let mut b = WadBuilder::default();
+    for (name, bytes) in &global {
+        b.lump(name, bytes);
+    }
+    for (_, marker, lumps) in &sections {
+        b.section(marker, |b| {
+            for (name, bytes) in lumps {
+                b.lump(name, bytes);
+            }
+        });
+    }
+    for wad in &embedded {
+        b.extend(wad);
+    }
+    Ok(b.build())
+}
+
+fn read_u16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn read_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::is_music_lump;
+
+    /// A ZIP with the given (path, method, stored bytes, uncompressed bytes) entries.
+    fn zip(files: &[(&str, u16, &[u8], &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for (path, method, stored, plain) in files {
+            let offset = out.len() as u32;
+            let mut common = Vec::new();
+            common.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            common.extend_from_slice(&0u16.to_le_bytes()); // flags
+            common.extend_from_slice(&method.to_le_bytes());
+            common.extend_from_slice(&[0; 4]); // time, date
+            common.extend_from_slice(&crc32fast::hash(plain).to_le_bytes());
+            common.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            common.extend_from_slice(&(plain.len() as u32).to_le_bytes());
+            common.extend_from_slice(&(path.len() as u16).to_le_bytes());
+            common.extend_from_slice(&0u16.to_le_bytes()); // extra
+
+            out.extend_from_slice(&LOCAL_SIG.to_le_bytes());
+            out.extend_from_slice(&common);
+            out.extend_from_slice(path.as_bytes());
+            out.extend_from_slice(stored);
+
+            central.extend_from_slice(&CENTRAL_SIG.to_le_bytes());
+            central.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            central.extend_from_slice(&common);
+            central.extend_from_slice(&[0; 6]); // comment length, disk, internal attrs
+            central.extend_from_slice(&[0; 4]); // external attrs
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(path.as_bytes());
+        }
+        let dir_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&END_SIG.to_le_bytes());
+        out.extend_from_slice(&[0; 4]); // disk numbers
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&dir_offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn pk3_folders_become_namespaces_and_embedded_wads_are_merged() {
+        let mut inner = WadBuilder::default();
+        inner.lump("D_E1M1", b"MUS\x1a");
+        let inner = inner.to_bytes();
+        // "hello hello hello", deflated
+        let deflated = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00];
+
+        let data = zip(&[
+            ("music/", 0, b"", b""),
+            ("music/runnin.ogg", 0, b"OggS", b"OggS"),
+            ("Music/Extra/d_bunny.mid", 0, b"MThd", b"MThd"),
+            ("sprites/d_trooa1.png", 0, b"png", b"png"),
+            ("readme.txt", 8, &deflated, b"hello hello hello"),
+            ("maps/e1m1.wad", 0, &inner, &inner),
+            ("maps/broken.wad", 0, b"PWAD", b"PWAD"),
+        ]);
+        let wad = to_wad(&data).unwrap();
+
+        assert_eq!(wad.read("README").unwrap(), b"hello hello hello");
+        assert_eq!(wad.read_in("RUNNIN", &[Namespace::Music]).unwrap(), b"OggS");
+        assert_eq!(wad.read_in("D_BUNNY", &[Namespace::Music]).unwrap(), b"MThd");
+        assert_eq!(wad.read("D_E1M1").unwrap(), b"MUS\x1a");
+        let songs: Vec<&str> = wad.lumps().iter().filter(|l| is_music_lump(l)).map(|l| l.name.as_str()).collect();
+        assert_eq!(songs, ["RUNNIN", "D_BUNNY", "D_E1M1"]);
+        assert!(wad.validate().is_empty());
+    }
+
+    #[test]
+    fn broken_archives_are_rejected() {
+        assert_eq!(entries(b"PK\x03\x04 not really").unwrap_err(), Pk3Error::NoCentralDirectory);
+
+        let mut data = zip(&[("music/x.mid", 0, b"MThd", b"MThd")]);
+        let e = entries(&data).unwrap().remove(0);
+        data[LOCAL_LEN + "music/x.mid".len()] ^= 0xff;
+        assert_eq!(read(&data, &e).unwrap_err(), Pk3Error::Crc("music/x.mid".into()));
+
+        let data = zip(&[("x.lmp", 14, b"??", b"??")]);
+        let e = entries(&data).unwrap().remove(0);
+        assert!(matches!(read(&data, &e), Err(Pk3Error::UnsupportedMethod { method: 14, .. })));
+
+        // Declares 5 bytes but inflates to 17: stopped at the limit
+        let deflated = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00];
+        let data = zip(&[("bomb.lmp", 8, &deflated, b"hello")]);
+        let e = entries(&data).unwrap().remove(0);
+        assert_eq!(read(&data, &e).unwrap_err(), Pk3Error::TooLarge("bomb.lmp".into()));
+
+        // Each entry may be small enough on its own, but not all of them together
+        let mut data = zip(&[("a.lmp", 0, b"a", b"a"), ("b.lmp", 0, b"b", b"b")]);
+        let dirs: Vec<usize> = (0..data.len() - 4).filter(|&i| data[i..i + 4] == CENTRAL_SIG.to_le_bytes()).collect();
+        for at in dirs {
+            data[at + 24..at + 28].copy_from_slice(&0xc000_0000u32.to_le_bytes());
+        }
+        let err = to_wad(&data).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Pk3Error::ArchiveTooLarge(0x1_8000_0000)));
+    }
+}
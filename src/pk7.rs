@@ -0,0 +1,61 @@
+//! pk7.rs
+//!
+//! PK7 archives: 7-Zip files laid out like a PK3, which is how ZDoom treats them.
+//! They are unpacked with `sevenz-rust` and repacked through `pk3::layout`, so the
+//! folders become the same namespaces. Encrypted archives are rejected.
+
+use std::io::Cursor;
+
+use anyhow::Result;
+use sevenz_rust::{Password, SevenZReader};
+
+use crate::pk3::{self, NamedLump, Pk3Error};
+use crate::wad::Wad;
+
+pub const MAGIC: &[u8] = b"7z\xbc\xaf\x27\x1c";
+
+/// Repack a PK7 into a PWAD.
+pub fn to_wad(data: &[u8]) -> Result<Wad> {
+    let mut reader = SevenZReader::new(Cursor::new(data), data.len() as u64, Password::empty())?;
+    let declared = reader.archive().files.iter().fold(0u64, |sum, f| sum.saturating_add(f.size));
+    if declared > pk3::MAX_UNPACKED {
+        return Err(Pk3Error::ArchiveTooLarge(declared).into());
+    }
+
+    let mut files: Vec<NamedLump> = Vec::new();
+    reader.for_each_entries(|entry, r| {
+        if entry.is_directory() {
+            return Ok(true);
+        }
+        // Each entry's reader stops at its declared size and checks its CRC at the end
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        files.push((entry.name().replace('\\', "/"), bytes));
+        Ok(true)
+    })?;
+    pk3::layout(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::Namespace;
+    use sevenz_rust::{SevenZArchiveEntry, SevenZWriter};
+
+    #[test]
+    fn pk7_folders_become_namespaces() {
+        let mut w = SevenZWriter::new(Cursor::new(Vec::new())).unwrap();
+        for (path, bytes) in [("music/d_runnin.mus", &b"MUS\x1a"[..]), ("readme.txt", b"hello")] {
+            let mut entry = SevenZArchiveEntry::new();
+            entry.name = path.to_string();
+            w.push_archive_entry(entry, Some(bytes)).unwrap();
+        }
+        let data = w.finish().unwrap().into_inner();
+        assert!(data.starts_with(MAGIC));
+
+        let wad = to_wad(&data).unwrap();
+        assert_eq!(wad.read_in("D_RUNNIN", &[Namespace::Music]).unwrap(), b"MUS\x1a");
+        assert_eq!(wad.read("README").unwrap(), b"hello");
+        assert!(to_wad(&data[..data.len() - 8]).is_err());
+    }
+}
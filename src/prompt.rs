@@ -0,0 +1,80 @@
+//! prompt.rs
+//!
+//! The REPL's input line: a small line editor with Tab completion. Without a
+//! terminal (piped input, CI) it falls back to plain line reads.
+
+use std::io::{stdin, stdout, IsTerminal, Write};
+
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+
+use crate::commands::RawGuard;
+
+/// Print `prompt` and read a line, completing with `complete` on Tab: a single
+/// completion replaces the input, several extend it as far as they agree and are
+/// listed if they don't agree any further. `None` means the input ended (Ctrl-C, or
+/// Ctrl-D on an empty line).
+pub fn read_line(prompt: &str, complete: impl Fn(&str) -> Vec<String>) -> Result<Option<String>> {
+    print!("{prompt}");
+    stdout().flush().ok();
+    if !stdin().is_terminal() {
+        let mut line = String::new();
+        return Ok((stdin().read_line(&mut line)? > 0).then_some(line));
+    }
+
+    let _raw = RawGuard::enter()?;
+    let mut line = String::new();
+    loop {
+        let Event::Key(k) = event::read()? else { continue };
+        if k.kind == KeyEventKind::Release {
+            continue;
+        }
+        let ctrl = k.modifiers.contains(KeyModifiers::CONTROL);
+        match k.code {
+            KeyCode::Enter => {
+                print!("\r\n");
+                return Ok(Some(line));
+            }
+            KeyCode::Char('c') if ctrl => {
+                print!("\r\n");
+                return Ok(None);
+            }
+            KeyCode::Char('d') if ctrl && line.is_empty() => {
+                print!("\r\n");
+                return Ok(None);
+            }
+            KeyCode::Char(c) if !ctrl => {
+                line.push(c);
+                print!("{c}");
+            }
+            // The pop is the edit itself, so it stays out of a match guard
+            #[allow(clippy::collapsible_match)]
+            KeyCode::Backspace => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+            }
+            KeyCode::Tab => {
+                let options = complete(&line);
+                let common = common_prefix(&options);
+                if common.chars().count() > line.chars().count() {
+                    print!("{}{}", "\x08 \x08".repeat(line.chars().count()), common);
+                    line = common;
+                } else if options.len() > 1 {
+                    print!("\r\n{}\r\n{}{line}", options.join("  "), prompt.trim_start());
+                }
+            }
+            _ => {}
+        }
+        stdout().flush().ok();
+    }
+}
+
+/// The longest start all of `options` share, ignoring case, spelled as the first one.
+fn common_prefix(options: &[String]) -> String {
+    let Some(first) = options.first() else { return String::new() };
+    let len = options[1..].iter().fold(first.chars().count(), |len, o| {
+        first.chars().zip(o.chars()).take(len).take_while(|(a, b)| a.eq_ignore_ascii_case(b)).count()
+    });
+    first.chars().take(len).collect()
+}
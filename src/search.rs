@@ -0,0 +1,188 @@
+//! search.rs
+//!
+//! Ranked, typo-tolerant song search for the REPL. Every song is indexed under its
+//! lump name, the name without its D_/MUS_ prefix, its track title (for a song known
+//! to be the IWAD's own), the maps that play it and those maps' titles, so "runin",
+//! "running from evil", "map7" and "entryway" all find something.
+//!
+//! A key scores best as an exact match, then as a prefix, then as a substring, then
+//! within a small edit distance of the query (or of one of its words), and last as
+//! a subsequence of it.
+
+use crate::mapinfo::MapTable;
+use crate::song::MUSIC_PREFIXES;
+
+/// One song and the strings it can be found by.
+struct Entry {
+    song: String,
+    keys: Vec<String>,
+}
+
+/// A search result: the song and the key that matched best.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub song: String,
+    pub key: String,
+    pub score: u32,
+}
+
+pub struct SongIndex {
+    entries: Vec<Entry>,
+}
+
+impl SongIndex {
+    /// Index `names`, each under its `title` too if it has one.
+    pub fn new<'t>(names: &[String], maps: &MapTable, title: impl Fn(&str) -> Option<&'t str>) -> Self {
+        let entries = names
+            .iter()
+            .map(|name| {
+                let mut keys = vec![name.clone()];
+                if let Some(bare) = MUSIC_PREFIXES.iter().find_map(|p| name.strip_prefix(p)) {
+                    keys.push(bare.to_string());
+                }
+                if let Some(title) = title(name) {
+                    keys.push(title.to_string());
+                }
+                for map in maps.maps_for(name) {
+                    if let Some(title) = maps.get(&map).and_then(|m| m.title.clone()) {
+                        keys.push(title);
+                    }
+                    keys.push(map);
+                }
+                Entry { song: name.clone(), keys }
+            })
+            .collect();
+        SongIndex { entries }
+    }
+
+    /// The best `limit` songs for `query`, best first; ties keep directory order.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<Hit> {
+        let mut hits: Vec<Hit> = self
+            .entries
+            .iter()
+            .filter_map(|e| {
+                e.keys
+                    .iter()
+                    .filter_map(|k| score(query, k).map(|s| (s, k)))
+                    .max_by_key(|&(s, _)| s)
+                    .map(|(score, key)| Hit { song: e.song.clone(), key: key.clone(), score })
+            })
+            .collect();
+        hits.sort_by_key(|h| std::cmp::Reverse(h.score));
+        hits.truncate(limit);
+        hits
+    }
+
+    /// Keys starting with `prefix`, ignoring case, for tab completion.
+    pub fn complete(&self, prefix: &str) -> Vec<String> {
+        let prefix = prefix.to_ascii_lowercase();
+        let mut out: Vec<String> = Vec::new();
+        for k in self.entries.iter().flat_map(|e| &e.keys) {
+            if k.to_ascii_lowercase().starts_with(&prefix) && !out.contains(k) {
+                out.push(k.clone());
+            }
+        }
+        out
+    }
+}
+
+/// Lower-case letters and digits, with every run of anything else made one space.
+fn normalize(s: &str) -> Vec<char> {
+    let mut out = Vec::new();
+    for c in s.chars() {
+        if c.is_alphanumeric() {
+            out.extend(c.to_lowercase());
+        } else if out.last().is_some_and(|&l| l != ' ') {
+            out.push(' ');
+        }
+    }
+    while out.last() == Some(&' ') {
+        out.pop();
+    }
+    out
+}
+
+/// How well `query` matches `key`, or `None` if it doesn't at all.
+pub fn score(query: &str, key: &str) -> Option<u32> {
+    let (q, k) = (normalize(query), normalize(key));
+    if q.is_empty() || k.is_empty() {
+        return None;
+    }
+    if q == k {
+        return Some(1000);
+    }
+    if k.starts_with(&q) {
+        return Some(900 - (k.len() - q.len()).min(99) as u32);
+    }
+    if let Some(pos) = k.windows(q.len()).position(|w| w == q.as_slice()) {
+        return Some(800 - pos.min(99) as u32);
+    }
+    // Typos: the closest any prefix of the key, or of the key from one of its words on,
+    // comes to the query
+    let typo = (q.len() >= 3)
+        .then(|| {
+            let starts = std::iter::once(0).chain(k.iter().enumerate().filter(|&(_, &c)| c == ' ').map(|(i, _)| i + 1));
+            starts.map(|s| prefix_distance(&q, &k[s..])).min()
+        })
+        .flatten()
+        .filter(|&d| d <= q.len() / 4 + 1)
+        .map(|d| 600 - 100 * d.min(5) as u32);
+    let subsequence = subsequence_gaps(&q, &k).map(|gaps| 300 - gaps.min(299) as u32);
+    typo.max(subsequence)
+}
+
+/// The smallest Levenshtein distance from `a` to any prefix of `b`.
+fn prefix_distance(a: &[char], b: &[char]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            cur[j + 1] = (prev[j] + (ca != cb) as usize).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev.into_iter().min().unwrap_or(0)
+}
+
+/// If `q` is a subsequence of `k`, how many characters of `k` it skips after its
+/// first match.
+fn subsequence_gaps(q: &[char], k: &[char]) -> Option<usize> {
+    let first = k.iter().position(|&c| c == q[0])?;
+    let mut gaps = 0;
+    let mut rest = k[first + 1..].iter();
+    for &c in &q[1..] {
+        let skipped = rest.by_ref().position(|&x| x == c)?;
+        gaps += skipped;
+    }
+    Some(gaps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::titles;
+
+    #[test]
+    fn ranks_exact_prefix_typo_and_subsequence_matches() {
+        assert_eq!(score("runnin", "RUNNIN"), Some(1000));
+        assert!(score("run", "RUNNIN") > score("nni", "RUNNIN"));
+        assert!(score("nni", "RUNNIN") > score("runin", "RUNNIN"));
+        assert_eq!(score("runin", "RUNNIN"), Some(500));
+        assert_eq!(score("runing from", "Running from Evil"), Some(500));
+        assert_eq!(score("rnfe", "Running from Evil"), Some(290));
+        assert_eq!(score("xyz", "RUNNIN"), None);
+
+        let mut maps = MapTable::vanilla(None);
+        maps.set_title("MAP01", "Entryway");
+        let names: Vec<String> = ["D_RUNNIN", "D_STALKS", "D_ROMERO"].map(String::from).to_vec();
+        let index = SongIndex::new(&names, &maps, |name| titles::title(name, None).filter(|_| name != "D_STALKS"));
+        let hits = index.search("entryway", 5);
+        assert_eq!((hits[0].song.as_str(), hits[0].key.as_str()), ("D_RUNNIN", "Entryway"));
+        assert_eq!(index.search("stlks", 5)[0].song, "D_STALKS");
+        assert_eq!(index.search("romro", 1)[0].song, "D_ROMERO");
+        assert_eq!(index.search("running evil", 1)[0].song, "D_RUNNIN");
+        // A song that isn't known to be the original isn't found by the title
+        assert!(index.search("the healer stalks", 5).iter().all(|h| h.key != "The Healer Stalks"));
+        assert_eq!(index.complete("ma"), ["MAP01", "MAP02", "MAP18"]);
+    }
+}
@@ -0,0 +1,386 @@
+//! song.rs
+//!
+//! Everything between "here are some lump bytes" and "here is a `Timeline` to play":
+//! format detection, conversion and the summary we print for `info`/`list`.
+//!
+//! Kept separate from `main.rs` so the REPL and the non-interactive subcommands
+//! agree on what a song is and how it gets loaded.
+
+use anyhow::{bail, Result};
+use midly::Smf;
+use serde::Serialize;
+
+use crate::digital::{Source, Track};
+use crate::imf::{self, ImfPlayer, ImfRate};
+use crate::mapinfo::MapTable;
+use crate::midi::{build_timeline, Timeline};
+use crate::wad::{Lump, Namespace};
+use crate::mus::{self, MusDiagnostic, MusHeader, Strictness};
+use crate::mus2mid::{self, MusProfile};
+use crate::tracker::{Module, Player};
+use crate::tracker_formats;
+
+/// Lump name prefixes that mark music in DOOM-engine WADs.
+pub const MUSIC_PREFIXES: &[&str] = &["D_", "MUS_"];
+
+/// Namespaces songs are looked up in, so a sprite called D_SOMETHING isn't a song.
+pub const MUSIC_NAMESPACES: &[Namespace] = &[Namespace::Global, Namespace::Music];
+
+/// Whether a lump is music: a prefixed name outside any markers, or anything between
+/// MU_START and MU_END.
+pub fn is_music_lump(l: &Lump) -> bool {
+    match l.namespace {
+        Namespace::Music => true,
+        Namespace::Global => MUSIC_PREFIXES.iter().any(|p| l.name.starts_with(p)),
+        _ => false,
+    }
+}
+
+/// Resolve user input to a song name.
+///
+/// Accepts: RUNNIN, D_RUNNIN, E1M1, MUS_E1M1, MAP07, etc.
+/// Tries the name as given, then the song `maps` says that map plays, then each known
+/// prefix (the game's own first). A built-in song a DEHACKED patch renamed is found
+/// by its old name, bare or in full, as long as the new lump exists.
+pub fn find_song<'a>(names: &'a [String], maps: &MapTable, input: &str) -> Option<&'a str> {
+    let q = input.trim().to_ascii_uppercase();
+    if q.is_empty() { return None; }
+
+    let lookup = |name: &str| names.iter().find(|n| *n == name).map(|n| n.as_str());
+    let resolve = |name: &str| maps.renamed(name).and_then(lookup).or_else(|| lookup(name));
+    if let Some(hit) = resolve(&q) {
+        return Some(hit);
+    }
+    if let Some(hit) = maps.get(&q).and_then(|m| lookup(&m.lump)) {
+        return Some(hit);
+    }
+    maps.prefixes().into_iter().find_map(|p| resolve(&format!("{}{}", p, q)))
+}
+
+/// What kind of data a music lump holds, judged by its magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SongFormat {
+    Mus,
+    Midi,
+    Ogg,
+    Mp3,
+    Flac,
+    Wav,
+    Mod,
+    S3m,
+    Xm,
+    It,
+    Imf,
+    Unknown,
+}
+
+impl SongFormat {
+    /// Sniff the format from the first few bytes of a lump.
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(b"MUS\x1A") {
+            Self::Mus
+        } else if bytes.starts_with(b"MThd") {
+            Self::Midi
+        } else if bytes.starts_with(b"OggS") {
+            Self::Ogg
+        } else if bytes.starts_with(b"fLaC") {
+            Self::Flac
+        } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+            Self::Wav
+        } else if bytes.starts_with(b"Extended Module: ") {
+            Self::Xm
+        } else if bytes.starts_with(b"IMPM") {
+            Self::It
+        } else if bytes.get(44..48) == Some(b"SCRM") {
+            Self::S3m
+        } else if tracker_formats::mod_channels(bytes).is_some() {
+            Self::Mod
+        } else if imf::detect(bytes).is_some() {
+            // No magic; checked before MP3 because a type 1 length can look like a frame sync
+            Self::Imf
+        } else if bytes.starts_with(b"ID3") || matches!(bytes, [0xFF, b, ..] if b & 0xE0 == 0xE0) {
+            // An ID3v2 tag, or straight into an MPEG frame sync
+            Self::Mp3
+        } else {
+            Self::Unknown
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Mus => "MUS",
+            Self::Midi => "MIDI",
+            Self::Ogg => "OGG",
+            Self::Mp3 => "MP3",
+            Self::Flac => "FLAC",
+            Self::Wav => "WAV",
+            Self::Mod => "MOD",
+            Self::S3m => "S3M",
+            Self::Xm => "XM",
+            Self::It => "IT",
+            Self::Imf => "IMF",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// File extension for the format, for exports and decoder hints.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Mus => "mus",
+            Self::Midi => "mid",
+            Self::Ogg => "ogg",
+            Self::Mp3 => "mp3",
+            Self::Flac => "flac",
+            Self::Wav => "wav",
+            Self::Mod => "mod",
+            Self::S3m => "s3m",
+            Self::Xm => "xm",
+            Self::It => "it",
+            Self::Imf => "imf",
+            Self::Unknown => "lmp",
+        }
+    }
+
+    /// Recorded audio rather than a score: played through `digital`, not the synth.
+    pub fn is_digital(self) -> bool {
+        matches!(self, Self::Ogg | Self::Mp3 | Self::Flac | Self::Wav)
+    }
+
+    /// A tracker module: rendered by `tracker`, not the synth.
+    pub fn is_module(self) -> bool {
+        matches!(self, Self::Mod | Self::S3m | Self::Xm | Self::It)
+    }
+
+    /// Played as a stream of samples (see `digital::open`) rather than as a MIDI score.
+    pub fn is_streamed(self) -> bool {
+        self.is_digital() || self.is_module() || self == Self::Imf
+    }
+}
+
+/// How MUS lumps get turned into MIDI, and how fast IMF songs tick.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MusOptions {
+    /// Only applies to the native converter; engine profiles fail where their engine would
+    pub strictness: Strictness,
+    pub profile: MusProfile,
+    pub imf_rate: ImfRate,
+}
+
+/// A parsed song and whatever looked wrong in it (always empty for MIDI).
+pub struct LoadedSong {
+    pub smf: Smf<'static>,
+    pub diagnostics: Vec<MusDiagnostic>,
+}
+
+/// Parse a MUS or MIDI lump, keeping the MUS diagnostics for the caller.
+pub fn load_song(bytes: &[u8], opts: MusOptions) -> Result<LoadedSong> {
+    match SongFormat::detect(bytes) {
+        SongFormat::Mus if opts.profile == MusProfile::Native => {
+            let conv = mus::convert(bytes, opts.strictness)?;
+            Ok(LoadedSong { smf: conv.smf, diagnostics: conv.diagnostics })
+        }
+        SongFormat::Mus => {
+            let midi = mus2mid::convert(bytes, opts.profile)?;
+            Ok(LoadedSong { smf: Smf::parse(&midi)?.make_static(), diagnostics: Vec::new() })
+        }
+        SongFormat::Midi => Ok(LoadedSong { smf: Smf::parse(bytes)?.make_static(), diagnostics: Vec::new() }),
+        f if f.is_digital() => bail!("{} is recorded audio, not a score", f.name()),
+        f if f.is_module() => bail!("{} modules are played as they are, not through MIDI", f.name()),
+        SongFormat::Imf => bail!("IMF songs are OPL register writes, not a score"),
+        _ => bail!("unknown music format"),
+    }
+}
+
+/// Parse a MUS or MIDI lump into the SMF we schedule from, printing any MUS warnings.
+pub fn load_smf(bytes: &[u8], opts: MusOptions) -> Result<Smf<'static>> {
+    let song = load_song(bytes, opts)?;
+    for d in &song.diagnostics {
+        eprintln!("warning: MUS {}", d);
+    }
+    Ok(song.smf)
+}
+
+/// Parse a MUS or MIDI lump straight into a playable timeline.
+pub fn load_timeline(bytes: &[u8], opts: MusOptions) -> Result<Timeline> {
+    Ok(build_timeline(&load_smf(bytes, opts)?))
+}
+
+/// A lump as Standard MIDI File bytes, for `export`.
+///
+/// Engine profiles are written by `mus2mid::convert`: exactly as the ports' mus2mids
+/// would, and as modelled for DMX.
+pub fn to_midi(bytes: &[u8], opts: MusOptions) -> Result<Vec<u8>> {
+    if SongFormat::detect(bytes) == SongFormat::Mus && opts.profile != MusProfile::Native {
+        return mus2mid::convert(bytes, opts.profile);
+    }
+    let mut out = Vec::new();
+    load_smf(bytes, opts)?.write_std(&mut out)?;
+    Ok(out)
+}
+
+/// Summary of one song, as shown by `list` and `info`.
+///
+/// Timing fields are `None` when the lump couldn't be parsed; `error` then says why.
+#[derive(Debug, Clone, Serialize)]
+pub struct SongInfo {
+    pub name: String,
+    /// File the lump was read from
+    pub source: String,
+    /// Lump size in bytes
+    pub size: usize,
+    pub format: SongFormat,
+    pub duration_us: Option<u64>,
+    pub events: Option<usize>,
+    pub ppq: Option<f64>,
+    /// Initial tempo in microseconds per quarter note
+    pub us_per_qn: Option<f64>,
+    /// Official title, for IWAD songs known to be the original
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Title of the IWAD song this lump overrides, when it isn't known to be the original
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<String>,
+    /// Whether the lump differs from the IWAD's; unknown without an IWAD to compare to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced: Option<bool>,
+    /// Maps that play this song, from MAPINFO, SNDINFO or the lump name
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub maps: Vec<String>,
+    /// Channel counts and instrument list, for MUS lumps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mus_header: Option<MusHeader>,
+    /// Problems found in a MUS lump that was still converted
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<MusDiagnostic>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SongInfo {
+    /// Inspect a song lump. Never fails: parse errors are recorded in `error`.
+    pub fn inspect(name: &str, source: &str, bytes: &[u8], opts: MusOptions) -> Self {
+        let format = SongFormat::detect(bytes);
+        let mut info = SongInfo {
+            name: name.to_string(),
+            source: source.to_string(),
+            size: bytes.len(),
+            format,
+            duration_us: None,
+            events: None,
+            ppq: None,
+            us_per_qn: None,
+            title: None,
+            replaces: None,
+            replaced: None,
+            maps: Vec::new(),
+            mus_header: None,
+            diagnostics: Vec::new(),
+            error: None,
+        };
+        if format.is_digital() {
+            match Track::open(bytes.to_vec(), format) {
+                Ok(track) => info.duration_us = track.duration_us(),
+                Err(e) => info.error = Some(format!("{:#}", e)),
+            }
+            return info;
+        }
+        if format.is_module() {
+            match Module::load(bytes, format) {
+                Ok(module) => info.duration_us = Some(Player::new(module).length_us()),
+                Err(e) => info.error = Some(format!("{:#}", e)),
+            }
+            return info;
+        }
+        if format == SongFormat::Imf {
+            match ImfPlayer::new(bytes, opts.imf_rate) {
+                Ok(song) => info.duration_us = Some(song.length_us()),
+                Err(e) => info.error = Some(format!("{:#}", e)),
+            }
+            return info;
+        }
+        if format == SongFormat::Unknown {
+            return info;
+        }
+        // The header can be fine even when the score isn't
+        info.mus_header = MusHeader::parse(bytes).ok();
+        match load_song(bytes, opts) {
+            Ok(song) => {
+                let tl = build_timeline(&song.smf);
+                info.duration_us = Some(tl.last_t_us);
+                info.events = Some(tl.events.len());
+                info.ppq = Some(tl.ppq);
+                info.us_per_qn = Some(tl.initial_us_per_qn);
+                info.diagnostics = song.diagnostics;
+            }
+            Err(e) => info.error = Some(e.to_string()),
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::GameId;
+    use crate::wad::WadBuilder;
+
+    #[test]
+    fn detects_formats_and_summarizes_mus() {
+        // Header (16 bytes, no instruments) + play note 60, delay 70, release, end
+        let score = [0x90, 0x80 | 60, 100, 70, 0x00, 60, 0x60];
+        let mut mus = b"MUS\x1A".to_vec();
+        mus.extend_from_slice(&(score.len() as u16).to_le_bytes());
+        mus.extend_from_slice(&16u16.to_le_bytes());
+        mus.extend_from_slice(&[0; 8]);
+        mus.extend_from_slice(&score);
+
+        assert_eq!(SongFormat::detect(b"MThd...."), SongFormat::Midi);
+        assert_eq!(SongFormat::detect(b"OggS\0\x02"), SongFormat::Ogg);
+        assert_eq!(SongFormat::detect(b"ID3\x04"), SongFormat::Mp3);
+        assert_eq!(SongFormat::detect(&[0xFF, 0xFB, 0x90, 0x64]), SongFormat::Mp3);
+        assert_eq!(SongFormat::detect(b"RIFF\0\0\0\0AVI "), SongFormat::Unknown);
+        assert!(!SongFormat::detect(b"PNG").is_digital());
+
+        let mut s3m = vec![0; 48];
+        s3m[44..].copy_from_slice(b"SCRM");
+        let mut m_k = vec![0; 1084];
+        m_k[1080..].copy_from_slice(b"M.K.");
+        let mut xchn = m_k.clone();
+        xchn[1080..].copy_from_slice(b"12CH");
+        assert_eq!(SongFormat::detect(b"Extended Module: tune"), SongFormat::Xm);
+        assert_eq!(SongFormat::detect(b"IMPMtune"), SongFormat::It);
+        assert_eq!(SongFormat::detect(&s3m), SongFormat::S3m);
+        assert_eq!(SongFormat::detect(&m_k), SongFormat::Mod);
+        assert_eq!(tracker_formats::mod_channels(&xchn), Some(12));
+        assert!(SongFormat::Mod.is_streamed() && !SongFormat::Mod.is_digital());
+
+        let info = SongInfo::inspect("D_TEST", "test.wad", &mus, MusOptions::default());
+        assert_eq!(info.format, SongFormat::Mus);
+        assert_eq!(info.ppq, Some(140.0));
+        // 70 ticks at 140 PPQ and 1 s/qn
+        assert_eq!(info.duration_us, Some(500_000));
+        assert!(info.error.is_none());
+        assert!(info.diagnostics.is_empty());
+        assert_eq!(info.mus_header.map(|h| h.score_start), Some(16));
+
+        let bad = SongInfo::inspect("D_BAD", "test.wad", b"MUS\x1A", MusOptions::default());
+        assert!(bad.error.is_some());
+    }
+
+    #[test]
+    fn finds_songs_by_name_map_and_old_name() {
+        let mut b = WadBuilder::default();
+        b.lump("DEHACKED", b"[MUSIC]\nRUNNIN = SONG1\n");
+        let maps = MapTable::from_wad(&b.build(), Some(GameId::Doom2), &[]);
+        let names = ["D_RUNNIN", "D_SONG1", "D_STALKS"].map(String::from);
+
+        assert_eq!(find_song(&names, &maps, "stalks"), Some("D_STALKS"));
+        assert_eq!(find_song(&names, &maps, "MAP01"), Some("D_SONG1"));
+        // The rename applies whether the old name is given bare or in full
+        assert_eq!(find_song(&names, &maps, "runnin"), Some("D_SONG1"));
+        assert_eq!(find_song(&names, &maps, "D_RUNNIN"), Some("D_SONG1"));
+        assert_eq!(find_song(&names[..1], &maps, "D_RUNNIN"), Some("D_RUNNIN"));
+        assert_eq!(find_song(&names, &maps, "nosuch"), None);
+    }
+}
@@ -10,6 +10,10 @@
 //!  - Initialize a FluidLite synth with reverb/chorus parameters and a SoundFont
 //!  - Set up a CPAL audio stream that continuously pulls audio from the synth
 //!  - Provide a simple API (`Audio::new`, `Audio::start`, `Audio::play_timeline`) to the rest of the program
+//!  - Let the user pick the audio host, output device, sample rate and buffer size (`OutputOptions`)
+//!  - Optionally skip the sound card entirely and render into a null or WAV sink (`OutputTarget`)
+//!  - Mix a streamed digital track (`digital`) over the synth, for OGG/MP3/FLAC/WAV lumps
+//!    and tracker modules
 //!
 //! ### How it works
 //! - The synth sits behind an `Arc<Mutex<…>>` so that both the audio thread (pulling samples)
@@ -22,22 +26,386 @@
 //!
 //! The result: a fully working software MIDI player.
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{SampleFormat, Stream};
+use cpal::{
+    BufferSize, Device, FromSample, Host, Sample, SampleFormat, SampleRate, SizedSample, Stream,
+    SupportedBufferSize, SupportedStreamConfig,
+};
 use fluidlite::{Settings, Synth};
 use std::{
-    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
-    thread,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicI8, AtomicU8, AtomicU16, Ordering}},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 use std::sync::mpsc::{self, Sender};
 
-use crate::midi::{Timeline};
+use crate::digital::{self, Deck, FeedSlot, Source};
+use crate::midi::{Msg, Timed, Timeline};
+use crate::mus::DRUM_CHANNEL;
+use crate::wav::WavWriter;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AudioError {
+    #[error("unknown audio host {0:?} (available: {1})")]
+    HostNotFound(String, String),
+    #[error("audio host {0} is not available on this system")]
+    HostUnavailable(String),
+    #[error("host {0} has no default output device")]
+    NoDefaultDevice(String),
+    #[error("no output device matching {0:?} (try --list-devices)")]
+    DeviceNotFound(String),
+    #[error("device {device:?} does not support {rate} Hz output")]
+    UnsupportedSampleRate { device: String, rate: u32 },
+    #[error("device {device:?} does not support a buffer of {frames} frames (supported: {supported})")]
+    UnsupportedBufferSize { device: String, frames: u32, supported: String },
+}
+
+/// Where rendered audio ends up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum OutputTarget {
+    /// A CPAL output device (the normal case)
+    #[default]
+    Device,
+    /// Render in real time and throw the samples away; handy on headless machines
+    Null,
+    /// Render in real time into a 16-bit stereo WAV file
+    Wav(PathBuf),
+}
+
+impl FromStr for OutputTarget {
+    type Err = String;
+
+    /// Parses `device`, `null` or `wav:<path>`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("device") {
+            Ok(Self::Device)
+        } else if s.eq_ignore_ascii_case("null") {
+            Ok(Self::Null)
+        } else if let Some(path) = s.strip_prefix("wav:").filter(|p| !p.is_empty()) {
+            Ok(Self::Wav(PathBuf::from(path)))
+        } else {
+            Err(format!("expected device, null or wav:<path>, got {s:?}"))
+        }
+    }
+}
+
+impl OutputTarget {
+    /// The target for the `n`th of several songs: a WAV path gets `-n` before its
+    /// extension, so each song lands in its own file.
+    pub fn numbered(&self, n: usize) -> Self {
+        match self {
+            Self::Wav(path) => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let name = match path.extension() {
+                    Some(ext) => format!("{stem}-{n}.{}", ext.to_string_lossy()),
+                    None => format!("{stem}-{n}"),
+                };
+                Self::Wav(path.with_file_name(name))
+            }
+            other => other.clone(),
+        }
+    }
+}
+
+/// Sample rate used by the null/WAV sinks when none is requested.
+const OFFLINE_SAMPLE_RATE: u32 = 44_100;
+/// Block size (frames) used by the null/WAV sinks when none is requested.
+const OFFLINE_BLOCK_FRAMES: u32 = 512;
+
+/// User choices for the output side of the pipeline.
+/// Anything left as `None` falls back to what CPAL reports as the default.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Sound card, null sink or WAV file
+    pub target: OutputTarget,
+    /// Audio host name as reported by CPAL (e.g. "ALSA", "JACK"), case-insensitive
+    pub host: Option<String>,
+    /// Output device name; exact match first, then case-insensitive substring
+    pub device: Option<String>,
+    /// Requested sample rate in Hz
+    pub sample_rate: Option<u32>,
+    /// Requested buffer size in frames
+    pub buffer_size: Option<u32>,
+}
+
+/// One output device as seen by `list_devices`.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub is_default: bool,
+    /// Human-readable summary of the default output config, if the device reports one
+    pub default_config: Option<String>,
+    /// Supported (channels, format, min..max Hz) ranges
+    pub ranges: Vec<String>,
+}
+
+/// All output devices of a single host.
+#[derive(Debug, Clone)]
+pub struct HostInfo {
+    pub name: String,
+    pub devices: Vec<DeviceInfo>,
+}
+
+/// Enumerate every audio host CPAL was compiled with and the output devices each one exposes.
+///
+/// Hosts that are compiled in but unavailable at runtime (e.g. JACK without a running server)
+/// are listed with no devices rather than failing the whole listing.
+pub fn list_devices() -> Vec<HostInfo> {
+    let mut hosts = Vec::new();
+    for id in cpal::available_hosts() {
+        let name = id.name().to_string();
+        let Ok(host) = cpal::host_from_id(id) else {
+            hosts.push(HostInfo { name, devices: vec![] });
+            continue;
+        };
+        let default_name = host.default_output_device().and_then(|d| d.name().ok());
+        let mut devices = Vec::new();
+        if let Ok(devs) = host.output_devices() {
+            for dev in devs {
+                let dev_name = dev.name().unwrap_or_else(|_| "<unnamed>".to_string());
+                let default_config = dev.default_output_config().ok().map(|c| describe_config(&c));
+                let ranges = dev
+                    .supported_output_configs()
+                    .map(|it| {
+                        it.map(|r| format!(
+                            "{} ch {:?} {}..{} Hz",
+                            r.channels(), r.sample_format(), r.min_sample_rate().0, r.max_sample_rate().0
+                        ))
+                        .collect()
+                    })
+                    .unwrap_or_default();
+                devices.push(DeviceInfo {
+                    is_default: default_name.as_deref() == Some(dev_name.as_str()),
+                    name: dev_name,
+                    default_config,
+                    ranges,
+                });
+            }
+        }
+        hosts.push(HostInfo { name, devices });
+    }
+    hosts
+}
+
+fn describe_config(c: &SupportedStreamConfig) -> String {
+    format!("{} ch {:?} {} Hz", c.channels(), c.sample_format(), c.sample_rate().0)
+}
+
+/// Build a FluidLite synth with our effect settings and the given SoundFont loaded.
+fn new_synth(soundfont: &str) -> Result<Synth> {
+    // Build synth with default settings
+    let settings = Settings::new()?;
+    let fl = Synth::new(settings)?;
+    fl.sfload(soundfont, true).context("loading soundfont")?;
+
+    // Some basic effects: master gain, reverb, chorus
+    fl.set_gain(0.7);
+    fl.set_reverb_on(true);
+    fl.set_reverb_params(0.7, 0.2, 0.9, 0.5);
+    fl.set_chorus_on(true);
+    fl.set_chorus_params(3, 1.2, 0.30, 8.0, Default::default());
+    Ok(fl)
+}
+
+/// How long to keep rendering after the last event so releases and reverb can ring out.
+const RENDER_TAIL_US: u64 = 2_000_000;
+
+/// Render a timeline straight into a WAV file, as fast as the CPU allows.
+///
+/// Unlike `play_timeline` there is no wall clock involved: we synthesize exactly up to
+/// each event's sample position, dispatch it, and carry on. The output is therefore
+/// deterministic for a given SoundFont and sample rate. Speed and transposition are
+/// taken from `transport` once, at the start. Returns the number of frames written.
+pub fn render_timeline(
+    soundfont: &str,
+    tl: &Timeline,
+    path: &std::path::Path,
+    sample_rate: u32,
+    mix: &ChannelMix,
+    transport: &Transport,
+) -> Result<u64> {
+    let synth = new_synth(soundfont)?;
+    reset_synth(&synth, sample_rate as f32);
+    let mut wav = WavWriter::create(path, sample_rate, 2)?;
+
+    let speed = transport.speed() as f64;
+    let frame_at = |t_us: u64| (t_us as f64 / speed * sample_rate as f64 / 1_000_000.0) as u64;
+
+    let mut buf = vec![0.0f32; OFFLINE_BLOCK_FRAMES as usize * 2];
+    let mut frames_done: u64 = 0;
+    let mut render_to = |target: u64, wav: &mut WavWriter<_>| -> Result<()> {
+        while frames_done < target {
+            let n = (target - frames_done).min(OFFLINE_BLOCK_FRAMES as u64) as usize;
+            if let Err(e) = synth.write(&mut buf[..n * 2]) {
+                bail!("fluid write: {e}");
+            }
+            wav.write_samples(&buf[..n * 2])?;
+            frames_done += n as u64;
+        }
+        Ok(())
+    };
+
+    let mut voices = Voices::default();
+    for e in &tl.events {
+        render_to(frame_at(e.t_us), &mut wav)?;
+        dispatch(&synth, e.msg, mix, transport.transpose(), &mut voices);
+    }
+    let total = frame_at(tl.last_t_us) + RENDER_TAIL_US * sample_rate as u64 / 1_000_000;
+    render_to(total, &mut wav)?;
+    wav.finish()?;
+    Ok(total)
+}
+
+/// Tell the synth the output sample rate and put every channel into a known state.
+fn reset_synth(s: &Synth, sample_rate: f32) {
+    s.set_sample_rate(sample_rate);
+    for ch in 0..16u32 {
+        let _ = s.pitch_bend(ch, 8192); // center
+        let _ = s.cc(ch, 121, 0);       // Reset All Controllers
+        let _ = s.cc(ch, 120, 0);       // All Sound Off
+    }
+}
+
+/// Build a CPAL output stream of sample type `T` that pulls audio from the synth.
+///
+/// The synth renders into a stereo f32 scratch buffer, any digital track playing is
+/// mixed on top, and the result is converted to `T` and spread over the device's
+/// channel count by `write_frames`.
+fn build_stream<T>(dev: &Device, cfg: &cpal::StreamConfig, synth: Arc<Mutex<Synth>>, feed: FeedSlot) -> Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = cfg.channels as usize;
+    let mut scratch: Vec<f32> = Vec::new();
+    let stream = dev.build_output_stream(
+        cfg,
+        move |out: &mut [T], _| {
+            let frames = out.len() / channels.max(1);
+            // Only grows, so the callback stops allocating after the first few buffers
+            scratch.resize(frames * 2, 0.0);
+            if let Err(e) = synth.lock().unwrap().write(&mut scratch[..]) {
+                eprintln!("fluid write: {e}");
+            }
+            digital::mix_into(&feed, &mut scratch);
+            write_frames(&scratch, out, channels);
+        },
+        |e| eprintln!("stream error: {e}"),
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Convert interleaved stereo f32 into `out`, interleaved with `channels` channels.
+///
+/// - 1 channel: L and R are averaged
+/// - 2 channels: copied as-is
+/// - more: L/R go to the first two channels, the rest are left silent
+pub fn write_frames<T>(stereo: &[f32], out: &mut [T], channels: usize)
+where
+    T: Sample + FromSample<f32>,
+{
+    if channels == 0 { return; }
+    for (frame, lr) in out.chunks_mut(channels).zip(stereo.chunks(2)) {
+        let (l, r) = (lr[0], lr.get(1).copied().unwrap_or(lr[0]));
+        match frame {
+            [m] => *m = T::from_sample((l + r) * 0.5),
+            [a, b, rest @ ..] => {
+                *a = T::from_sample(l);
+                *b = T::from_sample(r);
+                for x in rest { *x = T::EQUILIBRIUM; }
+            }
+            [] => {}
+        }
+    }
+}
+
+/// Resolve the host named in `opts`, or the platform default.
+fn select_host(opts: &OutputOptions) -> Result<Host> {
+    let Some(want) = opts.host.as_deref() else {
+        return Ok(cpal::default_host());
+    };
+    let ids = cpal::available_hosts();
+    let Some(&id) = ids.iter().find(|id| id.name().eq_ignore_ascii_case(want)) else {
+        let names: Vec<_> = ids.iter().map(|id| id.name()).collect();
+        return Err(AudioError::HostNotFound(want.to_string(), names.join(", ")).into());
+    };
+    cpal::host_from_id(id).map_err(|_| AudioError::HostUnavailable(id.name().to_string()).into())
+}
+
+/// Resolve the device named in `opts` on `host`, or the host's default output device.
+fn select_device(host: &Host, opts: &OutputOptions) -> Result<Device> {
+    let Some(want) = opts.device.as_deref() else {
+        return host
+            .default_output_device()
+            .ok_or_else(|| AudioError::NoDefaultDevice(host.id().name().to_string()).into());
+    };
+    let devices: Vec<(String, Device)> = host
+        .output_devices()
+        .context("enumerating output devices")?
+        .filter_map(|d| d.name().ok().map(|n| (n, d)))
+        .collect();
+
+    let lower = want.to_lowercase();
+    let pos = devices.iter().position(|(n, _)| n == want)
+        .or_else(|| devices.iter().position(|(n, _)| n.to_lowercase().contains(&lower)));
+    match pos {
+        Some(p) => Ok(devices.into_iter().nth(p).map(|(_, d)| d).unwrap()),
+        None => Err(AudioError::DeviceNotFound(want.to_string()).into()),
+    }
+}
+
+/// Pick a stream config honoring the requested sample rate / buffer size.
+///
+/// Without a requested rate we take the device default. With one, we look for any supported
+/// range containing it, preferring the default config's sample format and channel count.
+fn select_config(dev: &Device, opts: &OutputOptions) -> Result<(SupportedStreamConfig, cpal::StreamConfig)> {
+    let dev_name = dev.name().unwrap_or_default();
+    let default = dev.default_output_config().context("default_output_config")?;
+
+    let supported = match opts.sample_rate {
+        None => default,
+        Some(rate) => {
+            let mut ranges: Vec<_> = dev
+                .supported_output_configs()
+                .context("supported_output_configs")?
+                .collect();
+            // Prefer ranges that match the default format, then the default channel count
+            ranges.sort_by_key(|r| (r.sample_format() != default.sample_format(), r.channels() != default.channels()));
+            ranges
+                .into_iter()
+                .find_map(|r| r.try_with_sample_rate(SampleRate(rate)))
+                .ok_or(AudioError::UnsupportedSampleRate { device: dev_name.clone(), rate })?
+        }
+    };
+
+    let mut stream_cfg = supported.config();
+    if let Some(frames) = opts.buffer_size {
+        match supported.buffer_size() {
+            SupportedBufferSize::Range { min, max } if !(*min..=*max).contains(&frames) => {
+                return Err(AudioError::UnsupportedBufferSize {
+                    device: dev_name,
+                    frames,
+                    supported: format!("{min}..={max}"),
+                }.into());
+            }
+            _ => stream_cfg.buffer_size = BufferSize::Fixed(frames),
+        }
+    }
+
+    Ok((supported, stream_cfg))
+}
 
 pub struct Player {
     paused: Arc<AtomicBool>,
     stop_tx: Sender<()>,
     finished: Arc<AtomicBool>,
+    mix: Arc<ChannelMix>,
+    transport: Arc<Transport>,
 }
 
 impl Player {
@@ -49,15 +417,381 @@ impl Player {
     }
     pub fn stop(&self)   { let _ = self.stop_tx.send(()); }
     pub fn is_finished(&self) -> bool { self.finished.load(Ordering::SeqCst) }
+    /// Live channel mute/solo/volume state shared with the scheduler.
+    pub fn mix(&self) -> &ChannelMix { &self.mix }
+    /// Live speed/transpose state shared with the scheduler.
+    pub fn transport(&self) -> &Transport { &self.transport }
+}
+
+/// Per-channel mute, solo and volume, shared between the UI and the scheduler thread.
+///
+/// Everything is atomic so the REPL can flip switches while a song plays without locking.
+/// Solo wins over "not muted": if any channel is soloed, only soloed (and unmuted) channels sound.
+pub struct ChannelMix {
+    muted: [AtomicBool; 16],
+    soloed: [AtomicBool; 16],
+    /// Volume in percent, 0..=100
+    volume: [AtomicU8; 16],
+}
+
+impl Default for ChannelMix {
+    fn default() -> Self {
+        Self {
+            muted: std::array::from_fn(|_| AtomicBool::new(false)),
+            soloed: std::array::from_fn(|_| AtomicBool::new(false)),
+            volume: std::array::from_fn(|_| AtomicU8::new(100)),
+        }
+    }
+}
+
+impl ChannelMix {
+    /// Flip mute on a channel and return the new state.
+    pub fn toggle_mute(&self, ch: u8) -> bool {
+        !self.muted[ch as usize & 15].fetch_xor(true, Ordering::SeqCst)
+    }
+    /// Flip solo on a channel and return the new state.
+    pub fn toggle_solo(&self, ch: u8) -> bool {
+        !self.soloed[ch as usize & 15].fetch_xor(true, Ordering::SeqCst)
+    }
+    /// Set channel volume in percent (clamped to 100).
+    pub fn set_volume(&self, ch: u8, pct: u8) {
+        self.volume[ch as usize & 15].store(pct.min(100), Ordering::SeqCst);
+    }
+    pub fn volume(&self, ch: u8) -> u8 { self.volume[ch as usize & 15].load(Ordering::SeqCst) }
+    pub fn is_muted(&self, ch: u8) -> bool { self.muted[ch as usize & 15].load(Ordering::SeqCst) }
+    pub fn is_soloed(&self, ch: u8) -> bool { self.soloed[ch as usize & 15].load(Ordering::SeqCst) }
+
+    /// Clear all mutes and solos and put every volume back to 100%.
+    pub fn reset(&self) {
+        for ch in 0..16 {
+            self.muted[ch].store(false, Ordering::SeqCst);
+            self.soloed[ch].store(false, Ordering::SeqCst);
+            self.volume[ch].store(100, Ordering::SeqCst);
+        }
+    }
+
+    /// True if notes on this channel should currently be heard.
+    pub fn audible(&self, ch: u8) -> bool {
+        let any_solo = self.soloed.iter().any(|s| s.load(Ordering::SeqCst));
+        !self.is_muted(ch) && (!any_solo || self.is_soloed(ch)) && self.volume(ch) > 0
+    }
+
+    /// Velocity after applying mute/solo/volume, or `None` if the note should be dropped.
+    pub fn scale_velocity(&self, ch: u8, vel: u8) -> Option<u8> {
+        if !self.audible(ch) { return None; }
+        let v = vel as u32 * self.volume(ch) as u32 / 100;
+        Some(v.clamp(1, 127) as u8)
+    }
+}
+
+/// Live speed and transposition, shared between the UI and the scheduler thread.
+///
+/// Speed scales the scheduler's logical clock, so the timeline never needs rebuilding.
+/// Transposition shifts every non-drum note; the drum channel is left alone because
+/// its keys select instruments, not pitches.
+pub struct Transport {
+    /// Playback speed in percent, `MIN_SPEED..=MAX_SPEED`
+    speed_pct: AtomicU16,
+    /// Semitones, `-MAX_TRANSPOSE..=MAX_TRANSPOSE`
+    transpose: AtomicI8,
+}
+
+impl Transport {
+    pub const MIN_SPEED: u16 = 25;
+    pub const MAX_SPEED: u16 = 400;
+    pub const MAX_TRANSPOSE: i8 = 36;
+
+    pub fn new(speed: f32, transpose: i8) -> Self {
+        let t = Self { speed_pct: AtomicU16::new(100), transpose: AtomicI8::new(0) };
+        t.set_speed(speed);
+        t.set_transpose(transpose);
+        t
+    }
+
+    /// Speed as a multiplier (1.0 = as written).
+    pub fn speed(&self) -> f32 { self.speed_pct.load(Ordering::SeqCst) as f32 / 100.0 }
+    /// Set speed as a multiplier, clamped to 0.25×–4×. NaN, infinite and non-positive
+    /// speeds are ignored.
+    pub fn set_speed(&self, speed: f32) {
+        if !speed.is_finite() || speed <= 0.0 {
+            return;
+        }
+        let pct = (speed * 100.0).round().clamp(Self::MIN_SPEED as f32, Self::MAX_SPEED as f32);
+        self.speed_pct.store(pct as u16, Ordering::SeqCst);
+    }
+    /// Nudge speed by `delta_pct` percentage points, returning the new multiplier.
+    pub fn nudge_speed(&self, delta_pct: i16) -> f32 {
+        let pct = (self.speed_pct.load(Ordering::SeqCst) as i16 + delta_pct)
+            .clamp(Self::MIN_SPEED as i16, Self::MAX_SPEED as i16);
+        self.speed_pct.store(pct as u16, Ordering::SeqCst);
+        self.speed()
+    }
+
+    pub fn transpose(&self) -> i8 { self.transpose.load(Ordering::SeqCst) }
+    pub fn set_transpose(&self, semitones: i8) {
+        let st = semitones.clamp(-Self::MAX_TRANSPOSE, Self::MAX_TRANSPOSE);
+        self.transpose.store(st, Ordering::SeqCst);
+    }
+    /// Shift transposition by `delta` semitones, returning the new value.
+    pub fn nudge_transpose(&self, delta: i8) -> i8 {
+        self.set_transpose(self.transpose().saturating_add(delta));
+        self.transpose()
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self { Self::new(1.0, 0) }
+}
+
+/// The MIDI operations the scheduler needs from a synthesizer.
+///
+/// Implemented for FluidLite's `Synth`; keeping the scheduler generic over it lets the
+/// tests drive the real dispatch logic with a recorder instead of an audio device.
+pub trait MidiSink {
+    fn note_on(&self, ch: u8, key: u8, vel: u8);
+    fn note_off(&self, ch: u8, key: u8);
+    fn program_change(&self, ch: u8, prog: u8);
+    fn cc(&self, ch: u8, cc: u8, val: u8);
+    fn pitch_bend(&self, ch: u8, bend: u16);
+    fn key_pressure(&self, ch: u8, key: u8, val: u8);
+    fn channel_pressure(&self, ch: u8, val: u8);
+}
+
+impl MidiSink for Synth {
+    fn note_on(&self, ch: u8, key: u8, vel: u8) { let _ = Synth::note_on(self, ch as u32, key as u32, vel as u32); }
+    fn note_off(&self, ch: u8, key: u8) { let _ = Synth::note_off(self, ch as u32, key as u32); }
+    fn program_change(&self, ch: u8, prog: u8) { let _ = Synth::program_change(self, ch as u32, prog as u32); }
+    fn cc(&self, ch: u8, cc: u8, val: u8) { let _ = Synth::cc(self, ch as u32, cc as u32, val as u32); }
+    fn pitch_bend(&self, ch: u8, bend: u16) { let _ = Synth::pitch_bend(self, ch as u32, bend as u32); }
+    fn key_pressure(&self, ch: u8, key: u8, val: u8) { let _ = Synth::key_pressure(self, ch as u32, key as u32, val as u32); }
+    fn channel_pressure(&self, ch: u8, val: u8) { let _ = Synth::channel_pressure(self, ch as u32, val as u32); }
+}
+
+/// What the scheduler remembers about notes it has sent.
+struct Voices {
+    /// For each (channel, written key), the key actually sent to the synth.
+    /// Lets note-offs find their note even if transposition changed while it was held.
+    sent: [[Option<u8>; 128]; 16],
+}
+
+impl Default for Voices {
+    fn default() -> Self { Self { sent: [[None; 128]; 16] } }
+}
+
+/// Send one timeline message to the synth, applying the channel mix and transposition.
+///
+/// Only note-ons are filtered: note-offs always go through so nothing hangs when a channel
+/// is muted mid-note, and controllers/programs keep tracking so unmuting sounds right.
+fn dispatch<S: MidiSink>(s: &S, msg: Msg, mix: &ChannelMix, transpose: i8, voices: &mut Voices) {
+    let shift = |ch: u8, key: u8| -> u8 {
+        if ch == DRUM_CHANNEL { key } else { (key as i16 + transpose as i16).clamp(0, 127) as u8 }
+    };
+    match msg {
+        Msg::NoteOn(ch, key, vel) => {
+            if let Some(v) = mix.scale_velocity(ch, vel) {
+                let k = shift(ch, key);
+                voices.sent[ch as usize & 15][key as usize & 127] = Some(k);
+                s.note_on(ch, k, v);
+            }
+        }
+        Msg::NoteOff(ch, key, _vel) => {
+            let k = voices.sent[ch as usize & 15][key as usize & 127].take().unwrap_or_else(|| shift(ch, key));
+            s.note_off(ch, k);
+        }
+        Msg::Program(ch, prog)          => s.program_change(ch, prog),
+        Msg::Control(ch, cc, val)       => s.cc(ch, cc, val),
+        Msg::PitchBend(ch, bend)        => s.pitch_bend(ch, bend),
+        Msg::AfterTouch(ch, key, vel)   => s.key_pressure(ch, shift(ch, key), vel),
+        Msg::ChannelAftertouch(ch, vel) => s.channel_pressure(ch, vel),
+        Msg::Tempo(_)                   => {} // already baked into timeline
+    }
+}
+
+/// Spawn the scheduler thread for `events` and return a handle to control it.
+pub fn spawn_scheduler<S>(
+    synth: Arc<Mutex<S>>,
+    events: Vec<Timed>,
+    mix: Arc<ChannelMix>,
+    transport: Arc<Transport>,
+) -> Player
+where
+    S: MidiSink + Send + 'static,
+{
+    let paused   = Arc::new(AtomicBool::new(false));
+    let finished = Arc::new(AtomicBool::new(false));
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+    let paused_t   = paused.clone();
+    let finished_t = finished.clone();
+    let (mix_t, transport_t) = (mix.clone(), transport.clone());
+
+    thread::spawn(move || {
+        // Logical time advances by wall-clock time scaled by the current speed,
+        // and not at all while paused. Accumulating it lets speed change mid-song.
+        let mut logical_us: f64 = 0.0;
+        let mut last_tick = Instant::now();
+        // Which channels were audible last time round, to silence them as soon as they're muted
+        let mut audible = [true; 16];
+        let mut voices = Voices::default();
+
+        let mut i = 0usize;
+
+        'play: loop {
+            // Stop request?
+            if stop_rx.try_recv().is_ok() { break 'play; }
+
+            // Handle pausing: don't advance logical time while paused
+            if paused_t.load(Ordering::SeqCst) {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                last_tick = Instant::now();
+                continue;
+            }
+
+            // Finished all events?
+            if i >= events.len() { break 'play; }
+
+            // Channels that just went silent: cut their sounding notes
+            for ch in 0..16u8 {
+                let now = mix_t.audible(ch);
+                if audible[ch as usize] && !now && let Ok(s) = synth.lock() {
+                    s.cc(ch, 123, 0); // All Notes Off
+                }
+                audible[ch as usize] = now;
+            }
+
+            // Advance "logical now" in microseconds
+            let speed = transport_t.speed() as f64;
+            let tick = Instant::now();
+            logical_us += tick.duration_since(last_tick).as_micros() as f64 * speed;
+            last_tick = tick;
+            // Clamp to u64 for our event timestamps (float -> int casts saturate)
+            let now_us = logical_us as u64;
+
+            let e = events[i];
+
+            if now_us >= e.t_us {
+                // Dispatch this event
+                if let Ok(s) = synth.lock() {
+                    dispatch(&*s, e.msg, &mix_t, transport_t.transpose(), &mut voices);
+                } else {
+                    // If the lock is poisoned, bail out gracefully instead of panicking
+                    break 'play;
+                }
+                i += 1;
+            } else {
+                // Wait until it's time for this event, without underflow,
+                // converting the logical gap back into wall-clock time
+                let wait_us = (e.t_us.saturating_sub(now_us) as f64 / speed) as u64;
+                // Sleep a small chunk; don’t try to sleep the whole microsecond span
+                let ms = std::cmp::min(5, wait_us / 1000);
+                if ms > 0 {
+                    std::thread::sleep(std::time::Duration::from_millis(ms));
+                } else {
+                    // If <1ms, yield a tiny bit to avoid a busy spin
+                    std::thread::sleep(std::time::Duration::from_micros(200));
+                }
+            }
+        }
+
+        finished_t.store(true, Ordering::SeqCst);
+    });
+
+    Player { paused, stop_tx, finished, mix, transport }
+}
+
+/// Pulls audio from the synth on a plain thread instead of a sound card callback.
+///
+/// The thread renders fixed-size blocks and sleeps so it never gets ahead of the wall clock,
+/// because the scheduler in `play_timeline` times events against real time.
+struct OfflineSink {
+    started: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    /// Returns the number of frames rendered
+    handle: Option<JoinHandle<Result<u64>>>,
+}
+
+impl OfflineSink {
+    fn spawn(
+        synth: Arc<Mutex<Synth>>,
+        feed: FeedSlot,
+        sample_rate: u32,
+        block_frames: u32,
+        mut wav: Option<WavWriter<BufWriter<File>>>,
+    ) -> Self {
+        let started = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (started_t, stop_t) = (started.clone(), stop.clone());
+
+        let handle = thread::spawn(move || -> Result<u64> {
+            // Wait for Audio::start so behaviour matches a CPAL stream, which is silent until played
+            while !started_t.load(Ordering::SeqCst) {
+                if stop_t.load(Ordering::SeqCst) { return Ok(0); }
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            let mut buf = vec![0.0f32; block_frames as usize * 2];
+            let t0 = Instant::now();
+            let mut frames_done: u64 = 0;
+            while !stop_t.load(Ordering::SeqCst) {
+                if let Err(e) = synth.lock().unwrap().write(&mut buf[..]) {
+                    eprintln!("fluid write: {e}");
+                }
+                digital::mix_into(&feed, &mut buf);
+                if let Some(w) = wav.as_mut() {
+                    w.write_samples(&buf)?;
+                }
+                frames_done += block_frames as u64;
+
+                // Pace to real time
+                let due = Duration::from_secs_f64(frames_done as f64 / sample_rate as f64);
+                if let Some(wait) = due.checked_sub(t0.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+            if let Some(w) = wav {
+                w.finish()?;
+            }
+            Ok(frames_done)
+        });
+
+        Self { started, stop, handle: Some(handle) }
+    }
+
+    /// Stop rendering, finish the WAV file if there is one, and return how many
+    /// frames were rendered.
+    fn join(&mut self) -> Result<u64> {
+        self.stop.store(true, Ordering::SeqCst);
+        match self.handle.take() {
+            Some(h) => h.join().map_err(|_| anyhow!("thread panicked"))?,
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for OfflineSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.join() {
+            eprintln!("output sink: {e:#}");
+        }
+    }
+}
+
+/// The thing actually consuming samples from the synth.
+enum Output {
+    Device(Stream),
+    Offline(OfflineSink),
 }
 
 /// The `Audio` struct bundles together everything needed for playback:
 /// - a shared FluidLite synth instance
-/// - the CPAL audio stream driving the sound card
+/// - the output pulling from it (CPAL stream, or a null/WAV sink)
+/// - a slot for a digital track streamed alongside it
 /// - the sample rate chosen by the audio device
 pub struct Audio {
     pub synth: Arc<Mutex<Synth>>,
-    pub stream: Stream,
+    /// Digital track mixed over the synth, if one is playing
+    feed: FeedSlot,
+    output: Output,
     pub sample_rate: f32,
 }
 
@@ -68,172 +802,76 @@ impl Audio {
     /// - initialize a FluidLite synth
     /// - load the given SoundFont (for instrument sounds)
     /// - set gain, reverb, chorus parameters
-    /// - open the default audio device with CPAL
+    /// - open the requested (or default) audio device with CPAL, or a null/WAV sink
     /// - configure the audio stream callback so CPAL pulls PCM from FluidLite
-    pub fn new(soundfont: &str) -> Result<Self> {
-        // Build synth with default settings
-        let settings = Settings::new()?;
-        let fl = Synth::new(settings)?;
-        fl.sfload(soundfont, true).context("loading soundfont")?;
-
-        // Some basic effects: master gain, reverb, chorus
-        fl.set_gain(0.7);
-        fl.set_reverb_on(true);
-        fl.set_reverb_params(0.7, 0.2, 0.9, 0.5);
-        fl.set_chorus_on(true);
-        fl.set_chorus_params(3, 1.2, 0.30, 8.0, Default::default());
+    pub fn new(soundfont: &str, opts: &OutputOptions) -> Result<Self> {
+        let synth = Arc::new(Mutex::new(new_synth(soundfont)?));
+        let feed = FeedSlot::default();
 
-        let synth = Arc::new(Mutex::new(fl));
+        if opts.target != OutputTarget::Device {
+            let rate = opts.sample_rate.unwrap_or(OFFLINE_SAMPLE_RATE);
+            let block = opts.buffer_size.unwrap_or(OFFLINE_BLOCK_FRAMES).max(1);
+            let wav = match &opts.target {
+                OutputTarget::Wav(path) => Some(WavWriter::create(path, rate, 2)?),
+                _ => None,
+            };
+            reset_synth(&synth.lock().unwrap(), rate as f32);
+            let sink = OfflineSink::spawn(synth.clone(), feed.clone(), rate, block, wav);
+            return Ok(Self { synth, feed, output: Output::Offline(sink), sample_rate: rate as f32 });
+        }
 
         // Set up CPAL audio output
-        let host = cpal::default_host();
-        let dev = host.default_output_device().context("no default output device")?;
-        let cfg = dev.default_output_config().context("default_output_config")?;
-        let sample_rate = cfg.sample_rate().0 as f32;
+        let host = select_host(opts)?;
+        let dev = select_device(&host, opts)?;
+        let (cfg, stream_cfg) = select_config(&dev, opts)?;
+        let sample_rate = stream_cfg.sample_rate.0 as f32;
 
         // Inform the synth of the system sample rate and reset controllers
-        {
-            let s = synth.lock().unwrap();
-            s.set_sample_rate(sample_rate);
-            for ch in 0..16u32 {
-                let _ = s.pitch_bend(ch, 8192); // center
-                let _ = s.cc(ch, 121, 0);       // Reset All Controllers
-                let _ = s.cc(ch, 120, 0);       // All Sound Off
-            }
-        }
-
-        // CPAL error handler for the stream
-        let err_fn = |e| eprintln!("stream error: {e}");
-        let fmt = cfg.sample_format();
-        let stream_cfg = cfg.config();
+        reset_synth(&synth.lock().unwrap(), sample_rate);
 
         // Build an output stream. CPAL asks us to fill `out` with samples each frame.
-        // We simply forward that request to FluidLite's `write` method.
-        let stream = match fmt {
-            SampleFormat::I16 => dev.build_output_stream(
-                &stream_cfg,
-                {
-                    let synth = synth.clone();
-                    move |out: &mut [i16], _| {
-                        if let Err(e) = synth.lock().unwrap().write(out) {
-                            eprintln!("fluid write i16: {e}");
-                        }
-                    }
-                },
-                err_fn,
-                None,
-            )?,
-            _ => dev.build_output_stream(
-                &stream_cfg,
-                {
-                    let synth = synth.clone();
-                    move |out: &mut [f32], _| {
-                        if let Err(e) = synth.lock().unwrap().write(out) {
-                            eprintln!("fluid write f32: {e}");
-                        }
-                    }
-                },
-                err_fn,
-                None,
-            )?,
+        // FluidLite always renders interleaved stereo f32; `build_stream` converts that
+        // into whatever sample type and channel count the device negotiated.
+        let stream = match cfg.sample_format() {
+            SampleFormat::I8  => build_stream::<i8>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::I16 => build_stream::<i16>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::I32 => build_stream::<i32>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::I64 => build_stream::<i64>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::U8  => build_stream::<u8>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::U16 => build_stream::<u16>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::U32 => build_stream::<u32>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::U64 => build_stream::<u64>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::F32 => build_stream::<f32>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            SampleFormat::F64 => build_stream::<f64>(&dev, &stream_cfg, synth.clone(), feed.clone())?,
+            other => bail!("unsupported sample format {other:?}"),
         };
 
-        Ok(Self { synth, stream, sample_rate })
+        Ok(Self { synth, feed, output: Output::Device(stream), sample_rate })
     }
 
     /// Spawn a background thread that walks the `Timeline` of events
     /// and sends them to the synth at the correct wall-clock time.
     ///
     /// This acts as the "conductor", while CPAL is the "orchestra".
-    pub fn play_timeline(&self, tl: &Timeline) -> Player {
-        let paused   = Arc::new(AtomicBool::new(false));
-        let finished = Arc::new(AtomicBool::new(false));
-        let (stop_tx, stop_rx) = mpsc::channel::<()>();
-
-        let paused_t   = paused.clone();
-        let finished_t = finished.clone();
-        let synth = self.synth.clone();
-        let events = tl.events.clone(); // assuming Msg: Copy; else clone as needed
-
-        thread::spawn(move || {
-            let start = std::time::Instant::now();
-            let mut paused_since: Option<std::time::Instant> = None;
-            let mut paused_total_us: u128 = 0; // total paused micros accumulated
-
-            let mut i = 0usize;
-
-            'play: loop {
-                // Stop request?
-                if stop_rx.try_recv().is_ok() { break 'play; }
-
-                // Handle pausing: don't advance logical time while paused
-                if paused_t.load(std::sync::atomic::Ordering::SeqCst) {
-                    if paused_since.is_none() {
-                        paused_since = Some(std::time::Instant::now());
-                    }
-                    std::thread::sleep(std::time::Duration::from_millis(10));
-                    continue;
-                } else if let Some(since) = paused_since.take() {
-                    // just resumed: accumulate paused span
-                    let span = since.elapsed().as_micros();
-                    paused_total_us = paused_total_us.saturating_add(span);
-                }
-
-                // Finished all events?
-                if i >= events.len() { break 'play; }
-
-                // Compute "logical now" in microseconds (wall-clock elapsed minus paused time)
-                let wall_us = start.elapsed().as_micros();
-                let now_us_u128 = wall_us.saturating_sub(paused_total_us);
-                // Clamp to u64 for our event timestamps
-                let now_us = if now_us_u128 > u64::MAX as u128 { u64::MAX } else { now_us_u128 as u64 };
-
-                let e = events[i];
-
-                if now_us >= e.t_us {
-                    // Dispatch this event
-                    if let Ok(s) = synth.lock() {
-                        use crate::midi::Msg::*;
-                        match e.msg {
-                            NoteOn(ch, key, vel)   => { let _ = s.note_on(ch as u32, key as u32, vel as u32); }
-                            NoteOff(ch, key, _vel) => { let _ = s.note_off(ch as u32, key as u32); }
-                            Program(ch, prog)      => { let _ = s.program_change(ch as u32, prog as u32); }
-                            Control(ch, cc, val)   => { let _ = s.cc(ch as u32, cc as u32, val as u32); }
-                            PitchBend(ch, bend)  => { let _ = s.pitch_bend(ch as u32, bend as u32); }
-                            AfterTouch(ch, key, vel) => { let _ = s.key_pressure(ch as u32, key as u32, vel as u32); }
-                            ChannelAftertouch(ch, vel) => { let _ = s.channel_pressure(ch as u32, vel as u32); }
-                            Tempo(_)               => {} // already baked into timeline
-                        }
-                    } else {
-                        // If the lock is poisoned, bail out gracefully instead of panicking
-                        break 'play;
-                    }
-                    i += 1;
-                } else {
-                    // Wait until it's time for this event, without underflow
-                    let wait_us = e.t_us.saturating_sub(now_us); // safe u64 subtraction
-                    // Sleep a small chunk; don’t try to sleep the whole microsecond span
-                    let ms = std::cmp::min(5, wait_us / 1000);
-                    if ms > 0 {
-                        std::thread::sleep(std::time::Duration::from_millis(ms));
-                    } else {
-                        // If <1ms, yield a tiny bit to avoid a busy spin
-                        std::thread::sleep(std::time::Duration::from_micros(200));
-                    }
-                }
-            }
-
-            finished_t.store(true, std::sync::atomic::Ordering::SeqCst);
-        });
+    /// `mix` and `transport` are consulted for every event, so mute/solo/volume,
+    /// speed and transpose changes apply immediately.
+    pub fn play_timeline(&self, tl: &Timeline, mix: Arc<ChannelMix>, transport: Arc<Transport>) -> Player {
+        spawn_scheduler(self.synth.clone(), tl.events.clone(), mix, transport)
+    }
 
-        Player { paused, stop_tx, finished }
+    /// Stream a digital track to the output, replacing any that's playing.
+    pub fn play_digital(&self, track: Box<dyn Source>) -> Deck {
+        digital::start(track, self.sample_rate as u32, &self.feed)
     }
 
     /// Start the audio stream (begins pushing audio to the system device).
     ///
     /// Must be called before playback can be heard.
     pub fn start(&self) -> anyhow::Result<()> {
-        self.stream.play()?;
+        match &self.output {
+            Output::Device(stream) => stream.play()?,
+            Output::Offline(sink) => sink.started.store(true, Ordering::SeqCst),
+        }
         Ok(())
     }
 }
@@ -348,4 +986,206 @@ mod tests {
         assert_eq!(synth.note_off_calls.lock().unwrap().len(), 1);
         assert!(finished.load(Ordering::SeqCst));
     }
+
+    /// Records every MIDI call as a string so tests can assert on exact dispatch.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl MidiSink for Recorder {
+        fn note_on(&self, ch: u8, key: u8, vel: u8) { self.0.lock().unwrap().push(format!("on {ch} {key} {vel}")); }
+        fn note_off(&self, ch: u8, key: u8) { self.0.lock().unwrap().push(format!("off {ch} {key}")); }
+        fn program_change(&self, ch: u8, prog: u8) { self.0.lock().unwrap().push(format!("prog {ch} {prog}")); }
+        fn cc(&self, ch: u8, cc: u8, val: u8) { self.0.lock().unwrap().push(format!("cc {ch} {cc} {val}")); }
+        fn pitch_bend(&self, ch: u8, bend: u16) { self.0.lock().unwrap().push(format!("bend {ch} {bend}")); }
+        fn key_pressure(&self, _: u8, _: u8, _: u8) {}
+        fn channel_pressure(&self, _: u8, _: u8) {}
+    }
+
+    #[test]
+    fn muted_channels_drop_notes_but_keep_controllers() {
+        let mix = ChannelMix::default();
+        mix.toggle_mute(1);
+        mix.set_volume(2, 50);
+        let rec = Recorder::default();
+        let mut voices = Voices::default();
+
+        for msg in [
+            Msg::NoteOn(1, 60, 100),
+            Msg::Control(1, 7, 90),
+            Msg::NoteOff(1, 60, 0),
+            Msg::NoteOn(2, 64, 100),
+            Msg::Program(1, 5),
+        ] {
+            dispatch(&rec, msg, &mix, 0, &mut voices);
+        }
+        assert_eq!(
+            *rec.0.lock().unwrap(),
+            vec!["cc 1 7 90", "off 1 60", "on 2 64 50", "prog 1 5"]
+        );
+    }
+
+    #[test]
+    fn solo_silences_everything_else() {
+        let mix = ChannelMix::default();
+        assert!(mix.toggle_solo(9));
+        assert!(mix.audible(9));
+        assert!(!mix.audible(0));
+        // Muting a soloed channel still silences it
+        mix.toggle_mute(9);
+        assert!(!mix.audible(9));
+        mix.reset();
+        assert!(mix.audible(0) && mix.audible(9));
+    }
+
+    #[test]
+    fn scheduler_cuts_notes_when_channel_is_muted() {
+        let rec = Arc::new(Mutex::new(Recorder::default()));
+        let events = vec![
+            Timed { t_us: 0, msg: Msg::NoteOn(3, 60, 100) },
+            Timed { t_us: 60_000, msg: Msg::NoteOn(3, 62, 100) },
+        ];
+        let player = spawn_scheduler(rec.clone(), events, Arc::new(ChannelMix::default()), Arc::new(Transport::default()));
+        std::thread::sleep(Duration::from_millis(20));
+        player.mix().toggle_mute(3);
+        while !player.is_finished() { std::thread::sleep(Duration::from_millis(5)); }
+
+        let calls = rec.lock().unwrap().0.lock().unwrap().clone();
+        assert_eq!(calls, vec!["on 3 60 100", "cc 3 123 0"]);
+    }
+
+    #[test]
+    fn transpose_skips_drums_and_note_offs_follow_their_note() {
+        let mix = ChannelMix::default();
+        let rec = Recorder::default();
+        let mut voices = Voices::default();
+
+        dispatch(&rec, Msg::NoteOn(0, 60, 100), &mix, 2, &mut voices);
+        dispatch(&rec, Msg::NoteOn(DRUM_CHANNEL, 36, 100), &mix, 2, &mut voices);
+        // Transpose changes while the notes are held
+        dispatch(&rec, Msg::NoteOff(0, 60, 0), &mix, -5, &mut voices);
+        dispatch(&rec, Msg::NoteOff(DRUM_CHANNEL, 36, 0), &mix, -5, &mut voices);
+        dispatch(&rec, Msg::NoteOn(1, 126, 100), &mix, 12, &mut voices);
+
+        assert_eq!(
+            *rec.0.lock().unwrap(),
+            vec!["on 0 62 100", "on 9 36 100", "off 0 62", "off 9 36", "on 1 127 100"]
+        );
+    }
+
+    #[test]
+    fn transport_clamps_speed_and_transpose() {
+        let t = Transport::new(10.0, 100);
+        assert_eq!(t.speed(), 4.0);
+        assert_eq!(t.transpose(), Transport::MAX_TRANSPOSE);
+        t.set_speed(0.1);
+        assert_eq!(t.speed(), 0.25);
+        t.set_speed(f32::NAN);
+        t.set_speed(-2.0);
+        assert_eq!(t.speed(), 0.25);
+        assert_eq!(t.nudge_speed(25), 0.5);
+        assert_eq!(t.nudge_transpose(-100), -Transport::MAX_TRANSPOSE);
+    }
+
+    #[test]
+    fn scheduler_speed_scales_the_clock() {
+        let rec = Arc::new(Mutex::new(Recorder::default()));
+        let events = vec![Timed { t_us: 200_000, msg: Msg::NoteOn(0, 60, 100) }];
+        let started = Instant::now();
+        let player = spawn_scheduler(rec.clone(), events, Arc::new(ChannelMix::default()), Arc::new(Transport::new(4.0, 0)));
+        while !player.is_finished() { std::thread::sleep(Duration::from_millis(2)); }
+        // 200 ms of music at 4x should take ~50 ms
+        assert!(started.elapsed() < Duration::from_millis(150));
+        assert_eq!(rec.lock().unwrap().0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn output_target_parses() {
+        assert_eq!("null".parse::<OutputTarget>().unwrap(), OutputTarget::Null);
+        assert_eq!("Device".parse::<OutputTarget>().unwrap(), OutputTarget::Device);
+        assert_eq!(
+            "wav:out/song.wav".parse::<OutputTarget>().unwrap(),
+            OutputTarget::Wav(PathBuf::from("out/song.wav"))
+        );
+        assert!("wav:".parse::<OutputTarget>().is_err());
+        assert!("speakers".parse::<OutputTarget>().is_err());
+    }
+
+    /// Play `tl` through an offline sink until the scheduler finishes, returning the
+    /// frames rendered.
+    fn render_offline(tl: &Timeline, wav: Option<WavWriter<BufWriter<File>>>) -> u64 {
+        let synth = Arc::new(Mutex::new(Synth::new(Settings::new().unwrap()).unwrap()));
+        reset_synth(&synth.lock().unwrap(), 8000.0);
+        let mut sink = OfflineSink::spawn(synth.clone(), FeedSlot::default(), 8000, 80, wav);
+        sink.started.store(true, Ordering::SeqCst);
+        let player = spawn_scheduler(
+            synth,
+            tl.events.clone(),
+            Arc::new(ChannelMix::default()),
+            Arc::new(Transport::new(1.0, 0)),
+        );
+        let t0 = Instant::now();
+        while !player.is_finished() {
+            assert!(t0.elapsed() < Duration::from_secs(5), "scheduler never finished");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        sink.join().unwrap()
+    }
+
+    #[test]
+    fn timelines_render_to_null_and_wav_sinks() {
+        let tl = Timeline {
+            events: vec![
+                Timed { t_us: 0, msg: Msg::NoteOn(0, 60, 100) },
+                Timed { t_us: 50_000, msg: Msg::NoteOff(0, 60, 0) },
+            ],
+            last_t_us: 50_000,
+            ppq: 140.0,
+            initial_us_per_qn: 500_000.0,
+        };
+        // 50 ms at 8 kHz, in whole 80-frame blocks
+        let frames = render_offline(&tl, None);
+        assert!(frames >= 400 && frames.is_multiple_of(80), "{frames}");
+
+        let path = std::env::temp_dir().join(format!("synth-offline-{}.wav", std::process::id()));
+        let frames = render_offline(&tl, Some(WavWriter::create(&path, 8000, 2).unwrap()));
+        let wav = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(frames >= 400, "{frames}");
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()) as u64, frames * 4);
+        assert_eq!(wav.len() as u64, 44 + frames * 4);
+    }
+
+    #[test]
+    fn wav_targets_are_numbered_per_song() {
+        let target = OutputTarget::Wav(PathBuf::from("out/song.wav"));
+        assert_eq!(target.numbered(2), OutputTarget::Wav(PathBuf::from("out/song-2.wav")));
+        assert_eq!(OutputTarget::Wav(PathBuf::from("song")).numbered(1), OutputTarget::Wav(PathBuf::from("song-1")));
+        assert_eq!(OutputTarget::Null.numbered(3), OutputTarget::Null);
+    }
+
+    #[test]
+    fn write_frames_handles_channel_counts_and_formats() {
+        let stereo = [1.0f32, -1.0, 0.5, 0.5];
+
+        // Mono: average of L and R
+        let mut mono = [0.0f32; 2];
+        write_frames(&stereo, &mut mono, 1);
+        assert_eq!(mono, [0.0, 0.5]);
+
+        // Stereo i16: straight conversion
+        let mut st = [0i16; 4];
+        write_frames(&stereo, &mut st, 2);
+        assert_eq!(st[0], i16::MAX);
+        assert_eq!(st[1], i16::MIN);
+
+        // Unsigned formats are centered on their midpoint
+        let mut u = [0u16; 4];
+        write_frames(&[0.0, 0.0, 0.0, 0.0], &mut u, 2);
+        assert!(u.iter().all(|&x| x == 32768));
+
+        // 4 channels: extra channels are silent
+        let mut quad = [9.0f64; 8];
+        write_frames(&stereo, &mut quad, 4);
+        assert_eq!(quad, [1.0, -1.0, 0.0, 0.0, 0.5, 0.5, 0.0, 0.0]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,203 @@
+//! titles.rs
+//!
+//! Official titles of the IWAD songs, and whether a WAD's copy of one is still the
+//! original.
+//!
+//! Titles are keyed by lump name, which is all an IWAD song has to go by. A PWAD
+//! that replaces D_RUNNIN keeps the name but not the music, so each song's CRC-32 is
+//! checked against the IWAD's copy (`--iwad`, or the opened file itself when that is
+//! an IWAD) and songs that differ are flagged as replaced. Without an IWAD to check
+//! against, a PWAD's song is only said to replace the IWAD song of its name, never
+//! given its title.
+//!
+//! Titles are tabled for Doom's first two episodes and for Doom II's level songs.
+//! Which games have none is spelled out in `table`: either their music has no title
+//! list we could check, or they reuse Doom's lump names for other music. There are no
+//! built-in checksums either, so telling a replacement from an unchanged copy needs
+//! an IWAD to compare to.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::game::GameId;
+use crate::song::MUSIC_NAMESPACES;
+use crate::wad::Wad;
+
+/// Bobby Prince's titles for Doom's first two episodes.
+const DOOM_TITLES: &[(&str, &str)] = &[
+    ("D_E1M1", "At Doom's Gate"),
+    ("D_E1M2", "The Imp's Song"),
+    ("D_E1M3", "Dark Halls"),
+    ("D_E1M4", "Kitchen Ace (And Taking Names)"),
+    ("D_E1M5", "Suspense"),
+    ("D_E1M6", "On the Hunt"),
+    ("D_E1M7", "Demons on the Prey"),
+    ("D_E1M8", "Sign of Evil"),
+    ("D_E1M9", "Hiding the Secrets"),
+    ("D_E2M1", "I Sawed the Demons"),
+    ("D_E2M2", "The Demons from Adrian's Pen"),
+    ("D_E2M3", "Intermission from DOOM"),
+    ("D_E2M4", "They're Going to Get You"),
+    ("D_E2M5", "Demons on the Prey"),
+    ("D_E2M6", "Sinister"),
+    ("D_E2M7", "Waltz of the Demons"),
+    ("D_E2M8", "Nobody Told Me About id"),
+    ("D_E2M9", "Untitled"),
+];
+
+/// Bobby Prince's titles for Doom II.
+const DOOM2_TITLES: &[(&str, &str)] = &[
+    ("D_RUNNIN", "Running from Evil"),
+    ("D_STALKS", "The Healer Stalks"),
+    ("D_COUNTD", "Countdown to Death"),
+    ("D_BETWEE", "Between Levels"),
+    ("D_DOOM", "DOOM"),
+    ("D_THE_DA", "In the Dark"),
+    ("D_SHAWN", "Shawn's Got the Shotgun"),
+    ("D_DDTBLU", "The Dave D. Taylor Blues"),
+    ("D_IN_CIT", "Into Sandy's City"),
+    ("D_DEAD", "The Demon's Dead"),
+    ("D_STLKS2", "The Healer Stalks"),
+    ("D_THEDA2", "In the Dark"),
+    ("D_DOOM2", "DOOM"),
+    ("D_DDTBL2", "The Dave D. Taylor Blues"),
+    ("D_RUNNI2", "Running from Evil"),
+    ("D_DEAD2", "The Demon's Dead"),
+    ("D_STLKS3", "The Healer Stalks"),
+    ("D_ROMERO", "Waiting for Romero to Play"),
+    ("D_SHAWN2", "Shawn's Got the Shotgun"),
+    ("D_MESSAG", "Message for the Archvile"),
+    ("D_COUNT2", "Countdown to Death"),
+    ("D_DDTBL3", "The Dave D. Taylor Blues"),
+    ("D_AMPIE", "Bye Bye American Pie"),
+    ("D_THEDA3", "In the Dark"),
+    ("D_ADRIAN", "Adrian's Asleep"),
+    ("D_MESSG2", "Message for the Archvile"),
+    ("D_ROMER2", "Waiting for Romero to Play"),
+    ("D_TENSE", "Getting Too Tense"),
+    ("D_SHAWN3", "Shawn's Got the Shotgun"),
+    ("D_OPENIN", "Opening to Hell"),
+    ("D_EVIL", "Evil Incarnate"),
+    ("D_ULTIMA", "The Ultimate Challenge"),
+    ("D_READ_M", "Read Me"),
+];
+
+/// The titles tabled for `game`.
+fn table(game: GameId) -> &'static [(&'static str, &'static str)] {
+    match game {
+        // Episode 3 and the title, intermission and ending songs aren't tabled
+        GameId::DoomShareware | GameId::DoomRegistered | GameId::UltimateDoom => DOOM_TITLES,
+        // The level songs only; D_DM2TTL and D_DM2INT aren't tabled
+        GameId::Doom2 => DOOM2_TITLES,
+        // Their own soundtracks, with no title list tabled yet
+        GameId::Tnt | GameId::Heretic | GameId::Hexen | GameId::Strife => &[],
+        // Doom's lump names, other music
+        GameId::Plutonia | GameId::Chex | GameId::Freedoom1 | GameId::Freedoom2 => &[],
+    }
+}
+
+/// The official title of `game`'s song called `lump`; both Doom's and Doom II's
+/// titles are tried when the game isn't known.
+pub fn title(lump: &str, game: Option<GameId>) -> Option<&'static str> {
+    let tables = match game {
+        Some(g) => [table(g), &[]],
+        None => [DOOM_TITLES, DOOM2_TITLES],
+    };
+    tables.into_iter().flatten().find(|(l, _)| *l == lump).map(|&(_, t)| t)
+}
+
+/// How a song stands to the IWAD song of the same name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Provenance<'a> {
+    /// Official title, when the song is known to be the IWAD's own
+    pub title: Option<&'a str>,
+    /// Title of the IWAD song it overrides, when it isn't known to be that song
+    pub replaces: Option<&'a str>,
+    /// Whether it differs from the IWAD's copy; unknown without one to compare to
+    pub replaced: Option<bool>,
+}
+
+impl Provenance<'static> {
+    /// A song only gets the IWAD song's title when it is known to be that song. A
+    /// PWAD's lump of the same name overrides it in the game, so without an IWAD to
+    /// compare to it is said to replace it.
+    pub fn of(name: &str, bytes: &[u8], game: Option<GameId>, originals: Option<&Originals>) -> Self {
+        let replaced = originals.and_then(|o| o.replaced(name, bytes));
+        let title = title(name, game);
+        match replaced {
+            Some(false) => Provenance { title, replaces: None, replaced },
+            _ => Provenance { title: None, replaces: title, replaced },
+        }
+    }
+}
+
+/// As the song lists show it: `  "Title"` or `  replaces "Title"`, then `  [replaced]`.
+impl fmt::Display for Provenance<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.title, self.replaces) {
+            (Some(t), _) => write!(f, "  \"{t}\"")?,
+            (None, Some(t)) => write!(f, "  replaces \"{t}\"")?,
+            (None, None) => {}
+        }
+        if self.replaced == Some(true) {
+            f.write_str("  [replaced]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checksums of an IWAD's songs, to tell them from replacements.
+#[derive(Debug, Default)]
+pub struct Originals {
+    crcs: HashMap<String, u32>,
+}
+
+impl Originals {
+    /// The songs among `names` that `iwad` has.
+    pub fn from_iwad(iwad: &Wad, names: &[String]) -> Self {
+        let crcs = names
+            .iter()
+            .filter_map(|n| Some((n.clone(), crc32fast::hash(iwad.read_in(n, MUSIC_NAMESPACES).ok()?))))
+            .collect();
+        Originals { crcs }
+    }
+
+    /// Whether `bytes` differ from the IWAD's song `name`; `None` if the IWAD has no
+    /// such song.
+    pub fn replaced(&self, name: &str, bytes: &[u8]) -> Option<bool> {
+        self.crcs.get(name).map(|&crc| crc != crc32fast::hash(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::WadBuilder;
+
+    #[test]
+    fn titles_songs_and_spots_replacements() {
+        assert_eq!(title("D_RUNNIN", None), Some("Running from Evil"));
+        assert_eq!(title("D_E1M1", Some(GameId::UltimateDoom)), Some("At Doom's Gate"));
+        assert_eq!(title("D_RUNNIN", Some(GameId::Doom2)), Some("Running from Evil"));
+        assert_eq!(title("D_RUNNIN", Some(GameId::Tnt)), None);
+        assert_eq!(title("D_E1M1", Some(GameId::Doom2)), None);
+        assert_eq!(title("D_MYSONG", None), None);
+
+        let mut b = WadBuilder::default();
+        b.lump("D_RUNNIN", b"MUS\x1a original");
+        b.lump("D_STALKS", b"MUS\x1a original");
+        let iwad = b.build();
+        let names = ["D_RUNNIN", "D_STALKS", "D_NEW"].map(String::from);
+        let originals = Originals::from_iwad(&iwad, &names);
+        assert_eq!(originals.replaced("D_RUNNIN", b"MUS\x1a original"), Some(false));
+        assert_eq!(originals.replaced("D_STALKS", b"MUS\x1a remix"), Some(true));
+        assert_eq!(originals.replaced("D_NEW", b"MUS\x1a"), None);
+
+        let of = |name, bytes: &[u8], originals| Provenance::of(name, bytes, Some(GameId::Doom2), originals);
+        let original = of("D_RUNNIN", b"MUS\x1a original", Some(&originals));
+        assert_eq!(original.to_string(), "  \"Running from Evil\"");
+        assert_eq!(of("D_STALKS", b"MUS\x1a remix", Some(&originals)).to_string(), "  replaces \"The Healer Stalks\"  [replaced]");
+        assert_eq!(of("D_RUNNIN", b"MUS\x1a original", None).to_string(), "  replaces \"Running from Evil\"");
+        assert_eq!(of("D_NEW", b"MUS\x1a", None), Provenance::default());
+    }
+}
@@ -0,0 +1,862 @@
+//! tracker.rs
+//!
+//! A small module player for MOD, S3M, XM and IT music lumps.
+//!
+//! The loaders in `tracker_formats.rs` turn each format into the `Module` below, with
+//! notes numbered the same way (60 plays a sample at its C-5 speed) and effects
+//! translated into one `Fx` set, so a single engine plays all four. It covers what
+//! game music actually uses: speed/tempo, volume and pitch slides, tone portamento,
+//! vibrato, tremolo, arpeggio, sample offsets, pattern jumps, breaks, loops and delays,
+//! note cut/delay/retrigger and XM/IT volume envelopes. Surround, filters, NNAs and
+//! panning envelopes are left out.
+//!
+//! `Player` renders 44.1 kHz stereo one tick at a time and is a `digital::Source`, so
+//! modules stream, seek and render to WAV through the same path as OGG/MP3.
+
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+
+use crate::digital::Source;
+use crate::song::SongFormat;
+use crate::tracker_formats;
+
+/// Rate modules are rendered at; the output resamples if it runs at another.
+pub const RATE: u32 = 44_100;
+/// Stop after this long even if the song never loops back on itself.
+const MAX_LENGTH_US: u64 = 60 * 60 * 1_000_000;
+/// Note number that plays a sample at its C-5 speed.
+pub const MIDDLE_C: u8 = 60;
+/// Amiga period (in quarter units) times frequency, at the reference 8363 Hz C-5.
+const AMIGA_CLOCK: f64 = 8363.0 * 1712.0;
+
+/// How pitch slides are measured: in Amiga periods (MOD, S3M, XM in Amiga mode) or
+/// in 1/64 semitones (XM and IT in linear mode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slides {
+    Amiga,
+    Linear,
+}
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub channels: usize,
+    /// Pattern numbers in play order
+    pub orders: Vec<usize>,
+    pub patterns: Vec<Pattern>,
+    pub instruments: Vec<Instrument>,
+    pub samples: Vec<Sample>,
+    pub speed: u8,
+    pub tempo: u8,
+    /// 0..=64
+    pub global_volume: u8,
+    /// Initial panning per channel, 0 (left) to 255 (right)
+    pub panning: Vec<u8>,
+    pub slides: Slides,
+    /// Problems found while loading that didn't stop the module from playing
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pattern {
+    pub rows: usize,
+    /// `rows * channels` cells, row by row
+    pub cells: Vec<Cell>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cell {
+    pub note: Note,
+    /// 1-based; 0 for none
+    pub instrument: u8,
+    /// 0..=64, from the volume column
+    pub volume: Option<u8>,
+    /// The effect column, and anything else the volume column held
+    pub fx: [Fx; 2],
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Note {
+    #[default]
+    None,
+    On(u8),
+    Off,
+    Cut,
+}
+
+/// Effects, after translation from each format's letters and numbers.
+///
+/// Pitch amounts are in quarter Amiga periods or 1/64 semitones, per `Slides`; a
+/// coarse slide of `x` moves `4 * x` per tick. Zero parameters reuse the channel's
+/// last one where the formats do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fx {
+    #[default]
+    None,
+    Arpeggio(u8, u8),
+    PortaUp(u8),
+    PortaDown(u8),
+    /// Once, on the first tick; positive is up
+    FinePorta(i16),
+    TonePorta(u8),
+    Vibrato(u8, u8),
+    Tremolo(u8, u8),
+    /// Every tick but the first; positive is up
+    VolSlide(i8),
+    /// Once, on the first tick
+    FineVolSlide(i8),
+    TonePortaVolSlide(i8),
+    VibratoVolSlide(i8),
+    SampleOffset(u32),
+    PositionJump(u8),
+    PatternBreak(u8),
+    SetVolume(u8),
+    SetPanning(u8),
+    SetSpeed(u8),
+    SetTempo(u8),
+    SetGlobalVolume(u8),
+    GlobalVolSlide(i8),
+    PatternLoop(u8),
+    PatternDelay(u8),
+    NoteCut(u8),
+    NoteDelay(u8),
+    KeyOff(u8),
+    Retrigger(u8),
+}
+
+/// Which sample each note plays, plus the volume envelope. MOD and S3M get one per
+/// sample, mapping every note to it.
+#[derive(Debug, Clone)]
+pub struct Instrument {
+    /// For each note: the note actually played and the sample index
+    pub keymap: Vec<(u8, Option<usize>)>,
+    pub envelope: Option<Envelope>,
+    /// Subtracted from 65536 every tick after key-off
+    pub fadeout: u32,
+}
+
+impl Instrument {
+    pub fn single(sample: usize) -> Self {
+        Self { keymap: (0..120).map(|n| (n, Some(sample))).collect(), envelope: None, fadeout: 0 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    /// (tick, value 0..=64)
+    pub points: Vec<(u16, u8)>,
+    pub sustain: Option<usize>,
+    pub looped: Option<(usize, usize)>,
+}
+
+impl Envelope {
+    fn value(&self, tick: u16) -> f32 {
+        let Some(&(last_t, last_v)) = self.points.last() else { return 1.0 };
+        if tick >= last_t {
+            return last_v as f32 / 64.0;
+        }
+        let i = self.points.iter().rposition(|&(t, _)| t <= tick).unwrap_or(0);
+        let ((t0, v0), (t1, v1)) = (self.points[i], self.points[(i + 1).min(self.points.len() - 1)]);
+        let f = if t1 > t0 { (tick - t0) as f32 / (t1 - t0) as f32 } else { 0.0 };
+        (v0 as f32 + (v1 as f32 - v0 as f32) * f) / 64.0
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    pub data: Vec<f32>,
+    pub looped: Option<Loop>,
+    /// 0..=64
+    pub volume: u8,
+    /// Playback rate of note 60
+    pub c5speed: f64,
+    pub panning: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loop {
+    pub start: usize,
+    pub end: usize,
+    pub ping_pong: bool,
+}
+
+impl Module {
+    /// Parse a MOD, S3M, XM or IT lump.
+    pub fn load(bytes: &[u8], format: SongFormat) -> Result<Self> {
+        tracker_formats::load(bytes, format)
+    }
+
+    fn cell(&self, pattern: usize, row: usize, channel: usize) -> Cell {
+        self.patterns
+            .get(pattern)
+            .and_then(|p| p.cells.get(row * self.channels + channel))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn rows(&self, pattern: usize) -> usize {
+        self.patterns.get(pattern).map_or(64, |p| p.rows.max(1))
+    }
+}
+
+/// Vibrato and tremolo waveform: one sine cycle in 64 steps, amplitude 255.
+fn sine(pos: u8) -> i32 {
+    (((pos & 63) as f64 / 64.0 * std::f64::consts::TAU).sin() * 255.0).round() as i32
+}
+
+#[derive(Debug, Clone, Default)]
+struct Channel {
+    instrument: Option<usize>,
+    sample: Option<usize>,
+    active: bool,
+    pos: f64,
+    backwards: bool,
+    /// Current pitch: an Amiga period or 1/64 semitones, per `Slides`
+    pitch: f64,
+    target: f64,
+    c5speed: f64,
+    volume: i32,
+    pan: u8,
+    // Per-tick offsets from arpeggio, vibrato and tremolo
+    pitch_offset: f64,
+    semitone_offset: u8,
+    volume_offset: i32,
+    vib_pos: u8,
+    vib: (u8, u8),
+    trem_pos: u8,
+    trem: (u8, u8),
+    // Effect memory
+    porta_mem: u8,
+    tone_porta_mem: u8,
+    vol_slide_mem: i8,
+    offset_mem: u32,
+    retrig_mem: u8,
+    // Envelope and fade
+    env_tick: u16,
+    keyed_off: bool,
+    fade: u32,
+    // Pattern loop
+    loop_row: usize,
+    loop_count: u8,
+    /// Cell held back by a note delay
+    delayed: Option<(u8, Cell)>,
+}
+
+/// Plays a `Module`, one tick at a time.
+pub struct Player {
+    module: Arc<Module>,
+    channels: Vec<Channel>,
+    order: usize,
+    row: usize,
+    tick: u32,
+    speed: u32,
+    tempo: u32,
+    global_volume: i32,
+    /// Extra row repeats from a pattern delay
+    row_delay: u32,
+    delay_count: u32,
+    next: Option<(usize, usize)>,
+    visited: HashSet<(usize, usize)>,
+    ended: bool,
+    elapsed_frames: u64,
+    /// Fractional frames carried between ticks
+    frame_rest: f64,
+    frames_this_tick: usize,
+}
+
+impl Player {
+    pub fn new(module: Module) -> Self {
+        Self::shared(Arc::new(module))
+    }
+
+    fn shared(module: Arc<Module>) -> Self {
+        let mut p = Self {
+            channels: Vec::new(),
+            order: 0,
+            row: 0,
+            tick: 0,
+            speed: 6,
+            tempo: 125,
+            global_volume: 64,
+            row_delay: 0,
+            delay_count: 0,
+            next: None,
+            visited: HashSet::new(),
+            ended: false,
+            elapsed_frames: 0,
+            frame_rest: 0.0,
+            frames_this_tick: 0,
+            module,
+        };
+        p.reset();
+        p
+    }
+
+    /// How long the song plays before it ends or loops back on itself. Works it out
+    /// by running through the song without mixing.
+    pub fn length_us(&self) -> u64 {
+        let mut p = Self::shared(self.module.clone());
+        let mut frames = 0u64;
+        while let Some(n) = p.step() {
+            frames += n as u64;
+        }
+        frames * 1_000_000 / RATE as u64
+    }
+
+    fn reset(&mut self) {
+        let m = &self.module;
+        self.channels = (0..m.channels)
+            .map(|i| Channel { pan: m.panning.get(i).copied().unwrap_or(128), fade: 65536, ..Default::default() })
+            .collect();
+        self.order = 0;
+        self.row = 0;
+        self.tick = 0;
+        self.speed = m.speed.max(1) as u32;
+        self.tempo = m.tempo.max(32) as u32;
+        self.global_volume = m.global_volume as i32;
+        self.row_delay = 0;
+        self.delay_count = 0;
+        self.next = None;
+        self.visited.clear();
+        self.ended = false;
+        self.elapsed_frames = 0;
+        self.frame_rest = 0.0;
+        self.skip_empty_orders();
+    }
+
+    fn skip_empty_orders(&mut self) {
+        while self.order < self.module.orders.len() && self.module.orders[self.order] >= self.module.patterns.len() {
+            self.order += 1;
+        }
+        if self.order >= self.module.orders.len() {
+            self.ended = true;
+        }
+    }
+
+    /// Advance one tick and return how many frames it lasts, or `None` at the end.
+    fn step(&mut self) -> Option<usize> {
+        if self.ended || self.elapsed_frames * 1_000_000 / RATE as u64 >= MAX_LENGTH_US {
+            return None;
+        }
+        if self.tick == 0 && self.delay_count == 0 {
+            if !self.visited.insert((self.order, self.row)) && self.channels.iter().all(|c| c.loop_count == 0) {
+                self.ended = true;
+                return None;
+            }
+            self.play_row();
+        } else {
+            self.tick_effects();
+        }
+        self.run_delayed();
+        self.update_envelopes();
+
+        // A tick lasts 2.5 / tempo seconds
+        let exact = RATE as f64 * 2.5 / self.tempo as f64 + self.frame_rest;
+        let frames = exact as usize;
+        self.frame_rest = exact - frames as f64;
+        self.frames_this_tick = frames;
+        self.elapsed_frames += frames as u64;
+
+        self.tick += 1;
+        if self.tick >= self.speed {
+            self.tick = 0;
+            if self.delay_count < self.row_delay {
+                self.delay_count += 1;
+            } else {
+                self.delay_count = 0;
+                self.row_delay = 0;
+                self.advance_row();
+            }
+        }
+        Some(frames)
+    }
+
+    fn advance_row(&mut self) {
+        if let Some((order, row)) = self.next.take() {
+            if order != self.order || row <= self.row {
+                // Jumping or breaking starts the target pattern's loops afresh
+                for c in &mut self.channels {
+                    c.loop_row = 0;
+                }
+            }
+            self.order = order;
+            self.row = row;
+        } else {
+            self.row += 1;
+            let pattern = self.module.orders[self.order];
+            if self.row >= self.module.rows(pattern) {
+                self.row = 0;
+                self.order += 1;
+                for c in &mut self.channels {
+                    c.loop_row = 0;
+                }
+            }
+        }
+        self.skip_empty_orders();
+        if !self.ended && self.row >= self.module.rows(self.module.orders[self.order]) {
+            self.row = 0;
+        }
+    }
+
+    fn play_row(&mut self) {
+        let pattern = self.module.orders[self.order];
+        for ch in 0..self.channels.len() {
+            let cell = self.module.cell(pattern, self.row, ch);
+            let c = &mut self.channels[ch];
+            c.pitch_offset = 0.0;
+            c.semitone_offset = 0;
+            c.volume_offset = 0;
+            c.delayed = None;
+            if let Some(d) = cell.fx.iter().find_map(|f| match f { Fx::NoteDelay(d) if *d > 0 => Some(*d), _ => None }) {
+                c.delayed = Some((d, cell));
+                continue;
+            }
+            self.trigger(ch, cell);
+            for fx in cell.fx {
+                self.first_tick(ch, fx);
+            }
+        }
+    }
+
+    fn run_delayed(&mut self) {
+        for ch in 0..self.channels.len() {
+            if let Some((tick, cell)) = self.channels[ch].delayed
+                && tick as u32 == self.tick
+            {
+                self.channels[ch].delayed = None;
+                self.trigger(ch, cell);
+                for fx in cell.fx {
+                    self.first_tick(ch, fx);
+                }
+            }
+        }
+    }
+
+    /// Note, instrument and volume columns.
+    fn trigger(&mut self, ch: usize, cell: Cell) {
+        let m = &self.module;
+        let c = &mut self.channels[ch];
+        let tone_porta = cell.fx.iter().any(|f| matches!(f, Fx::TonePorta(_) | Fx::TonePortaVolSlide(_)));
+
+        if cell.instrument > 0 && (cell.instrument as usize) <= m.instruments.len() {
+            c.instrument = Some(cell.instrument as usize - 1);
+            // An instrument alone resets the volume to the sample's
+            let note = match cell.note { Note::On(n) => n, _ => MIDDLE_C };
+            if let Some(s) = resolve(m, c.instrument, note).map(|(_, s)| &m.samples[s]) {
+                c.volume = s.volume as i32;
+                if let Some(p) = s.panning { c.pan = p; }
+            }
+            if !tone_porta {
+                c.fade = 65536;
+                c.env_tick = 0;
+                c.keyed_off = false;
+            }
+        }
+
+        match cell.note {
+            Note::On(n) => {
+                if let Some((played, s)) = resolve(m, c.instrument, n) {
+                    let speed = m.samples[s].c5speed;
+                    let pitch = note_pitch(m.slides, played, speed);
+                    if tone_porta && c.active {
+                        c.target = pitch;
+                    } else {
+                        c.sample = Some(s);
+                        c.c5speed = speed;
+                        c.pitch = pitch;
+                        c.target = pitch;
+                        c.pos = 0.0;
+                        c.backwards = false;
+                        c.active = true;
+                        c.vib_pos = 0;
+                        c.trem_pos = 0;
+                        c.env_tick = 0;
+                        c.keyed_off = false;
+                        c.fade = 65536;
+                    }
+                }
+            }
+            Note::Off => key_off(c, m),
+            Note::Cut => c.active = false,
+            Note::None => {}
+        }
+        if let Some(v) = cell.volume {
+            c.volume = v.min(64) as i32;
+        }
+    }
+
+    fn first_tick(&mut self, ch: usize, fx: Fx) {
+        let slides = self.module.slides;
+        let c = &mut self.channels[ch];
+        match fx {
+            Fx::SetVolume(v) => c.volume = v.min(64) as i32,
+            Fx::SetPanning(p) => c.pan = p,
+            Fx::SetSpeed(s) if s > 0 => self.speed = s as u32,
+            Fx::SetTempo(t) if t >= 32 => self.tempo = t as u32,
+            Fx::SetGlobalVolume(v) => self.global_volume = v.min(64) as i32,
+            Fx::FinePorta(d) => slide(slides, c, d as f64),
+            Fx::FineVolSlide(d) => c.volume = (c.volume + d as i32).clamp(0, 64),
+            Fx::SampleOffset(o) => {
+                let o = if o == 0 { c.offset_mem } else { o };
+                c.offset_mem = o;
+                c.pos = o as f64;
+                if let Some(s) = c.sample.map(|s| &self.module.samples[s])
+                    && c.pos >= s.data.len() as f64
+                {
+                    c.active = false;
+                }
+            }
+            Fx::PositionJump(o) => {
+                let row = self.next.map_or(0, |(_, r)| r);
+                self.next = Some((o as usize, row));
+            }
+            Fx::PatternBreak(r) => {
+                let order = self.next.map_or(self.order + 1, |(o, _)| o);
+                self.next = Some((order, r as usize));
+            }
+            Fx::PatternLoop(0) => c.loop_row = self.row,
+            Fx::PatternLoop(n) => {
+                if c.loop_count == 0 {
+                    c.loop_count = n;
+                    self.next = Some((self.order, c.loop_row));
+                } else {
+                    c.loop_count -= 1;
+                    if c.loop_count > 0 {
+                        self.next = Some((self.order, c.loop_row));
+                    } else {
+                        c.loop_row = self.row + 1;
+                    }
+                }
+            }
+            Fx::PatternDelay(n) if self.row_delay == 0 => self.row_delay = n as u32,
+            Fx::NoteCut(0) => c.volume = 0,
+            Fx::KeyOff(0) => key_off(c, &self.module),
+            Fx::PortaUp(x) | Fx::PortaDown(x) if x > 0 => c.porta_mem = x,
+            Fx::TonePorta(x) if x > 0 => c.tone_porta_mem = x,
+            Fx::Vibrato(s, d) => {
+                if s > 0 { c.vib.0 = s; }
+                if d > 0 { c.vib.1 = d; }
+            }
+            Fx::Tremolo(s, d) => {
+                if s > 0 { c.trem.0 = s; }
+                if d > 0 { c.trem.1 = d; }
+            }
+            Fx::VolSlide(d) | Fx::TonePortaVolSlide(d) | Fx::VibratoVolSlide(d) if d != 0 => c.vol_slide_mem = d,
+            Fx::Retrigger(x) if x > 0 => c.retrig_mem = x,
+            _ => {}
+        }
+    }
+
+    fn tick_effects(&mut self) {
+        let slides = self.module.slides;
+        let pattern = self.module.orders[self.order];
+        let tick = self.tick;
+        for ch in 0..self.channels.len() {
+            let cell = self.module.cell(pattern, self.row, ch);
+            let c = &mut self.channels[ch];
+            c.pitch_offset = 0.0;
+            c.semitone_offset = 0;
+            c.volume_offset = 0;
+            for fx in cell.fx {
+                match fx {
+                    Fx::Arpeggio(x, y) => c.semitone_offset = [0, x, y][tick as usize % 3],
+                    Fx::PortaUp(_) => slide(slides, c, 4.0 * c.porta_mem as f64),
+                    Fx::PortaDown(_) => slide(slides, c, -4.0 * c.porta_mem as f64),
+                    Fx::TonePorta(_) => tone_porta(slides, c),
+                    Fx::TonePortaVolSlide(_) => {
+                        tone_porta(slides, c);
+                        c.volume = (c.volume + c.vol_slide_mem as i32).clamp(0, 64);
+                    }
+                    Fx::Vibrato(..) => vibrato(slides, c),
+                    Fx::VibratoVolSlide(_) => {
+                        vibrato(slides, c);
+                        c.volume = (c.volume + c.vol_slide_mem as i32).clamp(0, 64);
+                    }
+                    Fx::Tremolo(..) => {
+                        c.volume_offset = sine(c.trem_pos) * c.trem.1 as i32 / 64;
+                        c.trem_pos = c.trem_pos.wrapping_add(c.trem.0);
+                    }
+                    Fx::VolSlide(_) => c.volume = (c.volume + c.vol_slide_mem as i32).clamp(0, 64),
+                    Fx::GlobalVolSlide(d) => self.global_volume = (self.global_volume + d as i32).clamp(0, 64),
+                    Fx::NoteCut(t) if t as u32 == tick => c.volume = 0,
+                    Fx::KeyOff(t) if t as u32 == tick => key_off(c, &self.module),
+                    Fx::Retrigger(_) if c.retrig_mem > 0 && tick.is_multiple_of(c.retrig_mem as u32) => {
+                        c.pos = 0.0;
+                        c.backwards = false;
+                        c.active = c.sample.is_some();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn update_envelopes(&mut self) {
+        for c in &mut self.channels {
+            let Some(inst) = c.instrument.and_then(|i| self.module.instruments.get(i)) else { continue };
+            if let Some(env) = &inst.envelope {
+                let at_sustain = env.sustain.is_some_and(|s| env.points.get(s).is_some_and(|p| p.0 == c.env_tick));
+                if !at_sustain || c.keyed_off {
+                    c.env_tick = c.env_tick.saturating_add(1);
+                    if let Some((ls, le)) = env.looped
+                        && let (Some(&(start, _)), Some(&(end, _))) = (env.points.get(ls), env.points.get(le))
+                        && c.env_tick > end
+                    {
+                        c.env_tick = start;
+                    }
+                }
+            }
+            if c.keyed_off {
+                c.fade = c.fade.saturating_sub(inst.fadeout);
+            }
+        }
+    }
+
+    /// Mix the current tick's frames into `out` (interleaved stereo), or just move the
+    /// samples along when `out` is `None`.
+    fn mix(&mut self, mut out: Option<&mut [f32]>) {
+        let frames = self.frames_this_tick;
+        let m = &self.module;
+        let gain = 0.5 / (m.channels.max(4) as f32 / 4.0).sqrt();
+        for c in &mut self.channels {
+            if !c.active {
+                continue;
+            }
+            let Some(sample) = c.sample.and_then(|s| m.samples.get(s)) else { continue };
+            if sample.data.is_empty() {
+                c.active = false;
+                continue;
+            }
+            let env = c.instrument
+                .and_then(|i| m.instruments.get(i))
+                .and_then(|i| i.envelope.as_ref())
+                .map_or(1.0, |e| e.value(c.env_tick));
+            let vol = (c.volume + c.volume_offset).clamp(0, 64) as f32 / 64.0
+                * env
+                * c.fade as f32 / 65536.0
+                * self.global_volume as f32 / 64.0
+                * gain;
+            let (left, right) = ((255 - c.pan) as f32 / 255.0, c.pan as f32 / 255.0);
+            let step = frequency(m.slides, c) / RATE as f64;
+            let len = sample.data.len();
+
+            for f in 0..frames {
+                if let Some(out) = out.as_deref_mut() {
+                    let i = c.pos as usize;
+                    let frac = (c.pos - i as f64) as f32;
+                    let a = sample.data[i.min(len - 1)];
+                    let b = sample.data[(i + 1).min(len - 1)];
+                    let s = (a + (b - a) * frac) * vol;
+                    out[2 * f] += s * left;
+                    out[2 * f + 1] += s * right;
+                }
+                if c.backwards { c.pos -= step } else { c.pos += step }
+                match sample.looped {
+                    Some(l) if l.ping_pong => {
+                        if !c.backwards && c.pos >= l.end as f64 {
+                            c.pos = (2 * l.end) as f64 - c.pos - 1.0;
+                            c.backwards = true;
+                        }
+                        if c.backwards && c.pos < l.start as f64 {
+                            c.pos = (2 * l.start) as f64 - c.pos;
+                            c.backwards = false;
+                        }
+                        c.pos = c.pos.clamp(0.0, (len - 1) as f64);
+                    }
+                    // A fast sample can pass the end many times in one step
+                    Some(l) if c.pos >= l.end as f64 => {
+                        c.pos = l.start as f64 + (c.pos - l.start as f64).rem_euclid((l.end - l.start) as f64);
+                    }
+                    Some(_) => {}
+                    None if c.pos >= len as f64 => {
+                        c.active = false;
+                        break;
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+/// The note actually played and the sample for `note` on `instrument`.
+fn resolve(m: &Module, instrument: Option<usize>, note: u8) -> Option<(u8, usize)> {
+    let inst = m.instruments.get(instrument?)?;
+    let (played, sample) = *inst.keymap.get(note as usize)?;
+    sample.filter(|&s| s < m.samples.len()).map(|s| (played, s))
+}
+
+fn key_off(c: &mut Channel, m: &Module) {
+    c.keyed_off = true;
+    let enveloped = c.instrument.and_then(|i| m.instruments.get(i)).is_some_and(|i| i.envelope.is_some());
+    if !enveloped {
+        c.active = false;
+    }
+}
+
+fn note_pitch(slides: Slides, note: u8, c5speed: f64) -> f64 {
+    match slides {
+        Slides::Linear => note as f64 * 64.0,
+        Slides::Amiga => AMIGA_CLOCK / (c5speed * 2f64.powf((note as f64 - MIDDLE_C as f64) / 12.0)),
+    }
+}
+
+/// Move the pitch up (positive) or down by `amount` slide units.
+fn slide(slides: Slides, c: &mut Channel, amount: f64) {
+    match slides {
+        Slides::Linear => c.pitch = (c.pitch + amount).clamp(0.0, 120.0 * 64.0),
+        Slides::Amiga => c.pitch = (c.pitch - amount).clamp(56.0, 32_000.0),
+    }
+}
+
+fn tone_porta(slides: Slides, c: &mut Channel) {
+    let amount = 4.0 * c.tone_porta_mem as f64;
+    let up = match slides {
+        Slides::Linear => c.target > c.pitch,
+        Slides::Amiga => c.target < c.pitch,
+    };
+    slide(slides, c, if up { amount } else { -amount });
+    let overshot = match slides {
+        Slides::Linear => (c.pitch - c.target) * if up { 1.0 } else { -1.0 } > 0.0,
+        Slides::Amiga => (c.target - c.pitch) * if up { 1.0 } else { -1.0 } > 0.0,
+    };
+    if overshot {
+        c.pitch = c.target;
+    }
+}
+
+fn vibrato(slides: Slides, c: &mut Channel) {
+    let delta = (sine(c.vib_pos) * c.vib.1 as i32) as f64 / 32.0;
+    c.pitch_offset = match slides {
+        Slides::Linear => delta,
+        Slides::Amiga => -delta,
+    };
+    c.vib_pos = c.vib_pos.wrapping_add(c.vib.0);
+}
+
+/// Playback rate of the channel's sample for this tick, offsets included.
+fn frequency(slides: Slides, c: &Channel) -> f64 {
+    let semis = c.semitone_offset as f64;
+    match slides {
+        Slides::Linear => {
+            let note = (c.pitch + c.pitch_offset) / 64.0 + semis;
+            c.c5speed * 2f64.powf((note - MIDDLE_C as f64) / 12.0)
+        }
+        Slides::Amiga => {
+            let period = (c.pitch + c.pitch_offset).max(1.0);
+            AMIGA_CLOCK / period * 2f64.powf(semis / 12.0)
+        }
+    }
+}
+
+impl Source for Player {
+    fn sample_rate(&self) -> u32 {
+        RATE
+    }
+
+    fn duration_us(&self) -> Option<u64> {
+        Some(self.length_us())
+    }
+
+    fn next_block(&mut self) -> Result<Option<Vec<f32>>> {
+        let Some(frames) = self.step() else { return Ok(None) };
+        let mut out = vec![0.0; frames * 2];
+        self.mix(Some(&mut out));
+        Ok(Some(out))
+    }
+
+    /// Modules have no index to seek with, so this replays from the start without
+    /// mixing until it gets there.
+    fn seek(&mut self, us: u64) -> Result<()> {
+        self.reset();
+        let target = us * RATE as u64 / 1_000_000;
+        while self.elapsed_frames < target && self.step().is_some() {
+            self.mix(None);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One channel, one looping square-ish sample, `rows` rows at speed 6 / tempo 125.
+    fn module(rows: usize, cells: &[(usize, Cell)]) -> Module {
+        let mut pattern = Pattern { rows, cells: vec![Cell::default(); rows] };
+        for &(row, cell) in cells {
+            pattern.cells[row] = cell;
+        }
+        Module {
+            channels: 1,
+            orders: vec![0],
+            patterns: vec![pattern],
+            instruments: vec![Instrument::single(0)],
+            samples: vec![Sample {
+                data: [1.0, 1.0, -1.0, -1.0].repeat(8),
+                looped: Some(Loop { start: 0, end: 32, ping_pong: false }),
+                volume: 64,
+                c5speed: 8363.0,
+                panning: None,
+            }],
+            speed: 6,
+            tempo: 125,
+            global_volume: 64,
+            panning: vec![128],
+            slides: Slides::Amiga,
+            warnings: Vec::new(),
+        }
+    }
+
+    fn note(n: u8) -> Cell {
+        Cell { note: Note::On(n), instrument: 1, ..Default::default() }
+    }
+
+    #[test]
+    fn timing_follows_speed_tempo_and_flow_effects() {
+        // 64 rows * 6 ticks * 20 ms
+        assert_eq!(Player::new(module(64, &[])).length_us() / 1000, 7680);
+
+        // Speed 3 from row 0 halves it; a break on row 15 leaves 16 rows
+        let fx = |f: Fx| Cell { fx: [f, Fx::None], ..Default::default() };
+        assert_eq!(Player::new(module(64, &[(0, fx(Fx::SetSpeed(3)))])).length_us() / 1000, 3840);
+        assert_eq!(Player::new(module(64, &[(15, fx(Fx::PatternBreak(0)))])).length_us() / 1000, 16 * 120);
+
+        // Rows 0-3 looped twice more: 4 + 8 + 60 rows
+        let m = module(64, &[(3, fx(Fx::PatternLoop(2)))]);
+        assert_eq!(Player::new(m).length_us() / 1000, 72 * 120);
+    }
+
+    #[test]
+    fn notes_sound_and_slides_move_the_pitch() {
+        let mut p = Player::new(module(4, &[(0, note(MIDDLE_C))]));
+        let block = p.next_block().unwrap().unwrap();
+        assert_eq!(block.len(), 882 * 2);
+        assert!(block.iter().any(|&s| s.abs() > 0.1));
+        let c5 = frequency(Slides::Amiga, &p.channels[0]);
+        assert!((c5 - 8363.0).abs() < 0.01);
+
+        // An octave up doubles the rate, in both slide modes
+        for slides in [Slides::Amiga, Slides::Linear] {
+            let c = Channel { pitch: note_pitch(slides, 72, 8363.0), c5speed: 8363.0, ..Default::default() };
+            assert!((frequency(slides, &c) - 2.0 * 8363.0).abs() < 0.01);
+        }
+
+        let porta = Cell { note: Note::On(MIDDLE_C), instrument: 1, fx: [Fx::PortaUp(8), Fx::None], ..Default::default() };
+        let mut p = Player::new(module(4, &[(0, porta)]));
+        for _ in 0..6 {
+            p.next_block().unwrap();
+        }
+        // 5 ticks of 32 quarter-periods up from 1712
+        assert_eq!(p.channels[0].pitch, 1712.0 - 160.0);
+    }
+
+    #[test]
+    fn seeking_lands_on_the_same_tick() {
+        let m = module(64, &[(0, note(MIDDLE_C)), (32, note(MIDDLE_C + 12))]);
+        let mut p = Player::new(m);
+        p.seek(4_000_000).unwrap();
+        assert_eq!(p.row, 33);
+        assert!(p.channels[0].active);
+    }
+}
@@ -0,0 +1,1049 @@
+//! tracker_formats.rs
+//!
+//! Loaders for the four module formats found in WADs (ProTracker MOD, Scream Tracker
+//! S3M, FastTracker XM and Impulse Tracker IT), each producing a `tracker::Module`.
+//!
+//! Notes are renumbered so 60 is the sample's C-5 speed in all of them, and effect
+//! columns are translated into `Fx`. Anything the player doesn't do is dropped here.
+//! Truncated sample data is common in old MODs and is cut short rather than rejected;
+//! sample formats we can't decode (packed S3M, compressed IT) play silent with a
+//! warning.
+
+use anyhow::Result;
+
+use crate::song::SongFormat;
+use crate::tracker::{Cell, Envelope, Fx, Instrument, Loop, Module, Note, Pattern, Sample, Slides};
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ModuleError {
+    #[error("not a {0} module")]
+    NotAModule(&'static str),
+    #[error("truncated {0}")]
+    Truncated(&'static str),
+    #[error("{0} channels is more than a module can have")]
+    TooManyChannels(usize),
+    #[error("module has no orders to play")]
+    NoOrders,
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+    #[error("patterns hold more than {MAX_CELLS} cells")]
+    TooManyCells,
+}
+
+/// Most cells all of a module's patterns may hold together: 256 XM patterns of 256
+/// rows on 64 channels. Patterns can share their data, so a tiny file could otherwise
+/// ask for any amount.
+const MAX_CELLS: usize = 256 * 256 * 64;
+/// Rows per pattern the trackers allow.
+const XM_ROWS: std::ops::RangeInclusive<usize> = 1..=256;
+const IT_ROWS: std::ops::RangeInclusive<usize> = 1..=200;
+
+/// Order list value that ends the song in S3M and IT; 254 is a skipped marker.
+const ORDER_END: u8 = 255;
+const ORDER_SKIP: u8 = 254;
+
+/// Signature-bearing tag at offset 1080 of a 31-sample MOD, and its channel count.
+pub fn mod_channels(bytes: &[u8]) -> Option<usize> {
+    let tag = bytes.get(1080..1084)?;
+    let digit = |b: u8| b.is_ascii_digit().then(|| (b - b'0') as usize);
+    match tag {
+        b"M.K." | b"M!K!" | b"M&K!" | b"FLT4" | b"N.T." => Some(4),
+        b"FLT8" | b"CD81" | b"OKTA" | b"OCTA" => Some(8),
+        [n, b'C', b'H', b'N'] => digit(*n).filter(|&n| n > 0),
+        [a, b, b'C', b'H' | b'N'] => Some(digit(*a)? * 10 + digit(*b)?).filter(|&n| n > 0),
+        _ => None,
+    }
+}
+
+pub fn load(bytes: &[u8], format: SongFormat) -> Result<Module> {
+    let mut m = match format {
+        SongFormat::Mod => load_mod(bytes)?,
+        SongFormat::S3m => load_s3m(bytes)?,
+        SongFormat::Xm => load_xm(bytes)?,
+        SongFormat::It => load_it(bytes)?,
+        _ => return Err(ModuleError::NotAModule(format.name()).into()),
+    };
+    if m.orders.iter().all(|&o| o >= m.patterns.len()) {
+        return Err(ModuleError::NoOrders.into());
+    }
+    if m.channels > 64 {
+        return Err(ModuleError::TooManyChannels(m.channels).into());
+    }
+    m.panning.resize(m.channels, 128);
+    Ok(m)
+}
+
+/// Bounds-checked little/big-endian reads, failing with what was being read.
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a> {
+    fn slice(&self, at: usize, len: usize, what: &'static str) -> Result<&'a [u8], ModuleError> {
+        self.0.get(at..at.saturating_add(len)).ok_or(ModuleError::Truncated(what))
+    }
+    fn u8(&self, at: usize, what: &'static str) -> Result<u8, ModuleError> {
+        self.0.get(at).copied().ok_or(ModuleError::Truncated(what))
+    }
+    fn u16le(&self, at: usize, what: &'static str) -> Result<u16, ModuleError> {
+        Ok(u16::from_le_bytes(self.slice(at, 2, what)?.try_into().unwrap()))
+    }
+    fn u16be(&self, at: usize, what: &'static str) -> Result<u16, ModuleError> {
+        Ok(u16::from_be_bytes(self.slice(at, 2, what)?.try_into().unwrap()))
+    }
+    fn u32le(&self, at: usize, what: &'static str) -> Result<u32, ModuleError> {
+        Ok(u32::from_le_bytes(self.slice(at, 4, what)?.try_into().unwrap()))
+    }
+    /// Up to `len` bytes from `at`, fewer if the file ends first.
+    fn tail(&self, at: usize, len: usize) -> &'a [u8] {
+        let start = at.min(self.0.len());
+        &self.0[start..start.saturating_add(len).min(self.0.len())]
+    }
+}
+
+fn pcm8(data: &[u8], signed: bool) -> Vec<f32> {
+    let bias = if signed { 0 } else { 128 };
+    data.iter().map(|&b| (b.wrapping_sub(bias) as i8) as f32 / 128.0).collect()
+}
+
+fn pcm16(data: &[u8], signed: bool) -> Vec<f32> {
+    let bias = if signed { 0 } else { 0x8000 };
+    data.chunks_exact(2)
+        .map(|b| (u16::from_le_bytes([b[0], b[1]]).wrapping_sub(bias) as i16) as f32 / 32768.0)
+        .collect()
+}
+
+/// Count `n` more pattern cells against `MAX_CELLS`.
+fn reserve_cells(total: &mut usize, n: usize) -> Result<(), ModuleError> {
+    *total += n;
+    if *total > MAX_CELLS {
+        return Err(ModuleError::TooManyCells);
+    }
+    Ok(())
+}
+
+/// A loop, if it's inside the sample and at least two frames long.
+fn sample_loop(start: usize, end: usize, len: usize, ping_pong: bool) -> Option<Loop> {
+    let end = end.min(len);
+    (end >= start + 2).then_some(Loop { start, end, ping_pong })
+}
+
+/// Volume slide parameter `xy` as MOD/XM use it: up by x, else down by y.
+fn slide_param(p: u8) -> i8 {
+    if p >> 4 != 0 { (p >> 4) as i8 } else { -((p & 15) as i8) }
+}
+
+/// A ProTracker effect, as used by MOD and the first 16 XM effects.
+fn protracker_fx(e: u8, p: u8) -> Fx {
+    let (x, y) = (p >> 4, p & 15);
+    match e {
+        0x0 if p != 0 => Fx::Arpeggio(x, y),
+        0x1 => Fx::PortaUp(p),
+        0x2 => Fx::PortaDown(p),
+        0x3 => Fx::TonePorta(p),
+        0x4 => Fx::Vibrato(x, y),
+        0x5 => Fx::TonePortaVolSlide(slide_param(p)),
+        0x6 => Fx::VibratoVolSlide(slide_param(p)),
+        0x7 => Fx::Tremolo(x, y),
+        0x8 => Fx::SetPanning(p),
+        0x9 => Fx::SampleOffset(p as u32 * 256),
+        0xA => Fx::VolSlide(slide_param(p)),
+        0xB => Fx::PositionJump(p),
+        0xC => Fx::SetVolume(p.min(64)),
+        // Written in decimal
+        0xD => Fx::PatternBreak(x * 10 + y),
+        0xE => match x {
+            0x1 => Fx::FinePorta(4 * y as i16),
+            0x2 => Fx::FinePorta(-4 * y as i16),
+            0x6 => Fx::PatternLoop(y),
+            0x8 => Fx::SetPanning(y * 17),
+            0x9 => Fx::Retrigger(y),
+            0xA => Fx::FineVolSlide(y as i8),
+            0xB => Fx::FineVolSlide(-(y as i8)),
+            0xC => Fx::NoteCut(y),
+            0xD => Fx::NoteDelay(y),
+            0xE => Fx::PatternDelay(y),
+            _ => Fx::None,
+        },
+        0xF if p == 0 => Fx::None,
+        0xF if p < 32 => Fx::SetSpeed(p),
+        0xF => Fx::SetTempo(p),
+        _ => Fx::None,
+    }
+}
+
+/// Volume slide `Dxy` as S3M and IT use it, fine slides included.
+fn s3m_vol_slide(p: u8) -> Fx {
+    match (p >> 4, p & 15) {
+        (0, 0) => Fx::VolSlide(0),
+        (x, 0xF) if x > 0 => Fx::FineVolSlide(x as i8),
+        (0xF, y) if y > 0 => Fx::FineVolSlide(-(y as i8)),
+        (x, 0) => Fx::VolSlide(x as i8),
+        (_, y) => Fx::VolSlide(-(y as i8)),
+    }
+}
+
+/// Porta `Exx`/`Fxx` in S3M and IT: `Fx` is fine, `Ex` extra fine, anything else coarse.
+fn s3m_porta(p: u8, up: bool) -> Fx {
+    let sign = if up { 1 } else { -1 };
+    match p >> 4 {
+        0xF => Fx::FinePorta(sign * 4 * (p & 15) as i16),
+        0xE => Fx::FinePorta(sign * (p & 15) as i16),
+        _ if up => Fx::PortaUp(p),
+        _ => Fx::PortaDown(p),
+    }
+}
+
+/// An S3M or IT effect; `cmd` 1 is A. The two differ in `C`, `V` and `X` ranges.
+fn s3m_fx(cmd: u8, p: u8, it: bool) -> Fx {
+    let (x, y) = (p >> 4, p & 15);
+    let coarse = |f: Fx| match f {
+        Fx::VolSlide(d) => d,
+        _ => 0,
+    };
+    match cmd {
+        1 if p > 0 => Fx::SetSpeed(p),
+        2 => Fx::PositionJump(p),
+        3 if it => Fx::PatternBreak(p),
+        3 => Fx::PatternBreak(x * 10 + y),
+        4 => s3m_vol_slide(p),
+        5 => s3m_porta(p, false),
+        6 => s3m_porta(p, true),
+        7 => Fx::TonePorta(p),
+        8 => Fx::Vibrato(x, y),
+        10 => Fx::Arpeggio(x, y),
+        11 => Fx::VibratoVolSlide(coarse(s3m_vol_slide(p))),
+        12 => Fx::TonePortaVolSlide(coarse(s3m_vol_slide(p))),
+        15 => Fx::SampleOffset(p as u32 * 256),
+        17 => Fx::Retrigger(y),
+        18 => Fx::Tremolo(x, y),
+        19 => match x {
+            0x8 => Fx::SetPanning(y * 17),
+            0xB => Fx::PatternLoop(y),
+            0xC => Fx::NoteCut(y),
+            0xD => Fx::NoteDelay(y),
+            0xE => Fx::PatternDelay(y),
+            _ => Fx::None,
+        },
+        20 if p >= 0x20 => Fx::SetTempo(p),
+        // Fine vibrato, at a quarter of the depth
+        21 => Fx::Vibrato(x, y.div_ceil(4)),
+        22 if it => Fx::SetGlobalVolume(p.min(128) / 2),
+        22 => Fx::SetGlobalVolume(p.min(64)),
+        23 if it => Fx::GlobalVolSlide(coarse(s3m_vol_slide(p))),
+        24 if it => Fx::SetPanning(p),
+        24 => Fx::SetPanning((p.min(0x80) as u16 * 2).min(255) as u8),
+        _ => Fx::None,
+    }
+}
+
+/// Order list bytes up to the end marker, with skip markers mapped past every pattern.
+fn orders(list: &[u8]) -> Vec<usize> {
+    list.iter()
+        .take_while(|&&o| o != ORDER_END)
+        .map(|&o| if o == ORDER_SKIP { usize::MAX } else { o as usize })
+        .collect()
+}
+
+fn load_mod(bytes: &[u8]) -> Result<Module, ModuleError> {
+    let b = Bytes(bytes);
+    let channels = mod_channels(bytes).ok_or(ModuleError::NotAModule("MOD"))?;
+    let song_len = b.u8(950, "order list")?.clamp(1, 128) as usize;
+    let order_table = b.slice(952, 128, "order list")?;
+    let num_patterns = *order_table.iter().max().unwrap() as usize + 1;
+
+    let mut patterns = Vec::with_capacity(num_patterns);
+    let pattern_len = 64 * channels * 4;
+    for p in 0..num_patterns {
+        let data = b.slice(1084 + p * pattern_len, pattern_len, "pattern")?;
+        let cells = data
+            .chunks_exact(4)
+            .map(|c| {
+                let period = ((c[0] as u16 & 0x0F) << 8) | c[1] as u16;
+                // Period 428 is C-5; finetune is applied through the sample's speed
+                let note = if period == 0 {
+                    Note::None
+                } else {
+                    Note::On((60.0 + 12.0 * (428.0 / period as f64).log2()).round().clamp(0.0, 119.0) as u8)
+                };
+                Cell {
+                    note,
+                    instrument: (c[0] & 0xF0) | (c[2] >> 4),
+                    volume: None,
+                    fx: [protracker_fx(c[2] & 0x0F, c[3]), Fx::None],
+                }
+            })
+            .collect();
+        patterns.push(Pattern { rows: 64, cells });
+    }
+
+    let mut warnings = Vec::new();
+    let mut samples = Vec::with_capacity(31);
+    let mut at = 1084 + num_patterns * pattern_len;
+    for i in 0..31 {
+        let h = 20 + i * 30;
+        let len = b.u16be(h + 22, "sample header")? as usize * 2;
+        let finetune = ((b.u8(h + 24, "sample header")? << 4) as i8 >> 4) as f64;
+        let loop_start = b.u16be(h + 26, "sample header")? as usize * 2;
+        let loop_len = b.u16be(h + 28, "sample header")? as usize * 2;
+        let raw = b.tail(at, len);
+        if raw.len() < len {
+            warnings.push(format!("sample {} is cut short ({} of {} bytes)", i + 1, raw.len(), len));
+        }
+        at += len;
+        let data = pcm8(raw, true);
+        samples.push(Sample {
+            looped: if loop_len > 2 { sample_loop(loop_start, loop_start + loop_len, data.len(), false) } else { None },
+            data,
+            volume: b.u8(h + 25, "sample header")?.min(64),
+            // Finetune is in eighths of a semitone
+            c5speed: 8363.0 * 2f64.powf(finetune / 96.0),
+            panning: None,
+        });
+    }
+
+    Ok(Module {
+        channels,
+        orders: order_table[..song_len].iter().map(|&o| o as usize).collect(),
+        patterns,
+        instruments: (0..31).map(Instrument::single).collect(),
+        samples,
+        speed: 6,
+        tempo: 125,
+        global_volume: 64,
+        // Amiga LRRL, narrowed so headphones aren't hard-panned
+        panning: (0..channels).map(|c| if matches!(c % 4, 0 | 3) { 64 } else { 192 }).collect(),
+        slides: Slides::Amiga,
+        warnings,
+    })
+}
+
+fn load_s3m(bytes: &[u8]) -> Result<Module, ModuleError> {
+    let b = Bytes(bytes);
+    if b.slice(44, 4, "header")? != b"SCRM" {
+        return Err(ModuleError::NotAModule("S3M"));
+    }
+    let num_orders = b.u16le(0x20, "header")? as usize;
+    let num_samples = b.u16le(0x22, "header")? as usize;
+    let num_patterns = b.u16le(0x24, "header")? as usize;
+    let signed = b.u16le(0x2A, "header")? == 1;
+    let stereo = b.u8(0x33, "header")? & 0x80 != 0;
+    let settings = b.slice(0x40, 32, "channel settings")?;
+    let order_list = b.slice(0x60, num_orders, "order list")?;
+    let sample_ptrs = 0x60 + num_orders;
+    let pattern_ptrs = sample_ptrs + num_samples * 2;
+
+    // Channels 0-7 are left, 8-15 right; the rest are AdLib or off
+    let channels = settings.iter().rposition(|&s| s < 16).map_or(0, |c| c + 1);
+    let mut panning: Vec<u8> = settings[..channels]
+        .iter()
+        .map(|&s| if !stereo { 128 } else if s < 8 { 64 } else { 192 })
+        .collect();
+    if b.u8(0x35, "header")? == 252 {
+        let pans = b.slice(pattern_ptrs + num_patterns * 2, 32, "panning table")?;
+        for (pan, &v) in panning.iter_mut().zip(pans) {
+            if v & 0x20 != 0 {
+                *pan = (v & 15) * 17;
+            }
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let mut samples = Vec::with_capacity(num_samples);
+    for i in 0..num_samples {
+        let h = b.u16le(sample_ptrs + i * 2, "sample pointers")? as usize * 16;
+        if b.u8(h, "sample header")? != 1 {
+            // AdLib instruments or empty slots
+            samples.push(Sample::default());
+            continue;
+        }
+        let offset = ((b.u8(h + 0x0D, "sample header")? as usize) << 16 | b.u16le(h + 0x0E, "sample header")? as usize) * 16;
+        let len = b.u32le(h + 0x10, "sample header")? as usize;
+        let flags = b.u8(h + 0x1F, "sample header")?;
+        let sixteen = flags & 4 != 0;
+        let data = if b.u8(h + 0x1E, "sample header")? != 0 {
+            warnings.push(format!("sample {} is packed, which isn't supported", i + 1));
+            Vec::new()
+        } else if sixteen {
+            pcm16(b.tail(offset, len * 2), signed)
+        } else {
+            pcm8(b.tail(offset, len), signed)
+        };
+        let c2spd = b.u32le(h + 0x20, "sample header")?;
+        samples.push(Sample {
+            looped: if flags & 1 != 0 {
+                sample_loop(b.u32le(h + 0x14, "sample header")? as usize, b.u32le(h + 0x18, "sample header")? as usize, data.len(), false)
+            } else {
+                None
+            },
+            data,
+            volume: b.u8(h + 0x1C, "sample header")?.min(64),
+            c5speed: if c2spd == 0 { 8363.0 } else { c2spd as f64 },
+            panning: None,
+        });
+    }
+
+    let mut patterns = Vec::with_capacity(num_patterns);
+    let mut total = 0;
+    for i in 0..num_patterns {
+        let at = b.u16le(pattern_ptrs + i * 2, "pattern pointers")? as usize * 16;
+        reserve_cells(&mut total, 64 * channels)?;
+        let mut cells = vec![Cell::default(); 64 * channels];
+        if at == 0 {
+            patterns.push(Pattern { rows: 64, cells });
+            continue;
+        }
+        let mut pos = at + 2;
+        let mut row = 0;
+        while row < 64 {
+            let what = b.u8(pos, "pattern")?;
+            pos += 1;
+            if what == 0 {
+                row += 1;
+                continue;
+            }
+            let mut cell = Cell::default();
+            if what & 0x20 != 0 {
+                cell.note = match b.u8(pos, "pattern")? {
+                    255 => Note::None,
+                    254 => Note::Cut,
+                    n => Note::On(((n >> 4) * 12 + (n & 15) + 12).min(119)),
+                };
+                cell.instrument = b.u8(pos + 1, "pattern")?;
+                pos += 2;
+            }
+            if what & 0x40 != 0 {
+                cell.volume = Some(b.u8(pos, "pattern")?.min(64));
+                pos += 1;
+            }
+            if what & 0x80 != 0 {
+                cell.fx[0] = s3m_fx(b.u8(pos, "pattern")?, b.u8(pos + 1, "pattern")?, false);
+                pos += 2;
+            }
+            let ch = (what & 31) as usize;
+            if ch < channels {
+                cells[row * channels + ch] = cell;
+            }
+        }
+        patterns.push(Pattern { rows: 64, cells });
+    }
+
+    Ok(Module {
+        channels,
+        orders: orders(order_list),
+        patterns,
+        instruments: (0..num_samples).map(Instrument::single).collect(),
+        samples,
+        speed: b.u8(0x31, "header")?,
+        tempo: b.u8(0x32, "header")?,
+        global_volume: b.u8(0x30, "header")?.min(64),
+        panning,
+        slides: Slides::Amiga,
+        warnings,
+    })
+}
+
+fn load_xm(bytes: &[u8]) -> Result<Module, ModuleError> {
+    let b = Bytes(bytes);
+    if !bytes.starts_with(b"Extended Module: ") {
+        return Err(ModuleError::NotAModule("XM"));
+    }
+    let header_size = b.u32le(60, "header")? as usize;
+    let song_len = b.u16le(64, "header")? as usize;
+    let channels = b.u16le(68, "header")? as usize;
+    let num_patterns = b.u16le(70, "header")? as usize;
+    let num_instruments = b.u16le(72, "header")? as usize;
+    let linear = b.u16le(74, "header")? & 1 != 0;
+    let order_list = b.slice(80, song_len.min(256), "order list")?;
+    if channels > 64 {
+        return Err(ModuleError::TooManyChannels(channels));
+    }
+
+    let mut at = 60 + header_size;
+    let mut patterns = Vec::with_capacity(num_patterns.min(256));
+    let mut total = 0;
+    for _ in 0..num_patterns {
+        let header_len = b.u32le(at, "pattern header")? as usize;
+        if header_len == 0 {
+            return Err(ModuleError::Invalid("pattern header"));
+        }
+        let rows = (b.u16le(at + 5, "pattern header")? as usize).clamp(*XM_ROWS.start(), *XM_ROWS.end());
+        let packed = b.u16le(at + 7, "pattern header")? as usize;
+        at += header_len;
+        let data = b.slice(at, packed, "pattern")?;
+        at += packed;
+
+        reserve_cells(&mut total, rows * channels)?;
+        let mut cells = vec![Cell::default(); rows * channels];
+        let mut pos = 0;
+        for cell in cells.iter_mut().take(if packed == 0 { 0 } else { rows * channels }) {
+            let field = |pos: &mut usize| -> Result<u8, ModuleError> {
+                let v = *data.get(*pos).ok_or(ModuleError::Truncated("pattern"))?;
+                *pos += 1;
+                Ok(v)
+            };
+            let first = field(&mut pos)?;
+            let (mask, mut note) = if first & 0x80 != 0 { (first, 0) } else { (0x1F, first) };
+            let mut get = |bit: u8| if mask & bit != 0 { field(&mut pos) } else { Ok(0) };
+            if first & 0x80 != 0 {
+                note = get(1)?;
+            }
+            let (instrument, vol, e, p) = (get(2)?, get(4)?, get(8)?, get(16)?);
+
+            cell.note = match note {
+                1..=96 => Note::On(note - 1 + 12),
+                97 => Note::Off,
+                _ => Note::None,
+            };
+            cell.instrument = instrument;
+            cell.fx[0] = match e {
+                0..=15 => protracker_fx(e, p),
+                16 => Fx::SetGlobalVolume(p.min(64)),
+                17 => Fx::GlobalVolSlide(slide_param(p)),
+                20 => Fx::KeyOff(p),
+                27 => Fx::Retrigger(p & 15),
+                33 if p >> 4 == 1 => Fx::FinePorta((p & 15) as i16),
+                33 if p >> 4 == 2 => Fx::FinePorta(-((p & 15) as i16)),
+                _ => Fx::None,
+            };
+            let v = vol & 15;
+            match vol {
+                0x10..=0x50 => cell.volume = Some(vol - 0x10),
+                0x60..=0x6F => cell.fx[1] = Fx::VolSlide(-(v as i8)),
+                0x70..=0x7F => cell.fx[1] = Fx::VolSlide(v as i8),
+                0x80..=0x8F => cell.fx[1] = Fx::FineVolSlide(-(v as i8)),
+                0x90..=0x9F => cell.fx[1] = Fx::FineVolSlide(v as i8),
+                0xA0..=0xAF => cell.fx[1] = Fx::Vibrato(v, 0),
+                0xB0..=0xBF => cell.fx[1] = Fx::Vibrato(0, v),
+                0xC0..=0xCF => cell.fx[1] = Fx::SetPanning(v * 17),
+                0xF0..=0xFF => cell.fx[1] = Fx::TonePorta(v * 16),
+                _ => {}
+            }
+        }
+        patterns.push(Pattern { rows, cells });
+    }
+
+    let mut warnings = Vec::new();
+    let mut instruments = Vec::with_capacity(num_instruments);
+    let mut samples = Vec::new();
+    for i in 0..num_instruments {
+        let size = b.u32le(at, "instrument header")? as usize;
+        let count = b.u16le(at + 27, "instrument header")? as usize;
+        if count == 0 {
+            instruments.push(Instrument { keymap: vec![(0, None); 120], envelope: None, fadeout: 0 });
+            at += size.max(29);
+            continue;
+        }
+        let header_size = b.u32le(at + 29, "instrument header")? as usize;
+        let keymap = b.slice(at + 33, 96, "instrument header")?;
+        let points = b.slice(at + 129, 48, "instrument header")?;
+        let kind = b.u8(at + 233, "instrument header")?;
+        let envelope = (kind & 1 != 0).then(|| -> Result<Envelope, ModuleError> {
+            let n = (b.u8(at + 225, "instrument header")? as usize).clamp(1, 12);
+            Ok(Envelope {
+                points: points[..n * 4]
+                    .chunks_exact(4)
+                    .map(|p| (u16::from_le_bytes([p[0], p[1]]), p[2].min(64)))
+                    .collect(),
+                sustain: (kind & 2 != 0).then(|| b.u8(at + 227, "instrument header")).transpose()?.map(usize::from),
+                looped: if kind & 4 != 0 {
+                    Some((b.u8(at + 228, "instrument header")? as usize, b.u8(at + 229, "instrument header")? as usize))
+                } else {
+                    None
+                },
+            })
+        });
+        // FT2 fades from 32768, twice as fine as our 65536
+        let fadeout = b.u16le(at + 239, "instrument header")? as u32 * 2;
+        at += size;
+
+        let first = samples.len();
+        let mut headers = Vec::with_capacity(count);
+        for s in 0..count {
+            let h = at + s * header_size;
+            headers.push((
+                b.u32le(h, "sample header")? as usize,
+                b.u32le(h + 4, "sample header")? as usize,
+                b.u32le(h + 8, "sample header")? as usize,
+                b.u8(h + 12, "sample header")?,
+                b.u8(h + 13, "sample header")? as i8,
+                b.u8(h + 14, "sample header")?,
+                b.u8(h + 15, "sample header")?,
+                b.u8(h + 16, "sample header")? as i8,
+            ));
+        }
+        at += count * header_size;
+        for (len, loop_start, loop_len, volume, finetune, kind, pan, relative) in headers {
+            let raw = b.tail(at, len);
+            if raw.len() < len {
+                warnings.push(format!("instrument {} has a sample cut short", i + 1));
+            }
+            at += len;
+            let sixteen = kind & 0x10 != 0;
+            // Stored as deltas from the previous sample
+            let data: Vec<f32> = if sixteen {
+                let mut acc = 0i16;
+                raw.chunks_exact(2)
+                    .map(|d| {
+                        acc = acc.wrapping_add(i16::from_le_bytes([d[0], d[1]]));
+                        acc as f32 / 32768.0
+                    })
+                    .collect()
+            } else {
+                let mut acc = 0i8;
+                raw.iter()
+                    .map(|&d| {
+                        acc = acc.wrapping_add(d as i8);
+                        acc as f32 / 128.0
+                    })
+                    .collect()
+            };
+            let unit = if sixteen { 2 } else { 1 };
+            let (start, end) = (loop_start / unit, (loop_start + loop_len) / unit);
+            samples.push(Sample {
+                looped: match kind & 3 {
+                    1 => sample_loop(start, end, data.len(), false),
+                    2 => sample_loop(start, end, data.len(), true),
+                    _ => None,
+                },
+                data,
+                volume: volume.min(64),
+                c5speed: 8363.0 * 2f64.powf((relative as f64 + finetune as f64 / 128.0) / 12.0),
+                panning: Some(pan),
+            });
+        }
+
+        instruments.push(Instrument {
+            // XM maps 96 notes starting from our 12
+            keymap: (0..120u8)
+                .map(|n| {
+                    let s = n.checked_sub(12).and_then(|k| keymap.get(k as usize));
+                    (n, s.map(|&s| first + s as usize).filter(|&s| s < first + count))
+                })
+                .collect(),
+            envelope: envelope.transpose()?,
+            fadeout,
+        });
+    }
+
+    Ok(Module {
+        channels,
+        orders: order_list.iter().map(|&o| o as usize).collect(),
+        patterns,
+        instruments,
+        samples,
+        speed: b.u16le(76, "header")?.min(255) as u8,
+        tempo: b.u16le(78, "header")?.min(255) as u8,
+        global_volume: 64,
+        panning: vec![128; channels],
+        slides: if linear { Slides::Linear } else { Slides::Amiga },
+        warnings,
+    })
+}
+
+fn load_it(bytes: &[u8]) -> Result<Module, ModuleError> {
+    let b = Bytes(bytes);
+    if !bytes.starts_with(b"IMPM") {
+        return Err(ModuleError::NotAModule("IT"));
+    }
+    let num_orders = b.u16le(0x20, "header")? as usize;
+    let num_instruments = b.u16le(0x22, "header")? as usize;
+    let num_samples = b.u16le(0x24, "header")? as usize;
+    let num_patterns = b.u16le(0x26, "header")? as usize;
+    let compatible = b.u16le(0x2A, "header")?;
+    let flags = b.u16le(0x2C, "header")?;
+    let channel_pan = b.slice(0x40, 64, "header")?;
+    let order_list = b.slice(0xC0, num_orders, "order list")?;
+    let instrument_ptrs = 0xC0 + num_orders;
+    let sample_ptrs = instrument_ptrs + num_instruments * 4;
+    let pattern_ptrs = sample_ptrs + num_samples * 4;
+
+    let mut warnings = Vec::new();
+    let mut samples = Vec::with_capacity(num_samples);
+    for i in 0..num_samples {
+        let h = b.u32le(sample_ptrs + i * 4, "sample pointers")? as usize;
+        if b.slice(h, 4, "sample header")? != b"IMPS" {
+            return Err(ModuleError::Truncated("sample header"));
+        }
+        let flags = b.u8(h + 0x12, "sample header")?;
+        let len = b.u32le(h + 0x30, "sample header")? as usize;
+        let offset = b.u32le(h + 0x48, "sample header")? as usize;
+        let signed = b.u8(h + 0x2E, "sample header")? & 1 != 0;
+        let mut data = if flags & 1 == 0 {
+            Vec::new()
+        } else if flags & 8 != 0 {
+            warnings.push(format!("sample {} is compressed, which isn't supported", i + 1));
+            Vec::new()
+        } else if flags & 2 != 0 {
+            pcm16(b.tail(offset, len * 2), signed)
+        } else {
+            pcm8(b.tail(offset, len), signed)
+        };
+        // Sample global volume scales the data itself
+        let gain = b.u8(h + 0x11, "sample header")?.min(64) as f32 / 64.0;
+        data.iter_mut().for_each(|s| *s *= gain);
+        let (loop_at, ping_pong) = if flags & 0x10 != 0 {
+            (Some(0x34), flags & 0x40 != 0)
+        } else if flags & 0x20 != 0 {
+            // Sustain loops are treated as plain loops
+            (Some(0x40), flags & 0x80 != 0)
+        } else {
+            (None, false)
+        };
+        let looped = match loop_at {
+            Some(l) => sample_loop(b.u32le(h + l, "sample header")? as usize, b.u32le(h + l + 4, "sample header")? as usize, data.len(), ping_pong),
+            None => None,
+        };
+        let pan = b.u8(h + 0x2F, "sample header")?;
+        samples.push(Sample {
+            data,
+            looped,
+            volume: b.u8(h + 0x13, "sample header")?.min(64),
+            c5speed: b.u32le(h + 0x3C, "sample header")?.max(1) as f64,
+            panning: (pan & 0x80 != 0).then(|| ((pan & 0x7F).min(64) as u16 * 255 / 64) as u8),
+        });
+    }
+
+    let instruments = if flags & 4 == 0 {
+        (0..num_samples).map(Instrument::single).collect()
+    } else {
+        let mut instruments = Vec::with_capacity(num_instruments);
+        for i in 0..num_instruments {
+            let h = b.u32le(instrument_ptrs + i * 4, "instrument pointers")? as usize;
+            let keyboard = b.slice(h + 0x40, 240, "instrument header")?;
+            let keymap = keyboard
+                .chunks_exact(2)
+                .map(|k| (k[0].min(119), (k[1] as usize).checked_sub(1)))
+                .collect();
+            // Instruments from before IT 2.0 have another layout past the keyboard
+            let (envelope, fadeout) = if compatible < 0x200 {
+                (None, 0)
+            } else {
+                let env = b.slice(h + 0x130, 6 + 25 * 3, "instrument header")?;
+                let n = (env[1] as usize).clamp(1, 25);
+                let envelope = (env[0] & 1 != 0).then(|| Envelope {
+                    points: env[6..6 + n * 3]
+                        .chunks_exact(3)
+                        .map(|p| (u16::from_le_bytes([p[1], p[2]]), p[0].min(64)))
+                        .collect(),
+                    sustain: (env[0] & 4 != 0).then_some(env[4] as usize),
+                    looped: (env[0] & 2 != 0).then_some((env[2] as usize, env[3] as usize)),
+                });
+                // IT fades from 1024
+                (envelope, b.u16le(h + 0x14, "instrument header")? as u32 * 64)
+            };
+            instruments.push(Instrument { keymap, envelope, fadeout });
+        }
+        instruments
+    };
+
+    let mut patterns = Vec::with_capacity(num_patterns.min(256));
+    let mut channels = 0;
+    // Channels are only known once every pattern is read, so count them all
+    let mut total = 0;
+    for i in 0..num_patterns {
+        let at = b.u32le(pattern_ptrs + i * 4, "pattern pointers")? as usize;
+        if at == 0 {
+            reserve_cells(&mut total, 64 * 64)?;
+            patterns.push((64, Vec::new()));
+            continue;
+        }
+        let rows = (b.u16le(at + 2, "pattern")? as usize).clamp(*IT_ROWS.start(), *IT_ROWS.end());
+        reserve_cells(&mut total, rows * 64)?;
+        let mut pos = at + 8;
+        let mut masks = [0u8; 64];
+        let mut last = [(0u8, 0u8, 255u8, 0u8, 0u8); 64];
+        let mut cells = Vec::new();
+        let mut row = 0;
+        while row < rows {
+            let var = b.u8(pos, "pattern")?;
+            pos += 1;
+            if var == 0 {
+                row += 1;
+                continue;
+            }
+            let ch = ((var - 1) & 63) as usize;
+            if var & 0x80 != 0 {
+                masks[ch] = b.u8(pos, "pattern")?;
+                pos += 1;
+            }
+            let mask = masks[ch];
+            let l = &mut last[ch];
+            let (mut note, mut ins, mut vol, mut cmd) = (None, 0, 255, (0, 0));
+            if mask & 1 != 0 {
+                l.0 = b.u8(pos, "pattern")?;
+                pos += 1;
+            }
+            if mask & 2 != 0 {
+                l.1 = b.u8(pos, "pattern")?;
+                pos += 1;
+            }
+            if mask & 4 != 0 {
+                l.2 = b.u8(pos, "pattern")?;
+                pos += 1;
+            }
+            if mask & 8 != 0 {
+                (l.3, l.4) = (b.u8(pos, "pattern")?, b.u8(pos + 1, "pattern")?);
+                pos += 2;
+            }
+            if mask & 0x11 != 0 { note = Some(l.0); }
+            if mask & 0x22 != 0 { ins = l.1; }
+            if mask & 0x44 != 0 { vol = l.2; }
+            if mask & 0x88 != 0 { cmd = (l.3, l.4); }
+
+            let mut cell = Cell {
+                note: match note {
+                    None => Note::None,
+                    Some(n @ 0..=119) => Note::On(n),
+                    Some(254) => Note::Cut,
+                    // 255 is note off, anything else above 119 a fade
+                    Some(_) => Note::Off,
+                },
+                instrument: ins,
+                volume: None,
+                fx: [s3m_fx(cmd.0, cmd.1, true), Fx::None],
+            };
+            const PORTA_SPEEDS: [u8; 10] = [0, 1, 4, 8, 16, 32, 64, 96, 128, 255];
+            match vol {
+                0..=64 => cell.volume = Some(vol),
+                65..=74 => cell.fx[1] = Fx::FineVolSlide((vol - 65) as i8),
+                75..=84 => cell.fx[1] = Fx::FineVolSlide(-((vol - 75) as i8)),
+                85..=94 => cell.fx[1] = Fx::VolSlide((vol - 85) as i8),
+                95..=104 => cell.fx[1] = Fx::VolSlide(-((vol - 95) as i8)),
+                105..=114 => cell.fx[1] = Fx::PortaDown((vol - 105) * 4),
+                115..=124 => cell.fx[1] = Fx::PortaUp((vol - 115) * 4),
+                128..=192 => cell.fx[1] = Fx::SetPanning(((vol - 128) as u16 * 255 / 64) as u8),
+                193..=202 => cell.fx[1] = Fx::TonePorta(PORTA_SPEEDS[(vol - 193) as usize]),
+                203..=212 => cell.fx[1] = Fx::Vibrato(0, vol - 203),
+                _ => {}
+            }
+            channels = channels.max(ch + 1);
+            cells.push((row, ch, cell));
+        }
+        patterns.push((rows, cells));
+    }
+    let patterns = patterns
+        .into_iter()
+        .map(|(rows, sparse)| {
+            let mut cells = vec![Cell::default(); rows * channels];
+            for (row, ch, cell) in sparse {
+                cells[row * channels + ch] = cell;
+            }
+            Pattern { rows, cells }
+        })
+        .collect();
+
+    Ok(Module {
+        channels,
+        orders: orders(order_list),
+        patterns,
+        instruments,
+        samples,
+        speed: b.u8(0x32, "header")?,
+        tempo: b.u8(0x33, "header")?,
+        global_volume: b.u8(0x30, "header")?.min(128) / 2,
+        panning: channel_pan
+            .iter()
+            .take(channels)
+            .map(|&p| match p & 0x7F {
+                p @ 0..=64 => (p as u16 * 255 / 64) as u8,
+                _ => 128,
+            })
+            .collect(),
+        slides: if flags & 8 != 0 { Slides::Linear } else { Slides::Amiga },
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digital::Source;
+    use crate::tracker::Player;
+
+    /// A 32-frame square wave, as signed 8-bit.
+    const SQUARE: [i8; 4] = [100, 100, -100, -100];
+
+    fn put(buf: &mut Vec<u8>, at: usize, bytes: &[u8]) {
+        if buf.len() < at + bytes.len() {
+            buf.resize(at + bytes.len(), 0);
+        }
+        buf[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn square() -> Vec<u8> {
+        SQUARE.repeat(8).iter().map(|&s| s as u8).collect()
+    }
+
+    /// Each builder plays the square at C-5, volume 48, on channel 0, and lasts 4 rows.
+    fn mod_file() -> Vec<u8> {
+        let mut b = Vec::new();
+        put(&mut b, 20 + 22, &16u16.to_be_bytes());
+        put(&mut b, 20 + 25, &[64]);
+        put(&mut b, 20 + 28, &16u16.to_be_bytes());
+        put(&mut b, 950, &[1]);
+        put(&mut b, 1080, b"M.K.");
+        // Period 428, sample 1, C30; D00 on row 3
+        put(&mut b, 1084, &[0x01, 0xAC, 0x1C, 48]);
+        put(&mut b, 1084 + 3 * 16, &[0, 0, 0x0D, 0]);
+        put(&mut b, 1084 + 1024, &square());
+        b
+    }
+
+    fn s3m_file() -> Vec<u8> {
+        let mut b = Vec::new();
+        put(&mut b, 0x20, &[1, 0, 1, 0, 1, 0]);
+        put(&mut b, 0x2A, &[1, 0]);
+        put(&mut b, 0x2C, b"SCRM");
+        put(&mut b, 0x30, &[64, 6, 125, 0x80 | 48]);
+        put(&mut b, 0x40, &[0, 8]);
+        put(&mut b, 0x42, &[255; 30]);
+        put(&mut b, 0x60, &[0, 0x07, 0, 0x0E, 0]);
+        // Sample header at 0x70, data at 0xC0
+        put(&mut b, 0x70, &[1]);
+        put(&mut b, 0x70 + 0x0E, &[0x0C, 0, 32, 0, 0, 0, 0, 0, 0, 0, 32]);
+        put(&mut b, 0x70 + 0x1C, &[64, 0, 0, 1]);
+        put(&mut b, 0x70 + 0x20, &8363u32.to_le_bytes());
+        put(&mut b, 0x70 + 0x4C, b"SCRS");
+        put(&mut b, 0xC0, &square());
+        // Pattern at 0xE0: C-4 (ST3's middle C), instrument 1, volume 48; C00 on row 3
+        let rows = [&[0x60, 0x40, 1, 48, 0][..], &[0], &[0], &[0x80, 3, 0, 0]].concat();
+        put(&mut b, 0xE0 + 2, &rows);
+        put(&mut b, 0xE0 + 2 + rows.len() + 60, &[0]);
+        b
+    }
+
+    fn xm_file() -> Vec<u8> {
+        let mut b = b"Extended Module: ".to_vec();
+        put(&mut b, 37, &[0x1A]);
+        put(&mut b, 58, &[4, 1]);
+        put(&mut b, 60, &276u32.to_le_bytes());
+        for (i, v) in [1u16, 0, 2, 1, 1, 1, 6, 125].iter().enumerate() {
+            put(&mut b, 64 + 2 * i, &v.to_le_bytes());
+        }
+        // One 4-row pattern: C-4 (FT2's middle C), instrument 1, volume 48
+        let data = [&[0x87, 49, 1, 0x10 + 48, 0x80][..], &[0x80; 6]].concat();
+        let at = 336;
+        put(&mut b, at, &9u32.to_le_bytes());
+        put(&mut b, at + 5, &4u16.to_le_bytes());
+        put(&mut b, at + 7, &(data.len() as u16).to_le_bytes());
+        put(&mut b, at + 9, &data);
+        // Instrument with one sample mapped to every note, no envelope
+        let at = at + 9 + data.len();
+        put(&mut b, at, &243u32.to_le_bytes());
+        put(&mut b, at + 27, &[1, 0, 40, 0, 0, 0]);
+        put(&mut b, at + 242, &[0]);
+        let at = at + 243;
+        put(&mut b, at, &32u32.to_le_bytes());
+        put(&mut b, at + 8, &32u32.to_le_bytes());
+        put(&mut b, at + 12, &[64, 0, 1, 128, 0]);
+        put(&mut b, at + 39, &[0]);
+        let mut prev = 0i8;
+        let deltas: Vec<u8> = SQUARE.repeat(8).iter().map(|&s| {
+            let d = s.wrapping_sub(prev);
+            prev = s;
+            d as u8
+        }).collect();
+        put(&mut b, at + 40, &deltas);
+        b
+    }
+
+    fn it_file() -> Vec<u8> {
+        let mut b = b"IMPM".to_vec();
+        put(&mut b, 0x20, &[2, 0, 0, 0, 1, 0, 1, 0, 0x14, 0x02, 0x14, 0x02, 0x09, 0]);
+        put(&mut b, 0x30, &[128, 48, 6, 125]);
+        put(&mut b, 0x40, &[32; 64]);
+        put(&mut b, 0x80, &[64; 64]);
+        put(&mut b, 0xC0, &[0, 255]);
+        put(&mut b, 0xC2, &0xD0u32.to_le_bytes());
+        put(&mut b, 0xC6, &0x140u32.to_le_bytes());
+        // Sample header at 0xD0, data at 0x120
+        put(&mut b, 0xD0, b"IMPS");
+        put(&mut b, 0xD0 + 0x11, &[64, 0x11, 64]);
+        put(&mut b, 0xD0 + 0x2E, &[1, 0]);
+        for (i, v) in [32u32, 0, 32, 8363].iter().enumerate() {
+            put(&mut b, 0xD0 + 0x30 + 4 * i, &v.to_le_bytes());
+        }
+        put(&mut b, 0xD0 + 0x48, &0x120u32.to_le_bytes());
+        put(&mut b, 0x120, &square());
+        // Four rows: C-5, sample 1, volume 48
+        let data = [0x81, 0x07, 60, 1, 48, 0, 0, 0, 0];
+        put(&mut b, 0x140, &(data.len() as u16).to_le_bytes());
+        put(&mut b, 0x142, &4u16.to_le_bytes());
+        put(&mut b, 0x148, &data);
+        b
+    }
+
+    #[test]
+    fn every_format_loads_and_plays_the_same_note() {
+        for (bytes, format) in [
+            (mod_file(), SongFormat::Mod),
+            (s3m_file(), SongFormat::S3m),
+            (xm_file(), SongFormat::Xm),
+            (it_file(), SongFormat::It),
+        ] {
+            assert_eq!(SongFormat::detect(&bytes), format);
+            let m = load(&bytes, format).unwrap_or_else(|e| panic!("{}: {e:#}", format.name()));
+            assert!(m.warnings.is_empty(), "{}: {:?}", format.name(), m.warnings);
+
+            let cell = m.patterns[0].cells[0];
+            assert_eq!((cell.note, cell.instrument), (Note::On(60), 1), "{}", format.name());
+            let volume = cell.volume.or(match cell.fx[0] { Fx::SetVolume(v) => Some(v), _ => None });
+            assert_eq!(volume, Some(48), "{}", format.name());
+            let s = &m.samples[0];
+            assert_eq!(s.data.len(), 32, "{}", format.name());
+            assert!((s.data[0] - 100.0 / 128.0).abs() < 1e-6 && s.looped.is_some(), "{}", format.name());
+            assert!((s.c5speed - 8363.0).abs() < 0.01, "{}", format.name());
+
+            let mut p = Player::new(m);
+            assert_eq!(p.length_us(), 4 * 6 * 20_000, "{}", format.name());
+            let block = p.next_block().unwrap().unwrap();
+            assert!(block.iter().any(|s| s.abs() > 0.1), "{} is silent", format.name());
+        }
+    }
+
+    #[test]
+    fn damaged_modules_are_rejected() {
+        let mut short = mod_file();
+        short.truncate(1200);
+        assert_eq!(load_mod(&short).unwrap_err(), ModuleError::Truncated("pattern"));
+
+        // Sample data cut short still loads, with a warning
+        let mut clipped = mod_file();
+        clipped.truncate(clipped.len() - 8);
+        let m = load_mod(&clipped).unwrap();
+        assert_eq!(m.samples[0].data.len(), 24);
+        assert_eq!(m.warnings.len(), 1);
+
+        let mut no_orders = s3m_file();
+        no_orders[0x60] = 255;
+        assert_eq!(load(&no_orders, SongFormat::S3m).unwrap_err().downcast::<ModuleError>().unwrap(), ModuleError::NoOrders);
+        assert_eq!(load_it(b"IMPM").unwrap_err(), ModuleError::Truncated("header"));
+    }
+
+    #[test]
+    fn shared_pattern_data_cant_blow_up() {
+        // An XM pattern header of length 0 would be read again for every pattern
+        let mut xm = xm_file();
+        xm[336..340].fill(0);
+        assert_eq!(load_xm(&xm).unwrap_err(), ModuleError::Invalid("pattern header"));
+
+        // 9000 empty patterns claiming 65535 rows each: clamped to 256, still too many cells
+        let mut xm = xm_file();
+        put(&mut xm, 70, &9000u16.to_le_bytes());
+        for i in 0..9000 {
+            let at = 336 + 9 * i;
+            put(&mut xm, at, &9u32.to_le_bytes());
+            put(&mut xm, at + 5, &[0xFF, 0xFF, 0, 0]);
+        }
+        assert_eq!(load_xm(&xm).unwrap_err(), ModuleError::TooManyCells);
+
+        // Thousands of IT patterns all pointing at one 65535-row pattern
+        let mut it = it_file();
+        put(&mut it, 0x24, &[0, 0, 0xA0, 0x0F]);
+        for i in 0..4000 {
+            put(&mut it, 0xC2 + 4 * i, &0x4000u32.to_le_bytes());
+        }
+        put(&mut it, 0x4002, &[0xFF, 0xFF]);
+        put(&mut it, 0x4008, &[0; 256]);
+        assert_eq!(load_it(&it).unwrap_err(), ModuleError::TooManyCells);
+    }
+}
@@ -1,17 +1,33 @@
 pub use anyhow::{Context, Result};
 use std::{
     collections::HashMap,
-    fs::File,
-    io::{Read, Seek, SeekFrom},
     path::PathBuf,
 };
 
-#[derive(thiserror::Error, Debug)]
+use crate::game::Identity;
+use crate::{folder, pk3, pk7};
+
+/// Size of the WAD header: ident, lump count, directory offset.
+const HEADER_LEN: usize = 12;
+/// Size of one directory entry: offset, size, name.
+const DIR_ENTRY_LEN: usize = 16;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
 pub enum WadError {
     #[error("not a WAD file")]
     NotAWad,
     #[error("lump not found: {0}")]
     LumpNotFound(String),
+    #[error("WAD header truncated: file is only {0} bytes")]
+    TruncatedHeader(usize),
+    #[error("directory of {entries} entries at offset {offset} runs past end of file ({file_len} bytes)")]
+    DirectoryOutOfBounds { offset: usize, entries: usize, file_len: usize },
+    #[error("lump {index} ({name}) at {filepos}+{size} runs past end of file ({file_len} bytes)")]
+    LumpOutOfBounds { index: usize, name: String, filepos: u32, size: u32, file_len: usize },
+    #[error("lump {index} has a non-ASCII name {raw:02x?}")]
+    NonAsciiName { index: usize, raw: [u8; 8] },
+    #[error("lumps {first} ({first_name}) and {second} ({second_name}) overlap")]
+    OverlappingLumps { first: usize, first_name: String, second: usize, second_name: String },
 }
 
 /// WAD directory entry
@@ -20,44 +36,146 @@ pub struct Lump {
     pub name: String,   // 8-char upper ASCII without trailing NULs
     pub filepos: u32,   // offset from start of file
     pub size: u32,      // bytes
+    pub namespace: Namespace,
+}
+
+/// Section of the directory a lump sits in, delimited by `X_START`/`X_END` marker lumps.
+///
+/// Markers themselves are in the namespace around them, usually `Global`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Namespace {
+    /// Outside any markers
+    Global,
+    /// S_START/S_END, SS_START/SS_END
+    Sprites,
+    /// F_START/F_END, FF_, F1_ to F3_
+    Flats,
+    /// P_START/P_END, PP_, P1_ to P3_
+    Patches,
+    /// TX_START/TX_END
+    Textures,
+    /// C_START/C_END
+    Colormaps,
+    /// MU_START/MU_END: everything inside is music, whatever its name
+    Music,
+    /// DS_START/DS_END
+    Sounds,
+    /// VX_START/VX_END
+    Voxels,
+    /// HI_START/HI_END
+    HiRes,
+}
+
+impl Namespace {
+    /// The namespace a marker prefix opens or closes, e.g. "SS" for SS_START.
+    fn from_marker(prefix: &str) -> Option<Self> {
+        Some(match prefix {
+            "S" | "SS" => Self::Sprites,
+            "F" | "FF" | "F1" | "F2" | "F3" => Self::Flats,
+            "P" | "PP" | "P1" | "P2" | "P3" => Self::Patches,
+            "TX" => Self::Textures,
+            "C" => Self::Colormaps,
+            "MU" => Self::Music,
+            "DS" => Self::Sounds,
+            "VX" => Self::Voxels,
+            "HI" => Self::HiRes,
+            _ => return None,
+        })
+    }
+}
+
+/// Assign every lump its namespace. Markers nest (F1_START inside F_START), an `_END`
+/// that doesn't close the innermost open namespace is ignored, and a namespace left
+/// open runs to the end of the directory.
+fn assign_namespaces(lumps: &mut [Lump]) {
+    let mut open: Vec<Namespace> = Vec::new();
+    for l in lumps {
+        l.namespace = open.last().copied().unwrap_or(Namespace::Global);
+        if let Some(ns) = l.name.strip_suffix("_START").and_then(Namespace::from_marker) {
+            open.push(ns);
+        } else if let Some(ns) = l.name.strip_suffix("_END").and_then(Namespace::from_marker)
+            && open.last() == Some(&ns)
+        {
+            open.pop();
+            // The end marker belongs outside, like the start marker
+            l.namespace = open.last().copied().unwrap_or(Namespace::Global);
+        }
+    }
 }
 
 /// Parsed WAD file with a case-insensitive name index.
 /// Multiple lumps can share the same name, so the index maps to a list of indices.
-#[derive(Debug)]
+///
+/// The whole file is read into memory once and lumps are handed out as borrowed
+/// slices, so reads need only `&self` and a `Wad` can be shared across threads.
+/// Even the largest megawads are a few hundred MB, which is fine to hold.
 pub struct Wad {
-    file: File,
+    data: Vec<u8>,
     lumps: Vec<Lump>,
     index: HashMap<String, Vec<usize>>,
+    /// Where the directory starts, for re-reading raw names in `validate`
+    dir_offset: usize,
+}
+
+// The file contents would drown everything else
+impl std::fmt::Debug for Wad {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Wad")
+            .field("bytes", &self.data.len())
+            .field("lumps", &self.lumps)
+            .finish()
+    }
 }
 
 impl Wad {
-    /// Open and parse an IWAD or PWAD.
+    /// Open and parse an IWAD or PWAD, a PK3 or PK7 archive (see `pk3`, `pk7`) or a
+    /// folder of loose resources (see `folder`).
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         let path = path.into();
-        let mut f = File::open(&path).with_context(|| format!("opening {:?}", path))?;
+        if path.is_dir() {
+            return folder::to_wad(&path);
+        }
+        let data = std::fs::read(&path).with_context(|| format!("opening {:?}", path))?;
+        if pk3::is_zip(&data) {
+            return pk3::to_wad(&data).with_context(|| format!("reading archive {:?}", path));
+        }
+        if data.starts_with(pk7::MAGIC) {
+            return pk7::to_wad(&data).with_context(|| format!("reading archive {:?}", path));
+        }
+        Ok(Self::from_bytes(data)?)
+    }
 
+    /// Parse a WAD already in memory.
+    ///
+    /// Only problems that make the directory unreadable are errors here; lumps that
+    /// point outside the file fail when read and are listed by `validate`.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, WadError> {
         // Header: ident[4], numlumps[4], infotableofs[4]
-        let mut ident = [0u8; 4];
-        f.read_exact(&mut ident)?;
-        let numlumps = read_u32(&mut f)?;
-        let infoofs = read_u32(&mut f)?;
-
-        if &ident != b"IWAD" && &ident != b"PWAD" {
-            return Err(WadError::NotAWad.into());
+        let ident = data.get(0..4).ok_or(WadError::NotAWad)?;
+        if ident != b"IWAD" && ident != b"PWAD" {
+            return Err(WadError::NotAWad);
         }
-
-        // Directory: numlumps entries of { filepos[4], size[4], name[8] }
-        f.seek(SeekFrom::Start(infoofs as u64))?;
-        let mut lumps = Vec::with_capacity(numlumps as usize);
-        for _ in 0..numlumps {
-            let filepos = read_u32(&mut f)?;
-            let size = read_u32(&mut f)?;
-            let mut name_bytes = [0u8; 8];
-            f.read_exact(&mut name_bytes)?;
-            let name = bytes_to_lump_name(&name_bytes);
-            lumps.push(Lump { name, filepos, size });
+        if data.len() < HEADER_LEN {
+            return Err(WadError::TruncatedHeader(data.len()));
+        }
+        let numlumps = read_u32(&data, 4) as usize;
+        let infoofs = read_u32(&data, 8) as usize;
+
+        // Directory: numlumps entries of { filepos[4], size[4], name[8] }.
+        // Checking it fits in the file also caps the allocation below by the file size.
+        let dir = numlumps.checked_mul(DIR_ENTRY_LEN)
+            .and_then(|len| infoofs.checked_add(len))
+            .and_then(|end| data.get(infoofs..end))
+            .ok_or(WadError::DirectoryOutOfBounds { offset: infoofs, entries: numlumps, file_len: data.len() })?;
+        let mut lumps = Vec::with_capacity(numlumps);
+        for entry in dir.chunks_exact(DIR_ENTRY_LEN) {
+            let filepos = read_u32(entry, 0);
+            let size = read_u32(entry, 4);
+            let name = bytes_to_lump_name(entry[8..16].try_into().unwrap());
+            lumps.push(Lump { name, filepos, size, namespace: Namespace::Global });
         }
+        assign_namespaces(&mut lumps);
 
         // Build case-insensitive multi-map
         let mut index: HashMap<String, Vec<usize>> = HashMap::new();
@@ -65,7 +183,12 @@ impl Wad {
             index.entry(l.name.clone()).or_default().push(i);
         }
 
-        Ok(Self { file: f, lumps, index })
+        Ok(Self { data, lumps, index, dir_offset: infoofs })
+    }
+
+    /// The whole file. For archives and folders, this is the PWAD they were repacked into.
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
     }
 
     /// Number of lumps.
@@ -77,6 +200,17 @@ impl Wad {
         self.lumps.is_empty()
     }
 
+    /// Whether this is a game's own IWAD rather than a patch.
+    pub fn is_iwad(&self) -> bool {
+        self.data.starts_with(b"IWAD")
+    }
+
+    /// Which game and release this IWAD is; `None` for PWADs and IWADs of unknown
+    /// games.
+    pub fn identify(&self) -> Option<Identity> {
+        Identity::of(self)
+    }
+
     /// Borrow all directory entries.
     pub fn lumps(&self) -> &[Lump] {
         &self.lumps
@@ -97,6 +231,23 @@ impl Wad {
         self.index.contains_key(&name.to_uppercase())
     }
 
+    /// Index of the first lump with this name in one of `namespaces`.
+    pub fn find_in(&self, name: &str, namespaces: &[Namespace]) -> Option<usize> {
+        self.find_all(name)?.iter().copied().find(|&i| namespaces.contains(&self.lumps[i].namespace))
+    }
+
+    /// Borrow the first lump with this name in one of `namespaces`.
+    pub fn read_in(&self, name: &str, namespaces: &[Namespace]) -> Result<&[u8]> {
+        let idx = self.find_in(name, namespaces)
+            .ok_or_else(|| WadError::LumpNotFound(name.to_string()))?;
+        self.read_at(idx)
+    }
+
+    /// Lumps in one namespace, in file order.
+    pub fn in_namespace(&self, ns: Namespace) -> impl Iterator<Item = &Lump> {
+        self.lumps.iter().filter(move |l| l.namespace == ns)
+    }
+
     /// Get the first matching lump directory entry.
     pub fn get_first(&self, name: &str) -> Option<&Lump> {
         self.find_all(name).and_then(|ids| ids.first().map(|&i| &self.lumps[i]))
@@ -112,20 +263,79 @@ impl Wad {
             .collect()
     }
 
-    /// Read lump bytes by index.
-    pub fn read_at(&mut self, idx: usize) -> Result<Vec<u8>> {
+    /// Borrow lump bytes by index.
+    pub fn read_at(&self, idx: usize) -> Result<&[u8]> {
         let l = self.lumps.get(idx).context("lump index out of range")?;
-        self.read_span(l.filepos as u64, l.size as usize)
+        Ok(self.span(l).ok_or_else(|| self.out_of_bounds(idx))?)
     }
 
-    /// Read the first lump that matches the given name.
-    pub fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+    /// Borrow the first lump that matches the given name.
+    pub fn read(&self, name: &str) -> Result<&[u8]> {
         let idx = self.find_all(name)
             .and_then(|v| v.first().copied())
             .ok_or_else(|| WadError::LumpNotFound(name.to_string()))?;
         self.read_at(idx)
     }
 
+    /// Every problem in the directory: lumps outside the file, non-ASCII names and
+    /// lumps whose data partly overlaps. Lumps sharing exactly the same data are fine,
+    /// some tools deduplicate that way.
+    pub fn validate(&self) -> Vec<WadError> {
+        let mut problems = Vec::new();
+        for (i, l) in self.lumps.iter().enumerate() {
+            if self.span(l).is_none() {
+                problems.push(self.out_of_bounds(i));
+            }
+            let at = self.dir_offset + i * DIR_ENTRY_LEN + 8;
+            let raw: [u8; 8] = self.data[at..at + 8].try_into().unwrap();
+            // Tools often leave junk after the NUL that ends a short name
+            let name = raw.split(|&b| b == 0).next().unwrap_or_default();
+            if !name.is_ascii() {
+                problems.push(WadError::NonAsciiName { index: i, raw });
+            }
+        }
+
+        // Sort non-empty lumps by start; each only needs checking against the furthest end so far
+        let mut spans: Vec<(usize, u64, u64)> = self.lumps.iter().enumerate()
+            .filter(|(_, l)| l.size > 0)
+            .map(|(i, l)| (i, l.filepos as u64, l.filepos as u64 + l.size as u64))
+            .collect();
+        spans.sort_by_key(|&(i, start, _)| (start, i));
+        let mut reach: Option<(usize, u64, u64)> = None;
+        for &(i, start, end) in &spans {
+            if let Some((j, j_start, j_end)) = reach {
+                if start < j_end && !(start == j_start && end == j_end) {
+                    problems.push(WadError::OverlappingLumps {
+                        first: j.min(i),
+                        first_name: self.lumps[j.min(i)].name.clone(),
+                        second: j.max(i),
+                        second_name: self.lumps[j.max(i)].name.clone(),
+                    });
+                }
+                if end <= j_end { continue; }
+            }
+            reach = Some((i, start, end));
+        }
+        problems
+    }
+
+    /// The bytes a directory entry points at, if they're inside the file.
+    fn span(&self, l: &Lump) -> Option<&[u8]> {
+        let start = l.filepos as usize;
+        self.data.get(start..start.checked_add(l.size as usize)?)
+    }
+
+    fn out_of_bounds(&self, index: usize) -> WadError {
+        let l = &self.lumps[index];
+        WadError::LumpOutOfBounds {
+            index,
+            name: l.name.clone(),
+            filepos: l.filepos,
+            size: l.size,
+            file_len: self.data.len(),
+        }
+    }
+
     // Zero-alloc iterator variant so we don't need a Vec
     pub fn iter_with_prefixes<'a>(&'a self, prefixes: &[&str]) 
         -> impl Iterator<Item = &'a Lump> + 'a 
@@ -143,20 +353,79 @@ impl Wad {
             .collect()
     }
 
-    /// Raw file span read
-    fn read_span(&mut self, start: u64, len: usize) -> Result<Vec<u8>> {
-        self.file.seek(SeekFrom::Start(start))?;
-        let mut buf = vec![0u8; len];
-        self.file.read_exact(&mut buf)?;
-        Ok(buf)
+}
+
+/// Assembles a PWAD in memory, for sources that aren't WAD files themselves.
+#[derive(Default)]
+pub struct WadBuilder {
+    data: Vec<u8>,
+    dir: Vec<(u32, u32, [u8; 8])>,
+}
+
+impl WadBuilder {
+    /// Append a lump. The name is normalized with `lump_name` and cut to 8 bytes.
+    pub fn lump(&mut self, name: &str, bytes: &[u8]) {
+        let mut raw = [0u8; 8];
+        for (dst, src) in raw.iter_mut().zip(lump_name(name).bytes()) {
+            *dst = src;
+        }
+        self.dir.push((HEADER_LEN as u32 + self.data.len() as u32, bytes.len() as u32, raw));
+        self.data.extend_from_slice(bytes);
     }
+
+    /// Append every lump of another WAD, markers included.
+    pub fn extend(&mut self, wad: &Wad) {
+        for (i, l) in wad.lumps.iter().enumerate() {
+            // Unreadable lumps are left out rather than failing the whole archive
+            if let Ok(bytes) = wad.read_at(i) {
+                self.lump(&l.name, bytes);
+            }
+        }
+    }
+
+    /// Wrap everything `add` appends in `X_START`/`X_END` markers.
+    pub fn section(&mut self, marker: &str, add: impl FnOnce(&mut Self)) {
+        self.lump(&format!("{marker}_START"), &[]);
+        add(self);
+        self.lump(&format!("{marker}_END"), &[]);
+    }
+
+    /// The PWAD file: header, lump data, then the directory.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let dir_offset = HEADER_LEN + self.data.len();
+        let mut out = Vec::with_capacity(dir_offset + self.dir.len() * DIR_ENTRY_LEN);
+        out.extend_from_slice(b"PWAD");
+        out.extend_from_slice(&(self.dir.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(dir_offset as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+        for (filepos, size, name) in &self.dir {
+            out.extend_from_slice(&filepos.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(name);
+        }
+        out
+    }
+
+    pub fn build(self) -> Wad {
+        Wad::from_bytes(self.to_bytes()).expect("assembled WADs are well-formed")
+    }
+}
+
+/// Lump name for a file name: the stem before the first dot, upper-cased and cut to
+/// 8 characters, as ZDoom does for short names in PK3s. Non-ASCII becomes `_`.
+pub fn lump_name(file_name: &str) -> String {
+    let stem = file_name.split('.').next().unwrap_or(file_name);
+    stem.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() { c.to_ascii_uppercase() } else { '_' })
+        .take(8)
+        .collect()
 }
 
 /// Minimal helper for a one-off function call.
 /// Uses the Wad type under the hood.
 pub fn read_lump(path: &str, name: &str) -> Result<Vec<u8>> {
-    let mut wad = Wad::open(path)?;
-    wad.read(name)
+    let wad = Wad::open(path)?;
+    Ok(wad.read(name)?.to_vec())
 }
 
 /// Convert an 8-byte WAD name to upper ASCII without trailing NULs.
@@ -166,16 +435,14 @@ fn bytes_to_lump_name(b: &[u8; 8]) -> String {
     String::from_utf8_lossy(s).to_uppercase()
 }
 
-fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
-    let mut b = [0u8; 4];
-    r.read_exact(&mut b)?;
-    Ok(u32::from_le_bytes(b))
+fn read_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::{Seek, Write};
+    use std::io::{Seek, SeekFrom, Write};
     use tempfile::NamedTempFile;
 
     fn write_le_u32<W: Write>(w: &mut W, v: u32) { w.write_all(&v.to_le_bytes()).unwrap(); }
@@ -242,7 +509,7 @@ mod tests {
     #[test]
     fn reads_by_name_and_detects_format() {
         let tmp = make_fake_wad();
-        let mut wad = Wad::open(tmp.path()).unwrap();
+        let wad = Wad::open(tmp.path()).unwrap();
         let hello = wad.read("hello").unwrap();
         assert_eq!(hello, b"hello");
 
@@ -268,4 +535,91 @@ mod tests {
         let wad = Wad::open(tmp.path()).unwrap();
         assert_eq!(wad.list_with_prefixes(PREFS).len(), 1);
     }
+
+    #[test]
+    fn lumps_are_borrowed_and_shareable_across_threads() {
+        fn assert_sync<T: Sync + Send>() {}
+        assert_sync::<Wad>();
+
+        let tmp = make_fake_wad();
+        let wad = Wad::open(tmp.path()).unwrap();
+        let hello = wad.read("HELLO").unwrap();
+        // Points into the WAD's own buffer, no copy
+        assert!(std::ptr::eq(hello, &wad.data[12..17]));
+
+        std::thread::scope(|s| {
+            let a = s.spawn(|| wad.read("HELLO").unwrap().len());
+            let b = s.spawn(|| wad.read("D_TEST").unwrap().len());
+            assert_eq!((a.join().unwrap(), b.join().unwrap()), (5, 4));
+        });
+    }
+
+    /// A PWAD of `data` followed by a directory of (filepos, size, name) entries.
+    fn wad_bytes(data: &[u8], dir: &[(u32, u32, [u8; 8])]) -> Vec<u8> {
+        let mut w = b"PWAD".to_vec();
+        w.extend_from_slice(&(dir.len() as u32).to_le_bytes());
+        w.extend_from_slice(&(12 + data.len() as u32).to_le_bytes());
+        w.extend_from_slice(data);
+        for (pos, size, name) in dir {
+            w.extend_from_slice(&pos.to_le_bytes());
+            w.extend_from_slice(&size.to_le_bytes());
+            w.extend_from_slice(name);
+        }
+        w
+    }
+
+    #[test]
+    fn broken_headers_and_directories_are_rejected() {
+        assert_eq!(Wad::from_bytes(b"IWAD\0\0".to_vec()).unwrap_err(), WadError::TruncatedHeader(6));
+
+        // Claims four billion lumps; must fail before allocating for them
+        let mut huge = wad_bytes(b"", &[]);
+        huge[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Wad::from_bytes(huge), Err(WadError::DirectoryOutOfBounds { entries, .. }) if entries == u32::MAX as usize));
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let w = wad_bytes(&[0; 16], &[
+            (12, 8, *b"A\0\0\0\0\0\0\0"),
+            (16, 8, *b"B\0\0\0\0\0\0\0"),    // overlaps A
+            (12, 8, *b"SAME\0\0\0\0"),        // same data as A: fine
+            (20, 200, *b"LONG\0\0\0\0"),      // past the end of the file
+            (0, 0, *b"N\xc9\0\0\0\0\0\0"), // non-ASCII marker
+            (0, 0, *b"OK\0\xff\xfe\0\0\0"), // junk after the NUL: fine
+        ]);
+        let wad = Wad::from_bytes(w).unwrap();
+        let problems = wad.validate();
+        assert!(matches!(problems[0], WadError::LumpOutOfBounds { index: 3, .. }));
+        assert!(matches!(problems[1], WadError::NonAsciiName { index: 4, .. }));
+        assert!(matches!(&problems[2], WadError::OverlappingLumps { first: 0, second: 1, .. }));
+        assert_eq!(problems.len(), 4, "{problems:?}"); // B also overlaps LONG
+        assert!(wad.read("LONG").is_err());
+        assert_eq!(wad.read("SAME").unwrap(), wad.read("A").unwrap());
+    }
+
+    #[test]
+    fn markers_split_the_directory_into_namespaces() {
+        let name = |n: &str| { let mut b = [0u8; 8]; b[..n.len()].copy_from_slice(n.as_bytes()); b };
+        let dir: Vec<_> = [
+            "D_INTRO", "S_START", "D_SPRITE", "S_END",
+            "FF_START", "F1_START", "FLOOR1", "F1_END", "FLOOR2", "F_END", // F_END closes FF too
+            "MU_START", "TITLE", "MU_END", "D_SPRITE",
+        ].iter().map(|n| (12, 0, name(n))).collect();
+        let wad = Wad::from_bytes(wad_bytes(&[], &dir)).unwrap();
+
+        let ns: Vec<_> = wad.lumps().iter().map(|l| (l.name.as_str(), l.namespace)).collect();
+        use Namespace::*;
+        assert_eq!(ns, vec![
+            ("D_INTRO", Global), ("S_START", Global), ("D_SPRITE", Sprites), ("S_END", Global),
+            ("FF_START", Global), ("F1_START", Flats), ("FLOOR1", Flats), ("F1_END", Flats),
+            ("FLOOR2", Flats), ("F_END", Global),
+            ("MU_START", Global), ("TITLE", Music), ("MU_END", Global), ("D_SPRITE", Global),
+        ]);
+
+        // The sprite is skipped when only global lumps are wanted
+        assert_eq!(wad.find_in("D_SPRITE", &[Global]), Some(13));
+        assert_eq!(wad.find_in("D_SPRITE", &[Sprites]), Some(2));
+        assert_eq!(wad.in_namespace(Music).map(|l| l.name.as_str()).collect::<Vec<_>>(), vec!["TITLE"]);
+    }
 }
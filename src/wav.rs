@@ -0,0 +1,101 @@
+//! wav.rs
+//!
+//! A tiny RIFF/WAVE writer for 16-bit PCM.
+//!
+//! We only ever need to write what the synth produces (interleaved f32), so this
+//! converts to i16 on the way out and patches the RIFF/data chunk sizes in `finish`.
+//! The header is written up front with zero sizes; if the process dies before `finish`
+//! most players will still open the file and just see an empty or truncated stream.
+
+use anyhow::{Context, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Size of the canonical 44-byte header we write.
+const HEADER_LEN: u32 = 44;
+
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    data_bytes: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    /// Create (or truncate) a WAV file at `path`.
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> Result<Self> {
+        let f = File::create(path).with_context(|| format!("creating {:?}", path))?;
+        Self::new(BufWriter::new(f), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Start a new WAV stream and write its header.
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> Result<Self> {
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_u32::<LittleEndian>(HEADER_LEN - 8)?; // patched in finish()
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_u32::<LittleEndian>(16)?;             // fmt chunk size
+        out.write_u16::<LittleEndian>(1)?;              // PCM
+        out.write_u16::<LittleEndian>(channels)?;
+        out.write_u32::<LittleEndian>(sample_rate)?;
+        out.write_u32::<LittleEndian>(sample_rate * block_align as u32)?; // byte rate
+        out.write_u16::<LittleEndian>(block_align)?;
+        out.write_u16::<LittleEndian>(16)?;             // bits per sample
+        out.write_all(b"data")?;
+        out.write_u32::<LittleEndian>(0)?;              // patched in finish()
+        Ok(Self { out, data_bytes: 0 })
+    }
+
+    /// Append interleaved f32 samples in -1.0..=1.0, clipping anything outside.
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        for &s in samples {
+            let v = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_i16::<LittleEndian>(v)?;
+        }
+        // WAV sizes are u32; saturate rather than wrap on absurdly long renders
+        self.data_bytes = self.data_bytes.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+
+    /// Patch the chunk sizes and flush. Returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_u32::<LittleEndian>(self.data_bytes.saturating_add(HEADER_LEN - 8))?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_u32::<LittleEndian>(self.data_bytes)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn writes_header_and_patches_sizes() {
+        let mut w = WavWriter::new(Cursor::new(Vec::new()), 44_100, 2).unwrap();
+        w.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        let bytes = w.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 44_100);
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 8);
+
+        let samples: Vec<i16> = bytes[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples, vec![0, i16::MAX, -i16::MAX, i16::MAX]); // 2.0 is clipped
+    }
+}